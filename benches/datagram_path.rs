@@ -0,0 +1,146 @@
+// Round trips payloads between a ReliableConnection and a ReliableServer over loopback and
+// counts heap allocations while doing so. Once warmed up, the send and receive paths should not
+// allocate at all; the benchmark fails if they do.
+//
+// cargo bench --bench datagram_path
+
+extern crate common;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use common::clock;
+use common::net::{ReliableConnection, ReliableServer};
+use common::packet::MAX_PACKET_SIZE;
+
+const PROTOCOL_ID: u32 = 0x4C494645;
+const WARM_UP_ROUND_TRIPS: usize = 1000;
+const MEASURED_ROUND_TRIPS: usize = 20000;
+const TICK_EVERY: usize = 64;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// Client sends, server echoes, client receives. Loopback hands datagrams over straight away, but
+// we still give each hop a few tries before calling it lost.
+fn round_trip(client: &mut ReliableConnection, server: &mut ReliableServer, payload: &[u8], buffer: &mut [u8]) -> bool {
+    if client.SendPacket(payload).is_none() || client.Flush() != 1 {
+        return false;
+    }
+
+    let mut echoed = false;
+
+    for _ in 0..1000 {
+        match server.ReceivePacket(buffer) {
+            Some((sender, size)) => {
+                echoed = server.SendPacket(&sender, &buffer[..size]).is_some() && server.Flush() == 1;
+                break;
+            },
+            None => {},
+        }
+    }
+
+    if !echoed {
+        return false;
+    }
+
+    for _ in 0..1000 {
+        if client.ReceivePacket(buffer) > 0 {
+            return true;
+        }
+    }
+    false
+}
+
+fn run(client: &mut ReliableConnection, server: &mut ReliableServer, round_trips: usize) -> usize {
+    let payload = [7u8; 100];
+    let mut buffer = [0u8; MAX_PACKET_SIZE];
+    let mut completed = 0;
+
+    for i in 0..round_trips {
+        if round_trip(client, server, &payload, &mut buffer) {
+            completed += 1;
+        }
+
+        if i % TICK_EVERY == 0 {
+            client.Tick();
+            server.Tick();
+        }
+    }
+    completed
+}
+
+fn main() {
+    // Port 0 on both ends, so the benchmark never trips over whatever else is running.
+    let mut server = ReliableServer::bind(PROTOCOL_ID, 10.0, "127.0.0.1:0".parse().unwrap(), clock::system_clock());
+    let mut client = ReliableConnection::new(PROTOCOL_ID, 10.0, 0);
+
+    if !server.Start() || !client.Start() {
+        println!("Could not start the connections.");
+        process::exit(1);
+    }
+
+    client.SetDestination(server.local_addr());
+
+    server.Listen();
+    client.Connect();
+
+    // The request, the challenge, the request again with its cookie, and the response.
+    let mut buffer = [0u8; MAX_PACKET_SIZE];
+    for _ in 0..1000 {
+        if client.IsConnected() {
+            break;
+        }
+        client.Flush();
+        server.ReceivePacket(&mut buffer);
+        server.Flush();
+        client.ReceivePacket(&mut buffer);
+    }
+
+    if !client.IsConnected() {
+        println!("The handshake did not complete.");
+        process::exit(1);
+    }
+
+    // Lets every buffer reach its working size.
+    run(&mut client, &mut server, WARM_UP_ROUND_TRIPS);
+
+    let allocations_before = ALLOCATIONS.load(Ordering::SeqCst);
+    let start = Instant::now();
+
+    let completed = run(&mut client, &mut server, MEASURED_ROUND_TRIPS);
+
+    let elapsed = clock::as_seconds(start.elapsed());
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - allocations_before;
+
+    println!("{} of {} round trips in {:.3}s ({:.0} per second, {:.2}us each)",
+             completed, MEASURED_ROUND_TRIPS, elapsed,
+             completed as f64 / elapsed, elapsed * 1_000_000.0 / completed as f64);
+    println!("{} heap allocations in steady state", allocations);
+
+    if allocations > 0 {
+        println!("FAILED: the datagram path allocated.");
+        process::exit(1);
+    }
+}
@@ -12,11 +12,15 @@ use std::thread;
 use std::time;
 use std::io::{self, BufRead, Write};
 use std::{str};
+use rustc_serialize::hex::FromHex;
 
 use common::communicate::*;
 use common::packet::{Packet, MyLen};
 use common::netbuffers::{ get_network_buffer_manager};
+use common::sequence::Sequence16;
 use common::net as mynet;
+use common::clock;
+use common::connect_token;
 
 #[derive(PartialEq)]
 enum MessageType {
@@ -225,8 +229,7 @@ fn net_buffer_handler(skt: &mio::udp::UdpSocket,
                     match get_network_buffer_manager().lock() {
                         Ok(mut buffer) => {
                             let mut pkt = Packet::new();
-                            pkt.set_sequence_number(message.param1 as u32);
-                            pkt.set_client_id(String::from("Mang"));
+                            pkt.set_sequence_number(Sequence16(message.param1 as u16));
                             let result = buffer.insert(pkt);
                             println!("{:?}", result);
                         },
@@ -317,9 +320,94 @@ fn main() {
     use std::time::Duration;
     use std::thread;
 
-    let mut reliable_connection = mynet::ReliableConnection::new(0x4C494645, 6000000.0, 0xFFFFFFFF, mynet::Port::Client as u16);
+    // client [SERVER] [--port PORT] [--secret SECRET] [--encrypt] [--token-key KEY --client-id ID]
+    // The server may be given as "host:port" or "[v6]:port"; it defaults to the local one. Without
+    // --port we bind whatever port the system gives us, so any number of clients can run at once.
+    // --secret must match the server's, and --encrypt is needed if the server uses it. With
+    // --token-key we stand in for the token authority and mint our own connect token, for testing
+    // a server started with the same --token-key.
+    let mut server = mynet::Address::new(net::Ipv4Addr::new(127, 0, 0, 1), mynet::Port::Server as u16);
+    let mut port = 0;
+    let mut secret = None;
+    let mut encrypt = false;
+    let mut token_key = None;
+    let mut client_id = 0;
+    let mut args = std::env::args().skip(1);
 
-    reliable_connection.SetDestination(mynet::Address::new( net::Ipv4Addr::new(127, 0, 0, 1) , mynet::Port::Server as u16));
+    loop {
+        match args.next() {
+            Some(arg) => {
+                match arg.as_ref() {
+                    "--port" | "-p" => {
+                        match args.next().map(|value| value.parse::<u16>()) {
+                            Some(Ok(parsed)) => port = parsed,
+                            _ => panic!("--port needs a port number."),
+                        }
+                    },
+                    "--secret" | "-s" => {
+                        match args.next() {
+                            Some(value) => secret = Some(value),
+                            None => panic!("--secret needs a value."),
+                        }
+                    },
+                    "--encrypt" | "-e" => {
+                        encrypt = true;
+                    },
+                    "--token-key" | "-k" => {
+                        match args.next().map(|value| value.from_hex()) {
+                            Some(Ok(parsed)) => token_key = Some(parsed),
+                            _ => panic!("--token-key needs a key in hex."),
+                        }
+                    },
+                    "--client-id" | "-i" => {
+                        match args.next().map(|value| value.parse::<u64>()) {
+                            Some(Ok(parsed)) => client_id = parsed,
+                            _ => panic!("--client-id needs a whole number."),
+                        }
+                    },
+                    target => {
+                        match mynet::Address::resolve(target) {
+                            Ok(addresses) => server = addresses[0].clone(),
+                            Err(err) => panic!("Could not resolve {}: {}", target, err),
+                        }
+                    },
+                }
+            },
+            None => {
+                break;
+            },
+        }
+    }
+
+    // Bind the same family as the server so we can reach it.
+    let local_ip = match server.get_address() {
+        net::IpAddr::V4(_) => net::IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0)),
+        net::IpAddr::V6(_) => net::IpAddr::V6(net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+    };
+
+    let mut reliable_connection = mynet::ReliableConnection::bind(0x4C494645, 6000000.0, net::SocketAddr::new(local_ip, port), clock::system_clock());
+
+    println!("Sending from {} to server at {}", reliable_connection.local_addr(), server);
+    reliable_connection.SetDestination(server.clone());
+
+    if let Some(secret) = secret {
+        reliable_connection.SetSecretKey(secret.as_bytes());
+    }
+
+    if encrypt {
+        reliable_connection.EnableEncryption();
+    }
+
+    if let Some(token_key) = token_key {
+        match connect_token::generate_connect_token(&token_key, 0x4C494645, client_id, &[server], 300) {
+            Some(token) => {
+                if !reliable_connection.SetConnectToken(token) {
+                    panic!("The connect token names no server.");
+                }
+            },
+            None => panic!("--token-key needs a key of 64 hex digits."),
+        }
+    }
 
     if !reliable_connection.Start() {
         panic!("Error: Could not start reliable connection.")
@@ -327,14 +415,21 @@ fn main() {
 
     reliable_connection.Connect();
 
-    for x in 0..0xFF {
-        let mut buffer = Vec::<u8>::new();
-        for n in 0..100 {
+    // Nothing can be sent until the server has answered our handshake.
+    let mut reply = [0u8; 100];
+    while !reliable_connection.IsConnected() {
+        reliable_connection.Update(0.0003);
+        reliable_connection.ReceivePacket(&mut reply);
+        thread::sleep(Duration::from_millis(10));
+    }
 
-            buffer.push(n);
-        }
+    let mut buffer = [0u8; 100];
+    for n in 0..100 {
+        buffer[n] = n as u8;
+    }
 
-        let packet_sent = reliable_connection.SendPacket(buffer, 100*8);
+    for x in 0..0xFF {
+        let packet_sent = reliable_connection.SendPacket(&buffer).is_some();
 
         if !packet_sent {
             panic!("I couldn't send the packet :()");
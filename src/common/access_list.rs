@@ -0,0 +1,269 @@
+/*
+ * Allow and deny lists of subnets, for keeping a server to the networks it is meant to serve.
+ *
+ * A list is written one rule to a line, `allow` or `deny` followed by a subnet in CIDR notation,
+ * IPv4 or IPv6. A bare address stands for just itself. Anything after a # is a comment:
+ *
+ *     # The lab, except for the printers
+ *     allow 10.20.0.0/16
+ *     allow fd00:20::/32
+ *     deny  10.20.99.0/24
+ *
+ * Deny rules win. If there are any allow rules, an address has to match one of them to get in;
+ * with none, everything not denied does. IPv4 rules never match IPv6 addresses, nor the other way
+ * around. Connection holds peers that reach us over IPv4 on a dual-stack socket as IPv4, so write
+ * IPv4 rules for them.
+ */
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Cidr {
+    network : IpAddr,
+    prefix : u8,
+}
+
+impl Cidr {
+    // The subnet of `address` with the first `prefix` bits fixed. None if the prefix is longer than
+    // the address. Bits past the prefix are ignored.
+    pub fn new(address: IpAddr, prefix: u8) -> Option<Cidr> {
+        let network = match address {
+            IpAddr::V4(ip) if prefix <= 32 => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & v4_mask(prefix)))
+            },
+            IpAddr::V6(ip) if prefix <= 128 => {
+                let mut octets = ip.octets();
+                mask_octets(&mut octets, prefix);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            },
+            _ => {
+                return None;
+            }
+        };
+
+        Some(Cidr {
+            network : network,
+            prefix : prefix,
+        })
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.network, *address) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                u32::from(ip) & v4_mask(self.prefix) == u32::from(network)
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mut octets = ip.octets();
+                mask_octets(&mut octets, self.prefix);
+                octets == network.octets()
+            },
+            _ => false,
+        }
+    }
+}
+
+fn v4_mask(prefix: u8) -> u32 {
+    if prefix == 0 {
+        0
+    }
+    else {
+        !0u32 << (32 - prefix as u32)
+    }
+}
+
+fn mask_octets(octets: &mut [u8; 16], prefix: u8) {
+    for (index, octet) in octets.iter_mut().enumerate() {
+        let kept = (prefix as usize).saturating_sub(index * 8);
+
+        if kept < 8 {
+            *octet &= !(0xFFu8 >> kept as u32);
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Cidr, String> {
+        let mut parts = text.splitn(2, '/');
+        let address = match parts.next().map(|part| part.parse::<IpAddr>()) {
+            Some(Ok(address)) => address,
+            _ => {
+                return Err(format!("'{}' is not an address or subnet", text));
+            }
+        };
+
+        let prefix = match (parts.next(), address) {
+            (Some(prefix), _) => {
+                match prefix.parse::<u8>() {
+                    Ok(prefix) => prefix,
+                    Err(_) => {
+                        return Err(format!("'{}' has a bad prefix length", text));
+                    }
+                }
+            },
+            (None, IpAddr::V4(_)) => 32,
+            (None, IpAddr::V6(_)) => 128,
+        };
+
+        Cidr::new(address, prefix).ok_or(format!("'{}' has a prefix longer than its address", text))
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct AccessList {
+    allowed : Vec<Cidr>,
+    denied : Vec<Cidr>,
+}
+
+impl AccessList {
+    // Lets everybody in until rules are added.
+    pub fn new() -> AccessList {
+        AccessList {
+            allowed : Vec::new(),
+            denied : Vec::new(),
+        }
+    }
+
+    pub fn allow(&mut self, subnet: Cidr) {
+        self.allowed.push(subnet);
+    }
+
+    pub fn deny(&mut self, subnet: Cidr) {
+        self.denied.push(subnet);
+    }
+
+    pub fn permits(&self, address: &IpAddr) -> bool {
+        if self.denied.iter().any(|subnet| subnet.contains(address)) {
+            return false;
+        }
+
+        self.allowed.is_empty() || self.allowed.iter().any(|subnet| subnet.contains(address))
+    }
+
+    // Reads rules in the format described at the top of this file. Errors name the line at fault.
+    pub fn parse(text: &str) -> Result<AccessList, String> {
+        let mut list = AccessList::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let rule = line.splitn(2, '#').next().unwrap_or("");
+            let words : Vec<&str> = rule.split_whitespace().collect();
+
+            if words.is_empty() {
+                continue;
+            }
+
+            if words.len() != 2 || (words[0] != "allow" && words[0] != "deny") {
+                return Err(format!("line {}: expected 'allow SUBNET' or 'deny SUBNET'", number + 1));
+            }
+
+            let subnet = try!(words[1].parse::<Cidr>().map_err(|error| format!("line {}: {}", number + 1, error)));
+
+            if words[0] == "allow" {
+                list.allow(subnet);
+            }
+            else {
+                list.deny(subnet);
+            }
+        }
+
+        Ok(list)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<AccessList, String> {
+        let mut text = String::new();
+
+        match File::open(path.as_ref()).and_then(|mut file| file.read_to_string(&mut text)) {
+            Ok(_) => AccessList::parse(&text),
+            Err(error) => Err(format!("could not read {}: {}", path.as_ref().display(), error)),
+        }
+    }
+}
+
+// ---------------------------------
+// |       AccessList Tests        |
+// ---------------------------------
+
+#[cfg(test)]
+mod test {
+
+    use std::net::IpAddr;
+    use access_list::{AccessList, Cidr};
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let lab : Cidr = "10.20.0.0/16".parse().unwrap();
+        assert!(lab.contains(&ip("10.20.0.1")));
+        assert!(lab.contains(&ip("10.20.255.255")));
+        assert!(!lab.contains(&ip("10.21.0.1")));
+        assert!(!lab.contains(&ip("::ffff:10.20.0.1")));
+
+        let odd : Cidr = "192.168.1.77/25".parse().unwrap();
+        assert_eq!(format!("{}", odd), "192.168.1.0/25");
+        assert!(odd.contains(&ip("192.168.1.127")));
+        assert!(!odd.contains(&ip("192.168.1.128")));
+
+        let v6 : Cidr = "fd00:20::/36".parse().unwrap();
+        assert!(v6.contains(&ip("fd00:20:fff::1")));
+        assert!(!v6.contains(&ip("fd00:20:1000::1")));
+        assert!(!v6.contains(&ip("10.20.0.1")));
+
+        let everything : Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(&ip("203.0.113.9")));
+
+        let single : Cidr = "::1".parse().unwrap();
+        assert_eq!(format!("{}", single), "::1/128");
+        assert!(single.contains(&ip("::1")));
+        assert!(!single.contains(&ip("::2")));
+    }
+
+    #[test]
+    fn test_cidr_rejects_nonsense() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/eight".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_access_list_rules() {
+        let list = AccessList::parse("
+            # The lab, except for the printers
+            allow 10.20.0.0/16
+            allow fd00:20::/32   # and its v6 half
+            deny  10.20.99.0/24
+        ").unwrap();
+
+        assert!(list.permits(&ip("10.20.1.1")));
+        assert!(list.permits(&ip("fd00:20::5")));
+        assert!(!list.permits(&ip("10.20.99.3")));
+        assert!(!list.permits(&ip("192.168.0.1")));
+
+        // Without allow rules, only the denied are kept out.
+        let list = AccessList::parse("deny 203.0.113.0/24").unwrap();
+        assert!(list.permits(&ip("10.0.0.1")));
+        assert!(!list.permits(&ip("203.0.113.200")));
+        assert!(AccessList::new().permits(&ip("::1")));
+    }
+
+    #[test]
+    fn test_access_list_names_the_bad_line() {
+        assert_eq!(AccessList::parse("allow 10.0.0.0/8\npermit 10.0.0.0/8"), Err(String::from("line 2: expected 'allow SUBNET' or 'deny SUBNET'")));
+        assert_eq!(AccessList::parse("\n\ndeny 10.0.0.0/40"), Err(String::from("line 3: '10.0.0.0/40' has a prefix longer than its address")));
+    }
+}
@@ -0,0 +1,425 @@
+/*
+ * Batched datagram I/O.
+ *
+ * A busy server can get thousands of datagrams a tick, and reading or writing them one system
+ * call at a time costs more than the work done on them. A DatagramBatch holds a run of datagrams
+ * together with their addresses, and receive_batch/send_batch move a whole batch in as few
+ * system calls as possible: recvmmsg and sendmmsg on Linux, and a plain loop of recv_from and
+ * send_to everywhere else.
+ *
+ * The batch owns its buffers and keeps them for its whole life, so filling and emptying it does
+ * not allocate.
+ */
+
+use std::io;
+use std::net;
+
+pub struct DatagramBatch {
+    buffers : Vec<Vec<u8>>,
+    lengths : Vec<usize>,
+    addresses : Vec<net::SocketAddr>,
+    count : usize,
+}
+
+impl DatagramBatch {
+    pub fn new(capacity: usize, buffer_size: usize) -> DatagramBatch {
+        let unspecified = net::SocketAddr::V4(net::SocketAddrV4::new(net::Ipv4Addr::new(0, 0, 0, 0), 0));
+
+        let mut buffers = Vec::with_capacity(capacity);
+        for _ in 0..capacity {
+            buffers.push(vec![0; buffer_size]);
+        }
+
+        DatagramBatch {
+            buffers : buffers,
+            lengths : vec![0; capacity],
+            addresses : vec![unspecified; capacity],
+            count : 0,
+        }
+    }
+
+    // Adds a datagram for `address`, letting `write` fill in its contents and return their length.
+    // False if the batch is full or `write` gives up.
+    pub fn push<F>(&mut self, address: &net::SocketAddr, write: F) -> bool where F: FnOnce(&mut [u8]) -> Option<usize> {
+        if self.is_full() {
+            return false;
+        }
+
+        match write(&mut self.buffers[self.count]) {
+            Some(length) => {
+                self.commit(length, *address);
+                true
+            },
+            None => {
+                false
+            }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> (&[u8], &net::SocketAddr) {
+        assert!(index < self.count);
+
+        (&self.buffers[index][..self.lengths[index]], &self.addresses[index])
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count == self.buffers.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffers.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.count = 0;
+    }
+
+    // The whole buffer of the next free slot, for receiving into.
+    fn free_slot(&mut self, offset: usize) -> &mut [u8] {
+        &mut self.buffers[self.count + offset]
+    }
+
+    // Marks the next free slot as holding a datagram.
+    fn commit(&mut self, length: usize, address: net::SocketAddr) {
+        assert!(!self.is_full());
+
+        self.lengths[self.count] = length;
+        self.addresses[self.count] = address;
+        self.count += 1;
+    }
+}
+
+pub use self::platform::{receive_batch, send_batch};
+
+// True if the socket will take nothing more for now, so the rest of a batch has to wait. Any
+// other error belongs to the one datagram that caused it, such as one for an unreachable host.
+fn is_backed_up(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::WouldBlock || is_out_of_buffers(error)
+}
+
+#[cfg(unix)]
+fn is_out_of_buffers(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(::libc::ENOBUFS)
+}
+
+#[cfg(not(unix))]
+fn is_out_of_buffers(_error: &io::Error) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+
+    use std::cmp;
+    use std::io;
+    use std::mem;
+    use std::net;
+    use std::ptr;
+    use std::os::unix::io::AsRawFd;
+    use libc;
+    use mio;
+    use batch::{DatagramBatch, is_backed_up};
+
+    // How many datagrams go into one recvmmsg or sendmmsg. The message headers for them live on
+    // the stack, so this is kept modest.
+    const SYSCALL_BATCH: usize = 64;
+
+    // Fills the rest of `batch` with whatever datagrams are waiting. Returns how many arrived.
+    pub fn receive_batch(socket: &mio::udp::UdpSocket, batch: &mut DatagramBatch) -> usize {
+        let mut received = 0;
+
+        while !batch.is_full() {
+            let count = cmp::min(batch.capacity() - batch.len(), SYSCALL_BATCH);
+
+            let mut headers: [libc::mmsghdr; SYSCALL_BATCH] = unsafe { mem::zeroed() };
+            let mut iovecs: [libc::iovec; SYSCALL_BATCH] = unsafe { mem::zeroed() };
+            let mut names: [libc::sockaddr_storage; SYSCALL_BATCH] = unsafe { mem::zeroed() };
+
+            for i in 0..count {
+                let buffer = batch.free_slot(i);
+
+                iovecs[i].iov_base = buffer.as_mut_ptr() as *mut libc::c_void;
+                iovecs[i].iov_len = buffer.len();
+
+                headers[i].msg_hdr.msg_name = &mut names[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+                headers[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                headers[i].msg_hdr.msg_iov = &mut iovecs[i];
+                headers[i].msg_hdr.msg_iovlen = 1;
+            }
+
+            let result = unsafe {
+                libc::recvmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), count as libc::c_uint,
+                               libc::MSG_DONTWAIT, ptr::null_mut())
+            };
+
+            if result <= 0 {
+                break;
+            }
+
+            for i in 0..result as usize {
+                // Anything we cannot make sense of gets port 0, which connections already ignore.
+                let address = match to_socket_addr(&names[i]) {
+                    Some(address) => address,
+                    None => net::SocketAddr::V4(net::SocketAddrV4::new(net::Ipv4Addr::new(0, 0, 0, 0), 0)),
+                };
+
+                batch.commit(headers[i].msg_len as usize, address);
+            }
+
+            received += result as usize;
+
+            if (result as usize) < count {
+                break;
+            }
+        }
+        received
+    }
+
+    // Sends every datagram in `batch`, stopping early only if the socket will not take more. A
+    // datagram the system refuses for any other reason is dropped and the rest still go out.
+    // Returns how many were sent.
+    pub fn send_batch(socket: &mio::udp::UdpSocket, batch: &DatagramBatch) -> usize {
+        let mut next = 0;
+        let mut sent = 0;
+
+        while next < batch.len() {
+            let count = cmp::min(batch.len() - next, SYSCALL_BATCH);
+
+            let mut headers: [libc::mmsghdr; SYSCALL_BATCH] = unsafe { mem::zeroed() };
+            let mut iovecs: [libc::iovec; SYSCALL_BATCH] = unsafe { mem::zeroed() };
+            let mut names: [libc::sockaddr_storage; SYSCALL_BATCH] = unsafe { mem::zeroed() };
+
+            for i in 0..count {
+                let (datagram, address) = batch.get(next + i);
+
+                iovecs[i].iov_base = datagram.as_ptr() as *mut libc::c_void;
+                iovecs[i].iov_len = datagram.len();
+
+                headers[i].msg_hdr.msg_name = &mut names[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+                headers[i].msg_hdr.msg_namelen = to_sockaddr(address, &mut names[i]);
+                headers[i].msg_hdr.msg_iov = &mut iovecs[i];
+                headers[i].msg_hdr.msg_iovlen = 1;
+            }
+
+            let result = unsafe {
+                libc::sendmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), count as libc::c_uint, libc::MSG_DONTWAIT)
+            };
+
+            // sendmmsg stops at the first datagram that fails, and only reports the error if
+            // that was the first one; otherwise it turns up on the next call.
+            if result < 0 {
+                if is_backed_up(&io::Error::last_os_error()) {
+                    break;
+                }
+                next += 1;
+                continue;
+            }
+
+            if result == 0 {
+                break;
+            }
+
+            next += result as usize;
+            sent += result as usize;
+        }
+        sent
+    }
+
+    fn to_sockaddr(address: &net::SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match *address {
+            net::SocketAddr::V4(ref v4address) => {
+                let sockaddr = unsafe { &mut *(storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+
+                sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+                sockaddr.sin_port = v4address.port().to_be();
+                sockaddr.sin_addr = libc::in_addr { s_addr: u32::from(*v4address.ip()).to_be() };
+
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            },
+            net::SocketAddr::V6(ref v6address) => {
+                let sockaddr = unsafe { &mut *(storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+
+                sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sockaddr.sin6_port = v6address.port().to_be();
+                sockaddr.sin6_flowinfo = v6address.flowinfo();
+                sockaddr.sin6_addr.s6_addr = v6address.ip().octets();
+                sockaddr.sin6_scope_id = v6address.scope_id();
+
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        }
+    }
+
+    fn to_socket_addr(storage: &libc::sockaddr_storage) -> Option<net::SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let sockaddr = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+                let ip = net::Ipv4Addr::from(u32::from_be(sockaddr.sin_addr.s_addr));
+
+                Some(net::SocketAddr::V4(net::SocketAddrV4::new(ip, u16::from_be(sockaddr.sin_port))))
+            },
+            libc::AF_INET6 => {
+                let sockaddr = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+                let ip = net::Ipv6Addr::from(sockaddr.sin6_addr.s6_addr);
+
+                Some(net::SocketAddr::V6(net::SocketAddrV6::new(ip, u16::from_be(sockaddr.sin6_port),
+                                                                 sockaddr.sin6_flowinfo, sockaddr.sin6_scope_id)))
+            },
+            _ => {
+                None
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+
+    use mio;
+    use batch::{DatagramBatch, is_backed_up};
+
+    pub fn receive_batch(socket: &mio::udp::UdpSocket, batch: &mut DatagramBatch) -> usize {
+        let mut received = 0;
+
+        while !batch.is_full() {
+            let result = socket.recv_from(batch.free_slot(0));
+
+            match result {
+                Ok(Some((length, address))) => {
+                    batch.commit(length, address);
+                    received += 1;
+                },
+                _ => {
+                    break;
+                }
+            }
+        }
+        received
+    }
+
+    pub fn send_batch(socket: &mio::udp::UdpSocket, batch: &DatagramBatch) -> usize {
+        let mut sent = 0;
+
+        for index in 0..batch.len() {
+            let (datagram, address) = batch.get(index);
+
+            match socket.send_to(datagram, address) {
+                Ok(Some(_)) => {
+                    sent += 1;
+                },
+                Ok(None) => {
+                    break;
+                },
+                Err(ref error) if is_backed_up(error) => {
+                    break;
+                },
+                Err(_) => {}
+            }
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::net;
+    use std::thread;
+    use std::time::Duration;
+    use mio;
+    use batch::{DatagramBatch, receive_batch, send_batch};
+
+    fn loopback(port: u16) -> net::SocketAddr {
+        net::SocketAddr::V4(net::SocketAddrV4::new(net::Ipv4Addr::new(127, 0, 0, 1), port))
+    }
+
+    #[test]
+    fn test_batch_push_and_get() {
+        let mut batch = DatagramBatch::new(2, 16);
+        let address = loopback(4000);
+
+        assert!(batch.push(&address, |buffer| { buffer[0] = 7; Some(1) }));
+        assert!(!batch.push(&address, |_| None));
+        assert!(batch.push(&address, |buffer| { buffer[..3].copy_from_slice(&[1, 2, 3]); Some(3) }));
+        assert!(batch.is_full());
+        assert!(!batch.push(&address, |_| Some(1)));
+
+        assert_eq!(batch.get(0).0, &[7]);
+        assert_eq!(batch.get(1).0, &[1, 2, 3]);
+        assert_eq!(*batch.get(1).1, address);
+
+        batch.clear();
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn test_batch_round_trip_over_loopback() {
+        let sender = mio::udp::UdpSocket::bind(&loopback(0)).unwrap();
+        let receiver = mio::udp::UdpSocket::bind(&loopback(0)).unwrap();
+        let destination = receiver.local_addr().unwrap();
+
+        let mut outgoing = DatagramBatch::new(100, 64);
+        for i in 0..100 {
+            assert!(outgoing.push(&destination, |buffer| { buffer[0] = i as u8; buffer[1] = 0xAB; Some(2) }));
+        }
+
+        assert_eq!(send_batch(&sender, &outgoing), 100);
+
+        let mut incoming = DatagramBatch::new(128, 64);
+        for _ in 0..100 {
+            receive_batch(&receiver, &mut incoming);
+            if incoming.len() == 100 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(incoming.len(), 100);
+        for i in 0..100 {
+            let (datagram, address) = incoming.get(i);
+
+            assert_eq!(datagram, &[i as u8, 0xAB]);
+            assert_eq!(*address, sender.local_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_batch_skips_a_destination_it_cannot_send_to() {
+        let sender = mio::udp::UdpSocket::bind(&loopback(0)).unwrap();
+        let receiver = mio::udp::UdpSocket::bind(&loopback(0)).unwrap();
+        let destination = receiver.local_addr().unwrap();
+
+        // An IPv4 socket cannot send to an IPv6 address at all.
+        let unsendable = net::SocketAddr::V6(net::SocketAddrV6::new(net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 4000, 0, 0));
+
+        let mut outgoing = DatagramBatch::new(7, 64);
+        for i in 0..7 {
+            let address = if i == 3 { unsendable } else { destination };
+            assert!(outgoing.push(&address, |buffer| { buffer[0] = i as u8; Some(1) }));
+        }
+
+        assert_eq!(send_batch(&sender, &outgoing), 6);
+
+        let mut incoming = DatagramBatch::new(8, 64);
+        for _ in 0..100 {
+            receive_batch(&receiver, &mut incoming);
+            if incoming.len() == 6 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(incoming.len(), 6);
+        for (i, &expected) in [0, 1, 2, 4, 5, 6].iter().enumerate() {
+            assert_eq!(incoming.get(i).0, &[expected]);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+/*
+ * A pool of reusable datagram buffers.
+ *
+ * Every send and receive needs somewhere to put a whole datagram. Allocating a fresh Vec for each
+ * one puts the allocator on the hot path, so instead connections borrow a buffer from the pool,
+ * use it, and hand it back. Once the pool has warmed up to however many buffers are in flight at
+ * a time, sending and receiving stop allocating altogether.
+ *
+ * The pool never shrinks below what it was created with and never holds more than that either;
+ * buffers released into a full pool are simply freed.
+ */
+
+pub struct BufferPool {
+    free : Vec<Vec<u8>>,
+    buffer_size : usize,
+    capacity : usize,
+    allocations : usize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, capacity: usize) -> BufferPool {
+        let mut free = Vec::with_capacity(capacity);
+
+        for _ in 0..capacity {
+            free.push(vec![0; buffer_size]);
+        }
+
+        BufferPool {
+            free : free,
+            buffer_size : buffer_size,
+            capacity : capacity,
+            allocations : capacity,
+        }
+    }
+
+    // Hands out a buffer exactly buffer_size long. Its contents are whatever was left in it.
+    // Only allocates if every buffer is already out.
+    pub fn acquire(&mut self) -> Vec<u8> {
+        match self.free.pop() {
+            Some(buffer) => {
+                buffer
+            },
+            None => {
+                self.allocations += 1;
+                vec![0; self.buffer_size]
+            }
+        }
+    }
+
+    pub fn release(&mut self, mut buffer: Vec<u8>) {
+        if buffer.capacity() < self.buffer_size || self.free.len() >= self.capacity {
+            return;
+        }
+
+        // Never reallocates: the capacity was just checked.
+        buffer.resize(self.buffer_size, 0);
+        self.free.push(buffer);
+    }
+
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn get_buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    // How many buffers the pool has ever had to create, including the ones it started with.
+    pub fn get_allocations(&self) -> usize {
+        self.allocations
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use buffer_pool::BufferPool;
+
+    #[test]
+    fn test_buffer_pool_reuses_buffers() {
+        let mut pool = BufferPool::new(1472, 2);
+
+        for _ in 0..100 {
+            let first = pool.acquire();
+            let second = pool.acquire();
+
+            assert_eq!(first.len(), 1472);
+            assert_eq!(pool.available(), 0);
+
+            pool.release(first);
+            pool.release(second);
+        }
+
+        assert_eq!(pool.available(), 2);
+        assert_eq!(pool.get_allocations(), 2);
+    }
+
+    #[test]
+    fn test_buffer_pool_grows_when_drained_but_stays_bounded() {
+        let mut pool = BufferPool::new(64, 1);
+
+        let first = pool.acquire();
+        let mut second = pool.acquire();
+        assert_eq!(pool.get_allocations(), 2);
+
+        // Whatever length a buffer comes back at, it goes out again at full size.
+        second.truncate(3);
+        pool.release(second);
+        pool.release(first);
+
+        assert_eq!(pool.available(), 1);
+        assert_eq!(pool.acquire().len(), 64);
+    }
+
+    #[test]
+    fn test_buffer_pool_rejects_small_buffers() {
+        let mut pool = BufferPool::new(64, 4);
+        let _ = pool.acquire();
+
+        pool.release(Vec::with_capacity(8));
+
+        assert_eq!(pool.available(), 3);
+    }
+}
@@ -0,0 +1,172 @@
+/*
+ * Time sources for the reliability layer.
+ *
+ * Connections used to learn about time only through the deltaTime handed to Update, adding it
+ * onto every queued packet. Those f32 sums drift and lose precision the longer a session runs.
+ * Instead, connections now ask a Clock for the current time, stamp packets with it when they are
+ * sent, received or acked, and compare stamps to work out ages and timeouts.
+ *
+ * SystemClock reads the monotonic Instant clock. MockClock only moves when told to, which makes
+ * time-dependent behaviour testable without sleeping.
+ */
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::u64;
+
+pub trait Clock: Send + Sync {
+    // Time passed since some fixed starting point. Only the difference between two readings
+    // of the same clock means anything.
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock {
+    start : Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start : Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+pub struct MockClock {
+    now : Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            now : Mutex::new(Duration::new(0, 0)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + by;
+    }
+
+    pub fn advance_seconds(&self, seconds: f32) {
+        self.advance(seconds_to_duration(seconds));
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
+
+// As good as never: some 35 000 years.
+pub const FOREVER_SECONDS: u64 = 1 << 40;
+
+pub fn system_clock() -> Arc<Clock> {
+    Arc::new(SystemClock::new())
+}
+
+pub fn as_seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + (duration.subsec_nanos() as f64 / 1_000_000_000.0)
+}
+
+pub fn seconds_to_duration(seconds: f32) -> Duration {
+    assert!(seconds >= 0.0);
+
+    let whole_seconds = seconds.trunc();
+    let nanos = ((seconds - whole_seconds) * 1_000_000_000.0) as u32;
+
+    Duration::new(whole_seconds as u64, nanos)
+}
+
+// `seconds` after `now`, for deadlines such as bans that come from a person rather than from us.
+// Anything past FOREVER_SECONDS, infinity included, waits that long, and a deadline that would
+// overflow is pinned to the last moment a Duration can hold. NaN and negative spans end at once.
+pub fn deadline_after(now: Duration, seconds: f64) -> Duration {
+    let span = if !(seconds > 0.0) {
+        Duration::new(0, 0)
+    }
+    else if seconds >= FOREVER_SECONDS as f64 {
+        Duration::from_secs(FOREVER_SECONDS)
+    }
+    else {
+        let whole_seconds = seconds.trunc();
+        Duration::new(whole_seconds as u64, ((seconds - whole_seconds) * 1_000_000_000.0) as u32)
+    };
+
+    match now.checked_add(span) {
+        Some(deadline) => deadline,
+        None => Duration::new(u64::MAX, 999_999_999),
+    }
+}
+
+// Seconds from `earlier` to `later`; zero if `later` is not actually later.
+pub fn seconds_between(earlier: Duration, later: Duration) -> f64 {
+    match later.checked_sub(earlier) {
+        Some(difference) => {
+            as_seconds(difference)
+        },
+        None => {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::time::Duration;
+    use std::f64;
+    use std::u64;
+    use clock::{Clock, MockClock, SystemClock, FOREVER_SECONDS, as_seconds, deadline_after, seconds_between, seconds_to_duration};
+
+    #[test]
+    fn test_mock_clock_only_moves_when_advanced() {
+        let clock = MockClock::new();
+
+        assert_eq!(clock.now(), Duration::new(0, 0));
+        assert_eq!(clock.now(), Duration::new(0, 0));
+
+        clock.advance(Duration::from_millis(250));
+        clock.advance_seconds(1.5);
+
+        assert_eq!(clock.now(), Duration::from_millis(1750));
+    }
+
+    #[test]
+    fn test_system_clock_is_monotonic() {
+        let clock = SystemClock::new();
+
+        let first = clock.now();
+        let second = clock.now();
+
+        assert!(second >= first);
+    }
+
+    #[test]
+    fn test_seconds_conversions() {
+        assert_eq!(as_seconds(Duration::from_millis(1500)), 1.5);
+        assert_eq!(seconds_to_duration(2.25), Duration::from_millis(2250));
+        assert_eq!(seconds_between(Duration::from_secs(3), Duration::from_secs(5)), 2.0);
+        assert_eq!(seconds_between(Duration::from_secs(5), Duration::from_secs(3)), 0.0);
+    }
+
+    #[test]
+    fn test_deadline_after_never_overflows() {
+        let now = Duration::from_secs(100);
+        let forever = now + Duration::from_secs(FOREVER_SECONDS);
+
+        assert_eq!(deadline_after(now, 2.5), Duration::from_millis(102500));
+        assert_eq!(deadline_after(now, 1e30), forever);
+        assert_eq!(deadline_after(now, f64::INFINITY), forever);
+        assert_eq!(deadline_after(now, f64::NAN), now);
+        assert_eq!(deadline_after(now, -1.0), now);
+        assert_eq!(deadline_after(Duration::from_secs(u64::MAX), 1.0), Duration::new(u64::MAX, 999_999_999));
+    }
+}
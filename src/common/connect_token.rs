@@ -0,0 +1,470 @@
+/*
+ * Connect tokens, after the ones in netcode.io.
+ *
+ * Deciding who may join a server is left to something else, like a web login, which shares a
+ * private key with the servers. For each client it lets in, it mints a token with
+ * generate_connect_token and hands it over. The token says which servers the client may connect
+ * to and until when, and gives it two random keys. It also carries a private section, sealed with
+ * ChaCha20-Poly1305 under the private key, that only the servers can open: the client's id, the
+ * same server addresses and the same two keys.
+ *
+ * The client sends the private section in its ConnectionRequest. The server opens it, checks the
+ * token has not expired and names this server, and only then gives the client a peer slot. Both
+ * ends mix the two keys into their session keys, so only the client the token was given to can
+ * complete the handshake, even if someone else copies the token off the wire.
+ *
+ * Timestamps are seconds since the Unix epoch, so the authority and the servers need clocks that
+ * roughly agree.
+ */
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use rand::{Rng, OsRng};
+use net::Address;
+use packet::{write_u16, write_u32, write_u64, read_u16, read_u32, read_u64};
+
+// The private key shared by the authority and the servers, and the keys a token hands out.
+pub const CONNECT_TOKEN_KEY_SIZE: usize = 32;
+
+pub const MAX_SERVERS_PER_CONNECT: usize = 8;
+
+// An address as a family byte, 16 bytes of IP (IPv4 uses the first 4), and the port.
+const ADDRESS_SIZE: usize = 19;
+
+const ADDRESS_LIST_SIZE: usize = 4 + MAX_SERVERS_PER_CONNECT * ADDRESS_SIZE;
+
+// Client id, server addresses and both keys, padded out to a round size.
+const PRIVATE_DATA_SIZE: usize = 240;
+
+// The private section once sealed, tag included.
+pub const CONNECT_TOKEN_PRIVATE_SIZE: usize = PRIVATE_DATA_SIZE + 16;
+
+// What a client sends in its ConnectionRequest: the expiry, the nonce and the private section.
+pub const CONNECT_TOKEN_REQUEST_SIZE: usize = 8 + 8 + CONNECT_TOKEN_PRIVATE_SIZE;
+
+// A whole token as the authority hands it to the client.
+pub const CONNECT_TOKEN_SIZE: usize = 4 + 8 + 8 + 8 + CONNECT_TOKEN_PRIVATE_SIZE + ADDRESS_LIST_SIZE + 2 * CONNECT_TOKEN_KEY_SIZE;
+
+type Key = [u8; CONNECT_TOKEN_KEY_SIZE];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectToken {
+    pub protocol_id : u32,
+    pub create_timestamp : u64,
+    pub expire_timestamp : u64,
+    pub nonce : u64,
+    pub server_addresses : Vec<Address>,  // Try these in order
+    pub client_to_server_key : Key,
+    pub server_to_client_key : Key,
+    private_data : Vec<u8>,  // Sealed; only the servers can read it
+}
+
+// What a server finds in the private section.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrivateConnectToken {
+    pub client_id : u64,
+    pub server_addresses : Vec<Address>,
+    pub client_to_server_key : Key,
+    pub server_to_client_key : Key,
+}
+
+// Mints a token letting `client_id` connect to any of `server_addresses` for the next
+// `expire_seconds`. None if the key is the wrong size, there are no addresses or too many, or the
+// token would expire past the end of time.
+pub fn generate_connect_token(private_key: &[u8], protocol_id: u32, client_id: u64, server_addresses: &[Address], expire_seconds: u64) -> Option<ConnectToken> {
+    generate_connect_token_at(private_key, protocol_id, client_id, server_addresses, unix_time(), expire_seconds)
+}
+
+fn generate_connect_token_at(private_key: &[u8], protocol_id: u32, client_id: u64, server_addresses: &[Address], now: u64, expire_seconds: u64) -> Option<ConnectToken> {
+    if private_key.len() != CONNECT_TOKEN_KEY_SIZE || server_addresses.is_empty() || server_addresses.len() > MAX_SERVERS_PER_CONNECT {
+        return None;
+    }
+
+    let expire_timestamp = match now.checked_add(expire_seconds) {
+        Some(expire_timestamp) => expire_timestamp,
+        None => {
+            return None;
+        }
+    };
+
+    let mut rng = OsRng::new().expect("No source of randomness for a connect token.");
+    let mut client_to_server_key = [0u8; CONNECT_TOKEN_KEY_SIZE];
+    let mut server_to_client_key = [0u8; CONNECT_TOKEN_KEY_SIZE];
+    rng.fill_bytes(&mut client_to_server_key);
+    rng.fill_bytes(&mut server_to_client_key);
+
+    let private = PrivateConnectToken {
+        client_id : client_id,
+        server_addresses : server_addresses.to_vec(),
+        client_to_server_key : client_to_server_key,
+        server_to_client_key : server_to_client_key,
+    };
+
+    let nonce = rng.gen::<u64>();
+
+    let mut plaintext = [0u8; PRIVATE_DATA_SIZE];
+    private.write_to(&mut plaintext);
+
+    let mut private_data = vec![0u8; CONNECT_TOKEN_PRIVATE_SIZE];
+    {
+        let (sealed, tag) = private_data.split_at_mut(PRIVATE_DATA_SIZE);
+        private_cipher(private_key, protocol_id, expire_timestamp, nonce).encrypt(&plaintext, sealed, tag);
+    }
+
+    Some(ConnectToken {
+        protocol_id : protocol_id,
+        create_timestamp : now,
+        expire_timestamp : expire_timestamp,
+        nonce : nonce,
+        server_addresses : private.server_addresses,
+        client_to_server_key : client_to_server_key,
+        server_to_client_key : server_to_client_key,
+        private_data : private_data,
+    })
+}
+
+// The server's half: takes what the client sent, checks it has not expired, and opens the private
+// section. None if any of that fails.
+pub fn open_connect_token(private_key: &[u8], protocol_id: u32, request: &[u8], now: u64) -> Option<PrivateConnectToken> {
+    if private_key.len() != CONNECT_TOKEN_KEY_SIZE || request.len() != CONNECT_TOKEN_REQUEST_SIZE {
+        return None;
+    }
+
+    let expire_timestamp = read_u64(request, 0);
+    let nonce = read_u64(request, 8);
+
+    if now >= expire_timestamp {
+        return None;
+    }
+
+    let sealed = &request[16..];
+    let mut plaintext = [0u8; PRIVATE_DATA_SIZE];
+
+    if !private_cipher(private_key, protocol_id, expire_timestamp, nonce).decrypt(&sealed[..PRIVATE_DATA_SIZE], &mut plaintext, &sealed[PRIVATE_DATA_SIZE..]) {
+        return None;
+    }
+
+    PrivateConnectToken::read_from(&plaintext)
+}
+
+pub fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+impl ConnectToken {
+    // Lays the token out for handing to the client. None if `buffer` is under CONNECT_TOKEN_SIZE.
+    pub fn write_to(&self, buffer: &mut [u8]) -> Option<usize> {
+        if buffer.len() < CONNECT_TOKEN_SIZE {
+            return None;
+        }
+
+        write_u32(buffer, 0, self.protocol_id);
+        write_u64(buffer, 4, self.create_timestamp);
+        write_u64(buffer, 12, self.expire_timestamp);
+        write_u64(buffer, 20, self.nonce);
+        buffer[28..28 + CONNECT_TOKEN_PRIVATE_SIZE].copy_from_slice(&self.private_data);
+
+        let keys = 28 + CONNECT_TOKEN_PRIVATE_SIZE + ADDRESS_LIST_SIZE;
+        write_addresses(buffer, 28 + CONNECT_TOKEN_PRIVATE_SIZE, &self.server_addresses);
+        buffer[keys..keys + CONNECT_TOKEN_KEY_SIZE].copy_from_slice(&self.client_to_server_key);
+        buffer[keys + CONNECT_TOKEN_KEY_SIZE..keys + 2 * CONNECT_TOKEN_KEY_SIZE].copy_from_slice(&self.server_to_client_key);
+
+        Some(CONNECT_TOKEN_SIZE)
+    }
+
+    pub fn read_from(buffer: &[u8]) -> Option<ConnectToken> {
+        if buffer.len() < CONNECT_TOKEN_SIZE {
+            return None;
+        }
+
+        let server_addresses = match read_addresses(buffer, 28 + CONNECT_TOKEN_PRIVATE_SIZE) {
+            Some(addresses) => addresses,
+            None => {
+                return None;
+            }
+        };
+
+        let keys = 28 + CONNECT_TOKEN_PRIVATE_SIZE + ADDRESS_LIST_SIZE;
+        let mut client_to_server_key = [0u8; CONNECT_TOKEN_KEY_SIZE];
+        let mut server_to_client_key = [0u8; CONNECT_TOKEN_KEY_SIZE];
+        client_to_server_key.copy_from_slice(&buffer[keys..keys + CONNECT_TOKEN_KEY_SIZE]);
+        server_to_client_key.copy_from_slice(&buffer[keys + CONNECT_TOKEN_KEY_SIZE..keys + 2 * CONNECT_TOKEN_KEY_SIZE]);
+
+        Some(ConnectToken {
+            protocol_id : read_u32(buffer, 0),
+            create_timestamp : read_u64(buffer, 4),
+            expire_timestamp : read_u64(buffer, 12),
+            nonce : read_u64(buffer, 20),
+            server_addresses : server_addresses,
+            client_to_server_key : client_to_server_key,
+            server_to_client_key : server_to_client_key,
+            private_data : buffer[28..28 + CONNECT_TOKEN_PRIVATE_SIZE].to_vec(),
+        })
+    }
+
+    // Writes the part of the token that goes in a ConnectionRequest. None if it does not fit.
+    pub fn write_request(&self, buffer: &mut [u8]) -> Option<usize> {
+        if buffer.len() < CONNECT_TOKEN_REQUEST_SIZE {
+            return None;
+        }
+
+        write_u64(buffer, 0, self.expire_timestamp);
+        write_u64(buffer, 8, self.nonce);
+        buffer[16..CONNECT_TOKEN_REQUEST_SIZE].copy_from_slice(&self.private_data);
+
+        Some(CONNECT_TOKEN_REQUEST_SIZE)
+    }
+}
+
+impl PrivateConnectToken {
+    fn write_to(&self, buffer: &mut [u8; PRIVATE_DATA_SIZE]) {
+        write_u64(buffer, 0, self.client_id);
+        write_addresses(buffer, 8, &self.server_addresses);
+
+        let keys = 8 + ADDRESS_LIST_SIZE;
+        buffer[keys..keys + CONNECT_TOKEN_KEY_SIZE].copy_from_slice(&self.client_to_server_key);
+        buffer[keys + CONNECT_TOKEN_KEY_SIZE..keys + 2 * CONNECT_TOKEN_KEY_SIZE].copy_from_slice(&self.server_to_client_key);
+    }
+
+    fn read_from(buffer: &[u8; PRIVATE_DATA_SIZE]) -> Option<PrivateConnectToken> {
+        let server_addresses = match read_addresses(buffer, 8) {
+            Some(addresses) => addresses,
+            None => {
+                return None;
+            }
+        };
+
+        let keys = 8 + ADDRESS_LIST_SIZE;
+        let mut client_to_server_key = [0u8; CONNECT_TOKEN_KEY_SIZE];
+        let mut server_to_client_key = [0u8; CONNECT_TOKEN_KEY_SIZE];
+        client_to_server_key.copy_from_slice(&buffer[keys..keys + CONNECT_TOKEN_KEY_SIZE]);
+        server_to_client_key.copy_from_slice(&buffer[keys + CONNECT_TOKEN_KEY_SIZE..keys + 2 * CONNECT_TOKEN_KEY_SIZE]);
+
+        Some(PrivateConnectToken {
+            client_id : read_u64(buffer, 0),
+            server_addresses : server_addresses,
+            client_to_server_key : client_to_server_key,
+            server_to_client_key : server_to_client_key,
+        })
+    }
+}
+
+// Remembers the address each token was first used from until the token expires, so one copied off
+// the wire cannot take a second peer slot from somewhere else.
+pub struct TokenRegistry {
+    entries : Vec<([u8; 16], Address, u64)>,  // The private section's tag, who used it, and expiry
+}
+
+impl TokenRegistry {
+    pub fn new() -> TokenRegistry {
+        TokenRegistry {
+            entries : Vec::new(),
+        }
+    }
+
+    // False if the token in `request`, an opened one, is already in use from another address.
+    pub fn claim(&mut self, request: &[u8], address: &Address, now: u64) -> bool {
+        let mut tag = [0u8; 16];
+        tag.copy_from_slice(&request[CONNECT_TOKEN_REQUEST_SIZE - 16..CONNECT_TOKEN_REQUEST_SIZE]);
+
+        self.entries.retain(|entry| entry.2 > now);
+
+        match self.entries.iter().find(|entry| entry.0 == tag) {
+            Some(entry) => {
+                return entry.1 == *address;
+            },
+            None => {}
+        }
+
+        self.entries.push((tag, address.clone(), read_u64(request, 0)));
+        true
+    }
+}
+
+// The protocol id and expiry ride along unencrypted, so they are bound to the private section as
+// associated data; changing either breaks it. Every token has its own random nonce.
+fn private_cipher(private_key: &[u8], protocol_id: u32, expire_timestamp: u64, nonce: u64) -> ChaCha20Poly1305 {
+    let mut additional = [0u8; 12];
+    let mut nonce_bytes = [0u8; 8];
+
+    write_u32(&mut additional, 0, protocol_id);
+    write_u64(&mut additional, 4, expire_timestamp);
+    write_u64(&mut nonce_bytes, 0, nonce);
+
+    ChaCha20Poly1305::new(private_key, &nonce_bytes, &additional)
+}
+
+fn write_addresses(buffer: &mut [u8], offset: usize, addresses: &[Address]) {
+    write_u32(buffer, offset, addresses.len() as u32);
+
+    for (index, address) in addresses.iter().enumerate() {
+        let start = offset + 4 + index * ADDRESS_SIZE;
+
+        match address.get_address() {
+            IpAddr::V4(ip) => {
+                buffer[start] = 4;
+                buffer[start + 1..start + 5].copy_from_slice(&ip.octets());
+            },
+            IpAddr::V6(ip) => {
+                buffer[start] = 6;
+                buffer[start + 1..start + 17].copy_from_slice(&ip.octets());
+            }
+        }
+        write_u16(buffer, start + 17, address.get_port());
+    }
+}
+
+fn read_addresses(buffer: &[u8], offset: usize) -> Option<Vec<Address>> {
+    let count = read_u32(buffer, offset) as usize;
+
+    if count == 0 || count > MAX_SERVERS_PER_CONNECT {
+        return None;
+    }
+
+    let mut addresses = Vec::with_capacity(count);
+
+    for index in 0..count {
+        let start = offset + 4 + index * ADDRESS_SIZE;
+        let port = read_u16(buffer, start + 17);
+
+        let ip = match buffer[start] {
+            4 => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&buffer[start + 1..start + 5]);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            },
+            6 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&buffer[start + 1..start + 17]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            },
+            _ => {
+                return None;
+            }
+        };
+
+        addresses.push(Address::new(ip, port));
+    }
+
+    Some(addresses)
+}
+
+// ---------------------------------
+// |      ConnectToken Tests       |
+// ---------------------------------
+
+#[cfg(test)]
+mod test {
+
+    use std::u64;
+    use connect_token::{ConnectToken, TokenRegistry, generate_connect_token, generate_connect_token_at, open_connect_token,
+                        CONNECT_TOKEN_SIZE, CONNECT_TOKEN_REQUEST_SIZE, MAX_SERVERS_PER_CONNECT};
+    use net::Address;
+
+    const PRIVATE_KEY: [u8; 32] = [7; 32];
+
+    fn servers() -> Vec<Address> {
+        vec!["127.0.0.1:8890".parse().unwrap(), "[::1]:8891".parse().unwrap()]
+    }
+
+    #[test]
+    fn test_connect_token_round_trip() {
+        let token = generate_connect_token(&PRIVATE_KEY, 0x4C494645, 42, &servers(), 30).unwrap();
+        assert_eq!(token.expire_timestamp, token.create_timestamp + 30);
+
+        // As handed to the client.
+        let mut buffer = [0u8; CONNECT_TOKEN_SIZE];
+        assert_eq!(token.write_to(&mut buffer), Some(CONNECT_TOKEN_SIZE));
+        assert_eq!(ConnectToken::read_from(&buffer), Some(token.clone()));
+
+        // As the server sees it.
+        let mut request = [0u8; CONNECT_TOKEN_REQUEST_SIZE];
+        assert_eq!(token.write_request(&mut request), Some(CONNECT_TOKEN_REQUEST_SIZE));
+
+        let private = open_connect_token(&PRIVATE_KEY, 0x4C494645, &request, token.create_timestamp).unwrap();
+        assert_eq!(private.client_id, 42);
+        assert_eq!(private.server_addresses, servers());
+        assert_eq!(private.client_to_server_key, token.client_to_server_key);
+        assert_eq!(private.server_to_client_key, token.server_to_client_key);
+    }
+
+    #[test]
+    fn test_connect_token_rejects_forgery() {
+        let token = generate_connect_token(&PRIVATE_KEY, 0x4C494645, 42, &servers(), 30).unwrap();
+        let now = token.create_timestamp;
+
+        let mut request = [0u8; CONNECT_TOKEN_REQUEST_SIZE];
+        token.write_request(&mut request).unwrap();
+
+        assert!(open_connect_token(&[8; 32], 0x4C494645, &request, now).is_none());
+        assert!(open_connect_token(&PRIVATE_KEY, 0x4C494646, &request, now).is_none());
+        assert!(open_connect_token(&PRIVATE_KEY, 0x4C494645, &request[1..], now).is_none());
+
+        // Pushing back the expiry, or touching any other byte, breaks the seal.
+        for index in 0..CONNECT_TOKEN_REQUEST_SIZE {
+            let mut tampered = request;
+            tampered[index] ^= 0x01;
+            assert!(open_connect_token(&PRIVATE_KEY, 0x4C494645, &tampered, now).is_none());
+        }
+    }
+
+    #[test]
+    fn test_connect_token_expires() {
+        let token = generate_connect_token_at(&PRIVATE_KEY, 0x4C494645, 42, &servers(), 1000, 30).unwrap();
+
+        let mut request = [0u8; CONNECT_TOKEN_REQUEST_SIZE];
+        token.write_request(&mut request).unwrap();
+
+        assert!(open_connect_token(&PRIVATE_KEY, 0x4C494645, &request, 1029).is_some());
+        assert!(open_connect_token(&PRIVATE_KEY, 0x4C494645, &request, 1030).is_none());
+    }
+
+    #[test]
+    fn test_connect_token_used_from_one_address() {
+        let token = generate_connect_token_at(&PRIVATE_KEY, 0x4C494645, 42, &servers(), 1000, 30).unwrap();
+        let other = generate_connect_token_at(&PRIVATE_KEY, 0x4C494645, 43, &servers(), 1000, 30).unwrap();
+        let client : Address = "10.0.0.1:5000".parse().unwrap();
+        let thief : Address = "10.0.0.2:5000".parse().unwrap();
+
+        let mut request = [0u8; CONNECT_TOKEN_REQUEST_SIZE];
+        let mut other_request = [0u8; CONNECT_TOKEN_REQUEST_SIZE];
+        token.write_request(&mut request).unwrap();
+        other.write_request(&mut other_request).unwrap();
+
+        let mut registry = TokenRegistry::new();
+        assert!(registry.claim(&request, &client, 1000));
+        assert!(registry.claim(&request, &client, 1001));
+        assert!(!registry.claim(&request, &thief, 1001));
+        assert!(registry.claim(&other_request, &thief, 1001));
+
+        // Once expired the token is forgotten; it would not open any more anyway.
+        assert!(registry.claim(&request, &thief, 1030));
+    }
+
+    #[test]
+    fn test_connect_token_needs_servers_and_a_key() {
+        let too_many : Vec<Address> = (0..MAX_SERVERS_PER_CONNECT + 1).map(|port| format!("127.0.0.1:{}", port + 1).parse().unwrap()).collect();
+
+        assert!(generate_connect_token(&PRIVATE_KEY, 0x4C494645, 42, &[], 30).is_none());
+        assert!(generate_connect_token(&PRIVATE_KEY, 0x4C494645, 42, &too_many, 30).is_none());
+        assert!(generate_connect_token(&PRIVATE_KEY, 0x4C494645, 42, &too_many[..MAX_SERVERS_PER_CONNECT], 30).is_some());
+        assert!(generate_connect_token(&PRIVATE_KEY[..16], 0x4C494645, 42, &servers(), 30).is_none());
+
+        let mut buffer = [0u8; CONNECT_TOKEN_SIZE];
+        assert!(generate_connect_token(&PRIVATE_KEY, 0x4C494645, 42, &servers(), 30).unwrap().write_to(&mut buffer[..CONNECT_TOKEN_SIZE - 1]).is_none());
+    }
+
+    #[test]
+    fn test_connect_token_expiry_does_not_overflow() {
+        assert!(generate_connect_token(&PRIVATE_KEY, 0x4C494645, 42, &servers(), u64::MAX).is_none());
+        assert!(generate_connect_token_at(&PRIVATE_KEY, 0x4C494645, 42, &servers(), 1000, u64::MAX - 999).is_none());
+
+        let token = generate_connect_token_at(&PRIVATE_KEY, 0x4C494645, 42, &servers(), 1000, u64::MAX - 1000).unwrap();
+        assert_eq!(token.expire_timestamp, u64::MAX);
+    }
+}
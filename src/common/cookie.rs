@@ -0,0 +1,165 @@
+/*
+ * Stateless cookies for the connection handshake.
+ *
+ * If a server set aside a peer for every ConnectionRequest, anyone able to send datagrams with
+ * made-up source addresses could fill it up without ever hearing back. So the first request from
+ * an address only gets a cookie: the time it was made and an HMAC of that time and the address,
+ * under a key nobody but the server knows. The server remembers nothing about it. A client that
+ * really is at that address gets the cookie and sends it back with its request, and only then does
+ * the server do the key exchange and give it a peer.
+ *
+ * A cookie is only good from the address it was made for, and only for COOKIE_LIFETIME seconds.
+ * Timestamps come from the server's own clock, so cookies never need to make sense to anyone else.
+ */
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rand::{OsRng, Rng};
+
+use clock;
+use net::Address;
+use packet::{write_u64, read_u64};
+
+const TIMESTAMP_SIZE: usize = 8;
+const MAC_SIZE: usize = 16;
+
+pub const COOKIE_SIZE: usize = TIMESTAMP_SIZE + MAC_SIZE;
+
+// Long enough for a slow client to answer, short enough that a captured cookie soon goes stale.
+pub const COOKIE_LIFETIME: f64 = 10.0;
+
+pub struct CookieKey {
+    hmac : Hmac<Sha256>,
+}
+
+impl CookieKey {
+    // A key of our own, which nothing outside this server ever sees.
+    pub fn generate() -> CookieKey {
+        let mut secret = [0u8; 32];
+        OsRng::new().expect("No source of randomness for the cookie key.").fill_bytes(&mut secret);
+
+        CookieKey::new(&secret)
+    }
+
+    pub fn new(secret: &[u8]) -> CookieKey {
+        assert!(!secret.is_empty(), "An empty secret would let anyone make cookies.");
+
+        CookieKey {
+            hmac : Hmac::new(Sha256::new(), secret),
+        }
+    }
+
+    // Writes a cookie for `address`, made at `now`, into the first COOKIE_SIZE bytes of `cookie`.
+    pub fn make(&mut self, address: &Address, now: Duration, cookie: &mut [u8]) {
+        let timestamp = as_millis(now);
+
+        write_u64(cookie, 0, timestamp);
+        self.compute(address, timestamp, &mut cookie[TIMESTAMP_SIZE..COOKIE_SIZE]);
+    }
+
+    // Whether `cookie` is one of ours, was made for `address`, and has not gone stale by `now`.
+    pub fn check(&mut self, address: &Address, cookie: &[u8], now: Duration) -> bool {
+        if cookie.len() != COOKIE_SIZE {
+            return false;
+        }
+
+        let timestamp = read_u64(cookie, 0);
+        let made = Duration::from_millis(timestamp);
+
+        // From the future can only mean forged.
+        if made > now || clock::seconds_between(made, now) > COOKIE_LIFETIME {
+            return false;
+        }
+
+        let mut expected = [0u8; MAC_SIZE];
+        self.compute(address, timestamp, &mut expected);
+
+        fixed_time_eq(&expected, &cookie[TIMESTAMP_SIZE..])
+    }
+
+    fn compute(&mut self, address: &Address, timestamp: u64, mac: &mut [u8]) {
+        let mut stamp = [0u8; TIMESTAMP_SIZE];
+        let mut port = [0u8; 2];
+        let mut full = [0u8; 32];
+
+        write_u64(&mut stamp, 0, timestamp);
+        port[0] = (address.get_port() >> 8) as u8;
+        port[1] = address.get_port() as u8;
+
+        self.hmac.reset();
+        match address.get_address() {
+            IpAddr::V4(ip) => self.hmac.input(&ip.octets()),
+            IpAddr::V6(ip) => self.hmac.input(&ip.octets()),
+        }
+        self.hmac.input(&port);
+        self.hmac.input(&stamp);
+        self.hmac.raw_result(&mut full);
+
+        mac.copy_from_slice(&full[..MAC_SIZE]);
+    }
+}
+
+fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+// ---------------------------------
+// |        CookieKey Tests        |
+// ---------------------------------
+
+#[cfg(test)]
+mod test {
+
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+    use cookie::{CookieKey, COOKIE_SIZE};
+    use net::Address;
+
+    #[test]
+    fn test_cookie_round_trip() {
+        let mut key = CookieKey::generate();
+        let address = Address::new(Ipv4Addr::new(10, 0, 0, 1), 4000);
+        let mut cookie = [0u8; COOKIE_SIZE];
+
+        key.make(&address, Duration::from_secs(100), &mut cookie);
+
+        assert!(key.check(&address, &cookie, Duration::from_secs(100)));
+        assert!(key.check(&address, &cookie, Duration::from_secs(110)));
+        assert!(!key.check(&address, &cookie[..COOKIE_SIZE - 1], Duration::from_secs(100)));
+    }
+
+    #[test]
+    fn test_cookie_only_good_from_its_address() {
+        let mut key = CookieKey::new(b"server secret");
+        let address = Address::new(Ipv4Addr::new(10, 0, 0, 1), 4000);
+        let mut cookie = [0u8; COOKIE_SIZE];
+
+        key.make(&address, Duration::from_secs(100), &mut cookie);
+
+        assert!(!key.check(&Address::new(Ipv4Addr::new(10, 0, 0, 2), 4000), &cookie, Duration::from_secs(100)));
+        assert!(!key.check(&Address::new(Ipv4Addr::new(10, 0, 0, 1), 4001), &cookie, Duration::from_secs(100)));
+        assert!(!CookieKey::new(b"another server").check(&address, &cookie, Duration::from_secs(100)));
+    }
+
+    #[test]
+    fn test_cookie_goes_stale() {
+        let mut key = CookieKey::new(b"server secret");
+        let address = Address::new(Ipv4Addr::new(10, 0, 0, 1), 4000);
+        let mut cookie = [0u8; COOKIE_SIZE];
+
+        key.make(&address, Duration::from_secs(100), &mut cookie);
+
+        assert!(!key.check(&address, &cookie, Duration::from_millis(110001)));
+        assert!(!key.check(&address, &cookie, Duration::from_secs(99)));
+
+        // Moving the timestamp along breaks the MAC.
+        let mut tampered = cookie;
+        tampered[7] ^= 0x01;
+        assert!(!key.check(&address, &tampered, Duration::from_secs(105)));
+    }
+}
@@ -0,0 +1,369 @@
+/*
+ * End-to-end encryption for reliable connections.
+ *
+ * When a client connects, each side makes a fresh X25519 key pair and sends the other its public
+ * half in the ConnectionRequest and ConnectionResponse packets. Both ends then arrive at the same
+ * shared secret without it ever crossing the wire, and derive one key for each direction from it.
+ *
+ * Every payload packet after that is sealed with ChaCha20-Poly1305. The nonce is the packet's
+ * sequence number and the header goes in as associated data, so nothing in the datagram can be
+ * read or changed without the receiver noticing. A nonce must never repeat under one key, and
+ * sixteen-bit sequences repeat every 65536 packets, so both directions move to a new key, derived
+ * from the last, each time their sequence wraps. Keys are kept per epoch (one trip through the
+ * sequence space), and the receiver works out which epoch a packet belongs to from how its
+ * sequence compares with the newest it has opened.
+ *
+ * Sealing and opening work on the stack; only the handshake and a change of epoch touch the heap.
+ */
+
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+use rand::{Rng, OsRng};
+use sequence::Sequence16;
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+
+// The Poly1305 tag that follows every sealed payload.
+pub const TAG_SIZE: usize = 16;
+
+const KEY_SIZE: usize = 32;
+
+type Key = [u8; KEY_SIZE];
+
+// Our half of a key exchange. A new one is made for every connection, so a key that leaks later
+// cannot be used to read traffic that was recorded earlier.
+pub struct KeyPair {
+    secret : Key,
+    public : Key,
+}
+
+impl KeyPair {
+    pub fn generate() -> KeyPair {
+        let mut secret = [0u8; KEY_SIZE];
+        OsRng::new().expect("No source of randomness for a key pair.").fill_bytes(&mut secret);
+
+        KeyPair {
+            public : curve25519_base(&secret),
+            secret : secret,
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8; PUBLIC_KEY_SIZE] {
+        &self.public
+    }
+}
+
+pub struct Session {
+    local_public : Key,
+    remote_public : Key,
+    send_base : Key,  // Every epoch's sending key is derived from these two
+    receive_base : Key,
+    send_epoch : u32,
+    send_key : Key,
+    last_sent : Option<Sequence16>,
+    receive_epoch : u32,
+    receive_key : Key,
+    previous_receive_key : Option<Key>,  // Stragglers from before the last wrap still open
+    newest_received : Option<Sequence16>,
+    rejected_packets : u32,  // Failed to open: forged, damaged or sealed under another key
+}
+
+impl Session {
+    // Agrees keys with the peer whose public key is `remote_public`. The side that sent the
+    // ConnectionRequest is the initiator. None if the public key is not one we can use.
+    pub fn new(ours: &KeyPair, remote_public: &[u8], initiator: bool) -> Option<Session> {
+        Session::with_token_keys(ours, remote_public, initiator, &[], &[])
+    }
+
+    // Like new, with the keys from a connect token mixed into each direction's. A peer without
+    // the same token ends up with different keys, and nothing it sends opens.
+    pub fn with_token_keys(ours: &KeyPair, remote_public: &[u8], initiator: bool, client_to_server: &[u8], server_to_client: &[u8]) -> Option<Session> {
+        if remote_public.len() != PUBLIC_KEY_SIZE {
+            return None;
+        }
+
+        let shared = curve25519(&ours.secret, remote_public);
+
+        // A low-order point gives everyone the same all-zero secret.
+        if shared.iter().all(|byte| *byte == 0) {
+            return None;
+        }
+
+        let mut remote = [0u8; KEY_SIZE];
+        remote.copy_from_slice(remote_public);
+
+        let (client_public, server_public) = if initiator { (&ours.public, &remote) } else { (&remote, &ours.public) };
+        let to_server = derive(&shared, &[&b"client to server"[..], &client_public[..], &server_public[..], client_to_server]);
+        let to_client = derive(&shared, &[&b"server to client"[..], &client_public[..], &server_public[..], server_to_client]);
+
+        let (send_base, receive_base) = if initiator { (to_server, to_client) } else { (to_client, to_server) };
+
+        Some(Session {
+            local_public : ours.public,
+            remote_public : remote,
+            send_base : send_base,
+            receive_base : receive_base,
+            send_epoch : 0,
+            send_key : epoch_key(&send_base, 0),
+            last_sent : None,
+            receive_epoch : 0,
+            receive_key : epoch_key(&receive_base, 0),
+            previous_receive_key : None,
+            newest_received : None,
+            rejected_packets : 0,
+        })
+    }
+
+    pub fn local_public_key(&self) -> &[u8; PUBLIC_KEY_SIZE] {
+        &self.local_public
+    }
+
+    pub fn remote_public_key(&self) -> &[u8; PUBLIC_KEY_SIZE] {
+        &self.remote_public
+    }
+
+    // How many packets failed to open.
+    pub fn get_rejected_packets(&self) -> u32 {
+        self.rejected_packets
+    }
+
+    // Encrypts `plaintext` into `sealed`, tag and all, and returns how much of it was used. The
+    // header the packet goes out under is authenticated along with it. None if it does not fit.
+    pub fn seal(&mut self, sequence: Sequence16, header: &[u8], plaintext: &[u8], sealed: &mut [u8]) -> Option<usize> {
+        let length = plaintext.len() + TAG_SIZE;
+
+        if length > sealed.len() {
+            return None;
+        }
+
+        if let Some(last) = self.last_sent {
+            if sequence.0 < last.0 {
+                self.send_epoch += 1;
+                self.send_key = epoch_key(&self.send_base, self.send_epoch);
+            }
+        }
+        self.last_sent = Some(sequence);
+
+        let (ciphertext, tag) = sealed[..length].split_at_mut(plaintext.len());
+        let mut cipher = ChaCha20Poly1305::new(&self.send_key, &nonce(sequence), header);
+        cipher.encrypt(plaintext, ciphertext, tag);
+
+        Some(length)
+    }
+
+    // Decrypts a sealed payload into `plaintext` and returns its length. None, and nothing about
+    // the session changes, unless it was sealed by our peer under this header.
+    pub fn open(&mut self, sequence: Sequence16, header: &[u8], sealed: &[u8], plaintext: &mut [u8]) -> Option<usize> {
+        if sealed.len() < TAG_SIZE || sealed.len() - TAG_SIZE > plaintext.len() {
+            self.rejected_packets += 1;
+            return None;
+        }
+
+        let length = sealed.len() - TAG_SIZE;
+
+        let (epoch, key) = match self.epoch_of(sequence) {
+            Some(epoch) if epoch == self.receive_epoch => (epoch, self.receive_key),
+            Some(epoch) if epoch > self.receive_epoch => (epoch, epoch_key(&self.receive_base, epoch)),
+            Some(epoch) if self.previous_receive_key.is_some() => (epoch, self.previous_receive_key.unwrap()),
+            _ => {
+                self.rejected_packets += 1;
+                return None;
+            }
+        };
+
+        let mut cipher = ChaCha20Poly1305::new(&key, &nonce(sequence), header);
+
+        if !cipher.decrypt(&sealed[..length], &mut plaintext[..length], &sealed[length..]) {
+            self.rejected_packets += 1;
+            return None;
+        }
+
+        if epoch > self.receive_epoch {
+            self.previous_receive_key = Some(self.receive_key);
+            self.receive_key = key;
+            self.receive_epoch = epoch;
+        }
+
+        match self.newest_received {
+            Some(newest) if !sequence.more_recent_than(newest) => {},
+            _ => self.newest_received = Some(sequence),
+        }
+
+        Some(length)
+    }
+
+    // A sequence ahead of the newest we have opened but numerically below it has wrapped into the
+    // next epoch; one behind it but numerically above it is left over from the last, or from nowhere if this is the first.
+    fn epoch_of(&self, sequence: Sequence16) -> Option<u32> {
+        match self.newest_received {
+            Some(newest) => {
+                if sequence.more_recent_than(newest) && sequence.0 < newest.0 {
+                    Some(self.receive_epoch + 1)
+                }
+                else if newest.more_recent_than(sequence) && sequence.0 > newest.0 {
+                    self.receive_epoch.checked_sub(1)
+                }
+                else {
+                    Some(self.receive_epoch)
+                }
+            },
+            None => {
+                Some(self.receive_epoch)
+            }
+        }
+    }
+}
+
+fn nonce(sequence: Sequence16) -> [u8; 8] {
+    [0, 0, 0, 0, 0, 0, (sequence.0 >> 8) as u8, sequence.0 as u8]
+}
+
+fn epoch_key(base: &Key, epoch: u32) -> Key {
+    let epoch = [(epoch >> 24) as u8, (epoch >> 16) as u8, (epoch >> 8) as u8, epoch as u8];
+    derive(base, &[&b"epoch"[..], &epoch[..]])
+}
+
+// HMAC-SHA256 under `key` of the parts one after another.
+fn derive(key: &[u8], parts: &[&[u8]]) -> Key {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    let mut derived = [0u8; KEY_SIZE];
+
+    for part in parts {
+        hmac.input(part);
+    }
+    hmac.raw_result(&mut derived);
+    derived
+}
+
+// ---------------------------------
+// |        Session Tests          |
+// ---------------------------------
+
+#[cfg(test)]
+mod test {
+
+    use encryption::{KeyPair, Session, TAG_SIZE};
+    use sequence::Sequence16;
+
+    fn pair() -> (Session, Session) {
+        let client = KeyPair::generate();
+        let server = KeyPair::generate();
+
+        (Session::new(&client, server.public_key(), true).unwrap(),
+         Session::new(&server, client.public_key(), false).unwrap())
+    }
+
+    #[test]
+    fn test_session_round_trip() {
+        let (mut client, mut server) = pair();
+        let header = [1, 2, 3, 4];
+        let mut sealed = [0u8; 64];
+        let mut opened = [0u8; 64];
+
+        let length = client.seal(Sequence16(0), &header, b"hello", &mut sealed).unwrap();
+        assert_eq!(length, 5 + TAG_SIZE);
+        assert!(&sealed[..5] != b"hello");
+        assert_eq!(server.open(Sequence16(0), &header, &sealed[..length], &mut opened), Some(5));
+        assert_eq!(&opened[..5], b"hello");
+
+        // Each direction has its own key: the client cannot open what it sent itself.
+        assert_eq!(client.open(Sequence16(0), &header, &sealed[..length], &mut opened), None);
+
+        let length = server.seal(Sequence16(0), &header, b"", &mut sealed).unwrap();
+        assert_eq!(client.open(Sequence16(0), &header, &sealed[..length], &mut opened), Some(0));
+    }
+
+    #[test]
+    fn test_session_rejects_tampering() {
+        let (mut client, mut server) = pair();
+        let header = [1, 2, 3, 4];
+        let mut sealed = [0u8; 64];
+        let mut opened = [0u8; 64];
+
+        let length = client.seal(Sequence16(7), &header, b"hello", &mut sealed).unwrap();
+
+        for index in 0..length {
+            let mut tampered = sealed;
+            tampered[index] ^= 0x01;
+            assert_eq!(server.open(Sequence16(7), &header, &tampered[..length], &mut opened), None);
+        }
+
+        // The header and sequence are bound to the payload as well.
+        assert_eq!(server.open(Sequence16(7), &[1, 2, 3, 5], &sealed[..length], &mut opened), None);
+        assert_eq!(server.open(Sequence16(8), &header, &sealed[..length], &mut opened), None);
+        assert_eq!(server.open(Sequence16(7), &header, &sealed[..TAG_SIZE - 1], &mut opened), None);
+        assert_eq!(server.get_rejected_packets(), length as u32 + 3);
+
+        // Someone who was not part of the handshake gets nowhere either.
+        let (_, mut stranger) = pair();
+        assert_eq!(stranger.open(Sequence16(7), &header, &sealed[..length], &mut opened), None);
+
+        assert_eq!(server.open(Sequence16(7), &header, &sealed[..length], &mut opened), Some(5));
+    }
+
+    #[test]
+    fn test_session_mixes_in_token_keys() {
+        let client = KeyPair::generate();
+        let server = KeyPair::generate();
+        let header = [1, 2, 3, 4];
+        let mut sealed = [0u8; 64];
+        let mut opened = [0u8; 64];
+
+        let mut with_token = Session::with_token_keys(&client, server.public_key(), true, &[1; 32], &[2; 32]).unwrap();
+        let mut matching = Session::with_token_keys(&server, client.public_key(), false, &[1; 32], &[2; 32]).unwrap();
+        let mut without = Session::new(&server, client.public_key(), false).unwrap();
+
+        let length = with_token.seal(Sequence16(0), &header, b"hello", &mut sealed).unwrap();
+        assert_eq!(without.open(Sequence16(0), &header, &sealed[..length], &mut opened), None);
+        assert_eq!(matching.open(Sequence16(0), &header, &sealed[..length], &mut opened), Some(5));
+    }
+
+    #[test]
+    fn test_session_rejects_low_order_keys() {
+        let ours = KeyPair::generate();
+
+        assert!(Session::new(&ours, &[0u8; 32], true).is_none());
+        assert!(Session::new(&ours, &[9u8; 31], true).is_none());
+    }
+
+    #[test]
+    fn test_session_rekeys_across_wrap() {
+        let (mut client, mut server) = pair();
+        let header = [0u8; 4];
+        let mut sealed = [0u8; 64];
+        let mut opened = [0u8; 64];
+
+        // The same sequence in two different epochs must not seal to the same bytes.
+        let first = client.seal(Sequence16(0), &header, b"same", &mut sealed).unwrap();
+        let before = sealed;
+
+        let mut sequence = Sequence16(1);
+        let mut late = None;
+
+        for _ in 0..0x10000 {
+            let length = client.seal(sequence, &header, b"same", &mut sealed).unwrap();
+
+            if sequence == Sequence16(0xFFF0) {
+                // Held back, to arrive after the wrap.
+                late = Some(sealed);
+            }
+            else {
+                assert_eq!(server.open(sequence, &header, &sealed[..length], &mut opened), Some(4));
+            }
+            sequence = sequence.next();
+        }
+
+        assert_eq!(sequence, Sequence16(1));
+        assert!(&before[..first] != &sealed[..first]);
+
+        // A straggler from the previous epoch still opens under the key it was sealed with.
+        assert_eq!(server.open(Sequence16(0xFFF0), &header, &late.unwrap()[..first], &mut opened), Some(4));
+
+        // Replaying the first epoch's packet 0 against the second epoch's key fails.
+        assert_eq!(server.open(Sequence16(0), &header, &before[..first], &mut opened), None);
+    }
+}
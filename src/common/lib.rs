@@ -8,11 +8,25 @@ extern crate env_logger;
 extern crate crc;
 #[macro_use] extern crate lazy_static;
 extern crate rand;
+extern crate libc;
+extern crate crypto;
 
 pub mod debug;
+pub mod clock;
 pub mod communicate;
 pub mod packet;
 pub mod utils;
 pub mod netbuffers;
+pub mod buffer_pool;
+pub mod batch;
+pub mod mac;
+pub mod encryption;
+pub mod connect_token;
+pub mod cookie;
+pub mod rate_limit;
+pub mod access_list;
+pub mod sequence;
+pub mod sequence_buffer;
+pub mod tick;
 #[allow(non_snake_case)]
 pub mod net;
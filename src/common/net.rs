@@ -10,16 +10,39 @@
  */
 
 use std::net;
-use std::mem;
+use std::io;
+use std::cmp;
 use std::cmp::Ordering;
-use std::option;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
-use std::collections::{VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use std::vec;
 use net2::UdpBuilder;
 use mioco;
 use mio;
 use packet as Packet;
-use bincode;
+use clock;
+use clock::Clock;
+use sequence::{Sequence16, MAX_SEQUENCE16};
+use sequence_buffer::SequenceBuffer;
+use buffer_pool::BufferPool;
+use batch;
+use batch::DatagramBatch;
+use mac::{PacketKey, MAC_SIZE};
+use encryption::{KeyPair, Session, PUBLIC_KEY_SIZE};
+use cookie::{CookieKey, COOKIE_SIZE};
+use rate_limit::{RateLimit, RateLimiter, MAX_BAN_SECONDS};
+use access_list::AccessList;
+use connect_token::{ConnectToken, PrivateConnectToken, TokenRegistry, open_connect_token, unix_time,
+                    CONNECT_TOKEN_KEY_SIZE, CONNECT_TOKEN_REQUEST_SIZE};
+use utils::{bit_set, is_bit_set};
+use rand;
+
+pub use sequence_buffer::sequence_more_recent;
 
 #[derive(PartialEq)]
 enum State {
@@ -42,6 +65,67 @@ pub enum Port {
     Server = 8890,
 }
 
+// How many datagram buffers each connection keeps around for reuse.
+const DATAGRAM_BUFFERS: usize = 8;
+
+// How many datagrams a connection reads, or queues up to write, at a time.
+const BATCH_SIZE: usize = 64;
+
+// How many events are kept for an application that is not polling for them.
+const MAX_QUEUED_EVENTS: usize = 1024;
+
+// Every message currently travels on the one channel.
+pub const DEFAULT_CHANNEL: u8 = 0;
+
+// Requests from addresses that have not shown they can receive there are padded out to at least
+// this many bytes, not counting any MAC, and are ignored if they are not. Anything sent back before
+// then has to fit in what came in, so nobody can use us to aim more traffic at a spoofed address
+// than they send us themselves. See Connection::QueueReplyTo.
+pub const MIN_REQUEST_SIZE: usize = 256;
+
+// How many copies of a Disconnect a kicked peer is sent. We forget the peer straight after, so
+// nothing is resent if they all go missing; the peer times out instead.
+const DISCONNECT_PACKETS: usize = 3;
+
+// The code a peer is kicked with when it is banned.
+pub const BANNED_CODE: u8 = 0xFF;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DisconnectReason {
+    Stopped,       // we were stopped while connected
+    Reconnecting,  // Connect or Listen was called again while connected
+    Kicked { code : u8 },  // the server let us go, or we let this peer go, with a code of the application's choosing
+}
+
+// Who a server bans. Connect token client ids are the only ones that last beyond a connection;
+// the ids a server hands out are new every time.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum BanTarget {
+    Address(net::IpAddr),  // every port at the address
+    ClientId(u64),  // the client id in a connect token
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BanTarget::Address(address) => write!(f, "{}", address),
+            BanTarget::ClientId(client_id) => write!(f, "client {}", client_id),
+        }
+    }
+}
+
+// What happened on a connection since the application last polled it. Server events come paired
+// with the address of the client they are about.
+#[derive(PartialEq, Debug, Clone)]
+pub enum ConnectionEvent {
+    Connected,
+    Disconnected { reason : DisconnectReason },
+    Message { channel : u8, data : Vec<u8> },
+    MessageAcked { id : MessageId },  // `id` is what SendPacket returned for the message
+    MessageLost { id : MessageId },
+    TimedOut,
+}
+
 
 ///     #####
 ///    #     #  ####   ####  #    # ###### #####
@@ -54,15 +138,25 @@ pub enum Port {
 struct Socket {
     socket: mio::udp::UdpSocket,
     is_open: bool,
+    is_v6: bool,
+    local: Address,
 }
 
 impl Socket {
 
-    pub fn open(listen_on: net::SocketAddrV4) -> Socket {
+    // With `reuse_port`, other sockets may bind the same port as well, and the kernel shares
+    // incoming datagrams out between them.
+    // Binding the unspecified v6 address `[::]` gives a dual-stack socket that hears IPv4 peers too.
+    pub fn open(listen_on: net::SocketAddr, reuse_port: bool) -> Socket {
 
         let udp;
 
-        match UdpBuilder::new_v4() {
+        let builder = match listen_on {
+            net::SocketAddr::V4(_) => UdpBuilder::new_v4(),
+            net::SocketAddr::V6(_) => UdpBuilder::new_v6(),
+        };
+
+        match builder {
           Ok(new_udp) => {
               udp = new_udp
           },
@@ -73,6 +167,18 @@ impl Socket {
 
         let _ = udp.reuse_address(true);
 
+        if let net::SocketAddr::V6(v6address) = listen_on {
+            if let Err(err) = udp.only_v6(!v6address.ip().is_unspecified()) {
+                panic!("Could not configure IPv6 socket on {}: {}", listen_on, err);
+            }
+        }
+
+        if reuse_port {
+            if let Err(err) = set_reuse_port(&udp) {
+                panic!("Could not share port {}: {}", listen_on.port(), err);
+            }
+        }
+
         let sock = udp.bind(listen_on);
 
         let result_socket;
@@ -81,12 +187,20 @@ impl Socket {
             Ok(sock) => {
               let _ = sock.set_nonblocking(true);
 
+              // Port 0 asks the system to pick one, so read back what we actually got.
+              let local = match sock.local_addr() {
+                  Ok(local) => local,
+                  Err(err) => panic!("Could not read back the address bound for {}: {}", listen_on, err),
+              };
+
               match mio::udp::UdpSocket::from_socket(sock) {
                   Ok(mio_socket) => {
-                      info!("Bound socket to {}", listen_on);
+                      info!("Bound socket to {}", local);
                       let new_socket = Socket {
                           socket : mio_socket,
                           is_open : true,
+                          is_v6 : listen_on.is_ipv6(),
+                          local : Address::from_socket_addr(local),
                       };
                       result_socket = new_socket
                   },
@@ -102,54 +216,59 @@ impl Socket {
         result_socket
     }
 
-    pub fn receive(&self, data: &mut Vec<u8>)  ->  Result<(usize, net::SocketAddr), &str> {
-        let mut buf = [0u8; 1024 * 16];
-        let mut received_bytes = 0;
-
-        match self.socket.recv_from(&mut buf) {
-            Ok(Some((len, addr))) => {
-                info!("Length: {}, Addr: {}", len, addr);
-
-                let buf_as_vec = Vec::from(&buf[0..len]);
-                mem::replace::<(Vec<u8>)>(data, buf_as_vec);
-
-                return Ok((len, addr));
-            },
-            Ok(None) => {
-                return Err("Nothing to receive.");
-            },
-            Err(_) => {
-                return Err("Nothing to receive.");
-            }
-        }
+    // Reads every waiting datagram that fits into the rest of `batch`. Returns how many arrived.
+    pub fn receive_batch(&self, batch: &mut DatagramBatch) -> usize {
+        batch::receive_batch(&self.socket, batch)
     }
 
     pub fn close(&self) {
         drop(&self.socket);
     }
 
-    pub fn send(&self, ip: &net::Ipv4Addr, port: u16, data : Vec<u8>) -> bool {
-        let send_addr1 = net::SocketAddrV4::new(*ip, port);
-        let send_addr = net::SocketAddr::V4(send_addr1);
+    pub fn register(&self, poll: &mio::Poll, token: mio::Token) -> io::Result<()> {
+        poll.register(&self.socket, token, mio::Ready::readable(), mio::PollOpt::edge())
+    }
 
-        match self.socket.send_to(data.as_slice(), &send_addr) {
-            Ok(result) => {
-                match result {
-                    Some(bytes_written) => {
-                        true
-                    },
-                    None => {
-                        false
-                    },
-                }
-            },
-            Err(_) => {
-                false
+    // Sends the whole batch, returning how many datagrams made it onto the wire.
+    pub fn send_batch(&self, batch: &DatagramBatch) -> usize {
+        batch::send_batch(&self.socket, batch)
+    }
+
+    pub fn local_addr(&self) -> Address {
+        self.local.clone()
+    }
+
+    // Where to send a datagram meant for `address`. A v6 socket can only reach an IPv4 peer
+    // through the peer's v4-mapped address.
+    pub fn destination(&self, address: &Address) -> net::SocketAddr {
+        match address.get_socket_addr() {
+            net::SocketAddr::V4(v4address) if self.is_v6 => {
+                net::SocketAddr::V6(net::SocketAddrV6::new(v4address.ip().to_ipv6_mapped(), v4address.port(), 0, 0))
             },
+            socket_address => {
+                socket_address
+            }
         }
     }
 }
 
+#[cfg(unix)]
+fn set_reuse_port(udp: &UdpBuilder) -> io::Result<()> {
+    use net2::unix::UnixUdpBuilderExt;
+
+    udp.reuse_port(true).map(|_| ())
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_udp: &UdpBuilder) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "SO_REUSEPORT is not available on this platform"))
+}
+
+// Every IPv4 interface on `port`, which is what the port-only constructors listen on.
+fn any_address(port: u16) -> net::SocketAddr {
+    net::SocketAddr::new(net::IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0)), port)
+}
+
 ///       #
 ///      # #   #####  #####  #####  ######  ####   ####
 ///     #   #  #    # #    # #    # #      #      #
@@ -160,59 +279,121 @@ impl Socket {
 
 #[derive(Clone)]
 pub struct Address {
-    address : net::Ipv4Addr,
-    port : u16
+    address : net::SocketAddr,
 }
 
 impl Address {
-    pub fn new(address : net::Ipv4Addr, port : u16) -> Address {
+    pub fn new<I: Into<net::IpAddr>>(address : I, port : u16) -> Address {
+        Address::from_socket_addr(net::SocketAddr::new(address.into(), port))
+    }
+
+    // IPv4 peers heard on a dual-stack socket show up as v4-mapped v6 addresses. They are stored
+    // as plain IPv4 so that the same peer compares equal whichever socket it reached us on.
+    pub fn from_socket_addr(address : net::SocketAddr) -> Address {
+        let address = match address {
+            net::SocketAddr::V6(v6address) => {
+                let segments = v6address.ip().segments();
+
+                if segments[..6] == [0, 0, 0, 0, 0, 0xffff] {
+                    let (high, low) = (segments[6], segments[7]);
+                    let ip = net::Ipv4Addr::new((high >> 8) as u8, high as u8, (low >> 8) as u8, low as u8);
+                    net::SocketAddr::new(net::IpAddr::V4(ip), v6address.port())
+                }
+                else {
+                    address
+                }
+            },
+            _ => {
+                address
+            }
+        };
+
         Address {
             address : address,
-            port : port,
         }
     }
 
-    pub fn getAddress(&self) -> net::Ipv4Addr {
-        self.address.clone()
+    // Looks up `target`, given as "host:port" or "[v6]:port", and returns every address it
+    // resolves to. Literal addresses come straight back without a lookup.
+    pub fn resolve(target : &str) -> io::Result<Vec<Address>> {
+        let addresses : Vec<Address> = try!(target.to_socket_addrs()).map(Address::from_socket_addr).collect();
+
+        if addresses.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve to any address", target)));
+        }
+        Ok(addresses)
     }
 
-    pub fn getPort(&self) -> u16 {
-        self.port.clone()
+    pub fn get_address(&self) -> net::IpAddr {
+        self.address.ip()
     }
 
-    pub fn empty_address() -> net::Ipv4Addr {
-        net::Ipv4Addr::new(0,0,0,0)
+    pub fn get_port(&self) -> u16 {
+        self.address.port()
+    }
+
+    pub fn get_socket_addr(&self) -> net::SocketAddr {
+        self.address
     }
-}
 
-impl PartialEq  for Address {
+    pub fn empty_address() -> net::IpAddr {
+        net::IpAddr::V4(net::Ipv4Addr::new(0,0,0,0))
+    }
 
-    fn eq(&self, other: &Address) -> bool {
-        self.address == other.address && self.port == other.port
+    // True for the placeholder a connection holds while it has no destination, in either family.
+    pub fn is_empty(&self) -> bool {
+        self.address.ip().is_unspecified()
     }
+}
 
-    fn ne(&self, other: &Address) -> bool {
-        self.address != other.address
+// Two addresses are the same peer when both the ip and the port match. v6 flow info and scope
+// ids are not part of that, so they are left out of equality, ordering and hashing alike.
+impl PartialEq for Address {
+    fn eq(&self, other: &Address) -> bool {
+        self.get_address() == other.get_address() && self.get_port() == other.get_port()
     }
 }
 
-impl PartialOrd  for Address {
+impl Eq for Address {}
 
+impl PartialOrd for Address {
     fn partial_cmp(&self, other: &Address) -> Option<Ordering> {
-        Some(self.address.cmp(&other.address))
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Address {
+    fn cmp(&self, other: &Address) -> Ordering {
+        (self.get_address(), self.get_port()).cmp(&(other.get_address(), other.get_port()))
     }
+}
 
-    fn lt(&self, other: &Address) -> bool {
-        self.address < other.address
+impl Hash for Address {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.get_address().hash(state);
+        self.get_port().hash(state);
     }
-    fn le(&self, other: &Address) -> bool {
-        self.address <= other.address
+}
+
+// "1.2.3.4:5" or "[::1]:5", the same as SocketAddr.
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.address)
     }
-    fn gt(&self, other: &Address) -> bool {
-        self.address > other.address
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Address({})", self.address)
     }
-    fn ge(&self, other: &Address) -> bool {
-        self.address >= other.address
+}
+
+// Parses literal addresses only; use Address::resolve for hostnames.
+impl FromStr for Address {
+    type Err = net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Address, net::AddrParseError> {
+        s.parse().map(Address::from_socket_addr)
     }
 }
 
@@ -233,16 +414,45 @@ pub struct Connection {
     mode : Mode,
     state : State,
     socket : Socket,
-    timeout_accumulator : f32,
+    clock : Arc<Clock>,
+    last_heard : Duration,  // When our destination last reached us
     address : Address,  // Our destination
+    validated : bool,  // Our destination has shown it gets what we send; until then we only answer it
+    unanswered_bytes : usize,  // What an unvalidated destination has sent us, less what we sent back
+    buffers : BufferPool,  // Scratch space for datagrams on their way in
+    incoming : DatagramBatch,  // Datagrams read off the socket but not yet handed out
+    incoming_read : usize,  // How many of those have been handed out
+    outgoing : DatagramBatch,  // Datagrams waiting for the next Flush
+    events : VecDeque<ConnectionEvent>,  // Waiting for the application to poll them
+    key : Option<PacketKey>,  // Signs and checks every datagram, once a secret is set
+    rejected_packets : u32,  // Ours by protocol id, but failed the MAC check
+    limiter : Option<RateLimiter>,  // Checks every sender before we look at what it sent
+    access_list : Option<AccessList>,  // Who may reach us at all; checked ahead of the limiter
+    denied_packets : u64,  // From addresses the access list keeps out
 
 }
 
 impl Connection {
     pub fn new(protocol_id : u32, timeout : f32, port : u16) -> Connection {
+        Connection::with_clock(protocol_id, timeout, port, clock::system_clock())
+    }
+
+    pub fn with_clock(protocol_id : u32, timeout : f32, port : u16, clock : Arc<Clock>) -> Connection {
+        Connection::open(protocol_id, timeout, any_address(port), clock, false)
+    }
+
+    // For servers that run one Connection per thread, all listening on the same address.
+    pub fn with_reuse_port(protocol_id : u32, timeout : f32, listen_on : net::SocketAddr, clock : Arc<Clock>) -> Connection {
+        Connection::open(protocol_id, timeout, listen_on, clock, true)
+    }
+
+    // Listens on exactly `listen_on`, which may be IPv4 or IPv6. `[::]` takes both, and port 0
+    // lets the system pick a free port; local_addr says which.
+    pub fn bind(protocol_id : u32, timeout : f32, listen_on : net::SocketAddr, clock : Arc<Clock>) -> Connection {
+        Connection::open(protocol_id, timeout, listen_on, clock, false)
+    }
 
-        let ip = net::Ipv4Addr::new(0, 0, 0, 0);
-        let listen_addr = net::SocketAddrV4::new(ip, port);
+    fn open(protocol_id : u32, timeout : f32, listen_on : net::SocketAddr, clock : Arc<Clock>, reuse_port : bool) -> Connection {
 
         let mut new_connection = Connection {
             protocol_id : protocol_id,
@@ -250,9 +460,22 @@ impl Connection {
             mode : Mode::None,
             running : false,
             state : State::Disconnected,
-            timeout_accumulator : 0.0,
-            address : Address::new(ip, port),
-            socket : Socket::open(listen_addr),
+            last_heard : clock.now(),
+            clock : clock,
+            address : Address::from_socket_addr(listen_on),
+            validated : false,
+            unanswered_bytes : 0,
+            socket : Socket::open(listen_on, reuse_port),
+            buffers : BufferPool::new(Packet::MAX_PACKET_SIZE, DATAGRAM_BUFFERS),
+            incoming : DatagramBatch::new(BATCH_SIZE, Packet::MAX_PACKET_SIZE),
+            incoming_read : 0,
+            outgoing : DatagramBatch::new(BATCH_SIZE, Packet::MAX_PACKET_SIZE),
+            events : VecDeque::new(),
+            key : None,
+            rejected_packets : 0,
+            limiter : None,
+            access_list : None,
+            denied_packets : 0,
         };
 
         new_connection.ClearData();
@@ -267,13 +490,75 @@ impl Connection {
         self.address.clone()
     }
 
+    // The address our socket is bound to, as opposed to the destination.
+    pub fn local_addr(&self) -> Address {
+        self.socket.local_addr()
+    }
+
+    // From now on every datagram we send carries a MAC made with `secret`, and anything arriving
+    // without a valid one is dropped. Both ends need the same secret.
+    pub fn SetSecretKey(&mut self, secret: &[u8]) {
+        self.key = Some(PacketKey::new(secret));
+    }
+
+    // How many datagrams carried our protocol id but failed the MAC check.
+    pub fn get_rejected_packets(&self) -> u32 {
+        self.rejected_packets
+    }
+
+    // Holds every sender to `limit`, banning for a while any that go over it or keep sending us
+    // datagrams we cannot use. See rate_limit.rs. False, changing nothing, unless `limit.is_valid()`.
+    pub fn SetRateLimit(&mut self, limit: RateLimit) -> bool {
+        match RateLimiter::new(limit) {
+            Some(limiter) => {
+                self.limiter = Some(limiter);
+                true
+            },
+            None => {
+                false
+            }
+        }
+    }
+
+    pub fn GetRateLimiter(&self) -> Option<&RateLimiter> {
+        self.limiter.as_ref()
+    }
+
+    // Drops every datagram from an address `list` does not permit, before anything else is done
+    // with it. Takes effect on the next datagram, so calling it again reloads the rules.
+    pub fn SetAccessList(&mut self, list: AccessList) {
+        self.access_list = Some(list);
+    }
+
+    pub fn GetAccessList(&self) -> Option<&AccessList> {
+        self.access_list.as_ref()
+    }
+
+    pub fn get_denied_packets(&self) -> u64 {
+        self.denied_packets
+    }
+
+    // Counts a datagram from `address` that got past the MAC check but was no good to us anyway,
+    // and holds it against the sender.
+    fn Reject(&mut self, address: &Address) {
+        self.rejected_packets += 1;
+        self.NoteMalformed(address);
+    }
+
+    fn NoteMalformed(&mut self, address: &Address) {
+        let now = self.clock.now();
+
+        if let Some(ref mut limiter) = self.limiter {
+            limiter.malformed(address, now);
+        }
+    }
+
     pub fn Start(&mut self) -> bool {
         assert_eq!(self.running, false);
 
-        println!("Starting connection on port (self.address.port){}", self.address.port);
+        println!("Starting connection on port (self.address.port){}", self.address.get_port());
 
         self.running = true;
-        self.OnStart();
         true
     }
 
@@ -288,9 +573,8 @@ impl Connection {
         self.running = false;
 
         if connected {
-            self.OnDisconnect();
+            self.OnDisconnect(DisconnectReason::Stopped);
         }
-        self.OnStop();
     }
 
     pub fn IsRunning(&self) -> bool {
@@ -303,25 +587,39 @@ impl Connection {
         self.ClearData();
 
         if isConnected {
-            self.OnDisconnect()
+            self.OnDisconnect(DisconnectReason::Reconnecting)
         }
         self.mode = Mode::Server;
         self.state = State::Listening;
     }
 
     pub fn Connect(&mut self, dest_addr : &Address) {
-        println!("Connecting to {}:{}", dest_addr.getAddress(), dest_addr.getPort());
+        println!("Connecting to {}", dest_addr);
 
         let isConnected = self.IsConnected();
         self.ClearData();
 
         if isConnected {
-            self.OnDisconnect();
+            self.OnDisconnect(DisconnectReason::Reconnecting);
         }
 
         self.mode = Mode::Client;
         self.state = State::Connecting;
         self.address = (*dest_addr).clone();
+
+        // We picked the destination ourselves, rather than taking a source address on trust.
+        self.validated = true;
+    }
+
+    // Whether our destination has shown it is really at its address. A listening Connection
+    // takes on whoever reaches it first; until the layer above sees proof, such as a packet
+    // echoing something only the real sender could have had from us, it calls Validate.
+    pub fn IsValidated(&self) -> bool {
+        self.validated
+    }
+
+    fn Validate(&mut self) {
+        self.validated = true;
     }
 
     pub fn IsConnecting(&self) -> bool {
@@ -348,128 +646,303 @@ impl Connection {
         self.protocol_id
     }
 
-    pub fn Update(&mut self, deltaTime: f32) {
+    // Only kept for callers that still drive us with a frame delta. The delta is not needed any
+    // more; how long we have been waiting is read off the clock.
+    pub fn Update(&mut self, _deltaTime: f32) {
+        self.Tick();
+    }
+
+    pub fn Tick(&mut self) {
         assert!(self.IsRunning(), true);
 
-        self.timeout_accumulator += deltaTime;
+        if let Some(ref mut limiter) = self.limiter {
+            limiter.prune(self.clock.now());
+        }
+
+        let waited = clock::seconds_between(self.last_heard, self.clock.now());
 
-        if self.timeout_accumulator > self.timeout {
+        if waited > self.timeout as f64 {
             if self.IsConnecting() {
                 println!("Connection Attempt Timed Out");
-                self.ClearData();
+                self.OnTimeout();
                 self.state = State::ConnectFail;
-                self.OnDisconnect();
             }
             else if self.IsConnected() {
                 println!("Connection Timed Out");
-                self.ClearData();
-                if self.state == State::Connecting {
-                    self.state = State::ConnectFail;
-                }
-                self.OnDisconnect();
+                self.OnTimeout();
             }
         }
     }
 
-    fn SendPacket(&self, data: &Vec<u8>, size: usize) -> bool {
+    // Queues a datagram for our destination. `write` fills it in and returns its length.
+    // Nothing goes out until Flush. Until the destination is validated, everything we send it is
+    // an answer, paid for out of what it has sent us; see QueueReplyTo.
+    fn QueuePacket<F>(&mut self, write: F) -> bool where F: FnOnce(&mut [u8]) -> Option<usize> {
+        let address = self.address.clone();
+
+        if self.validated {
+            return self.QueuePacketTo(&address, write);
+        }
+
+        let mut length = 0;
+        let budget = self.unanswered_bytes;
+        let queued = self.QueueReplyTo(&address, budget, |buffer| {
+            let written = write(buffer);
+            length = written.unwrap_or(0);
+            written
+        });
+
+        if queued {
+            self.unanswered_bytes -= length;
+        }
+        queued
+    }
+
+    // Used by the server side, which talks to many peers over the one socket.
+    fn QueuePacketTo<F>(&mut self, address: &Address, write: F) -> bool where F: FnOnce(&mut [u8]) -> Option<usize> {
         assert_eq!(self.IsRunning(), true);
 
-        if self.address.getAddress() == Address::empty_address() {
+        if address.is_empty() {
             return false;
         }
 
-        self.socket.send(&self.address.getAddress(), self.address.getPort(), data.clone())
-    }
+        if self.outgoing.is_full() {
+            self.Flush();
+        }
 
-    fn ReceivePacket(&mut self, data: &mut Vec<u8>, size: usize) -> usize {
-        assert!(self.IsRunning(), true);
+        let socket_address = self.socket.destination(address);
 
-        let mut recv_address : net::IpAddr = net::IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0));
-        let mut recv_port : u16 = 0;
-        let mut buffer = Vec::<u8>::new();
-        let mut databfr = buffer.as_mut();
-        let mut bytes_received = 0;
-
-         match self.socket.receive(databfr) {
-             Ok((amount, addr)) => {
-                 bytes_received = amount;
-                 recv_address = addr.ip();
-                 recv_port = addr.port();
-             },
-             Err(_) => {
-                 bytes_received = 0;
-             },
-         }
-
-        if bytes_received <= 4 { // to do define this magic number
-            return 0;
+        match self.key {
+            Some(ref mut key) => {
+                self.outgoing.push(&socket_address, |buffer| {
+                    let room = buffer.len() - MAC_SIZE;
+                    match write(&mut buffer[..room]) {
+                        Some(length) => key.sign(buffer, length),
+                        None => None,
+                    }
+                })
+            },
+            None => {
+                self.outgoing.push(&socket_address, write)
+            }
+        }
+    }
+
+    // Queues an answer to an address we have not validated yet, which has sent us `request_bytes`
+    // we have not answered. Answers bigger than that are dropped rather than sent, and so is
+    // everything while it comes to less than one request padded to MIN_REQUEST_SIZE.
+    fn QueueReplyTo<F>(&mut self, address: &Address, request_bytes: usize, write: F) -> bool where F: FnOnce(&mut [u8]) -> Option<usize> {
+        if request_bytes < MIN_REQUEST_SIZE {
+            return false;
         }
 
-        let decoded: Packet::Packet = bincode::rustc_serialize::decode(&databfr[..]).unwrap();
+        self.QueuePacketTo(address, |buffer| {
+            match write(buffer) {
+                Some(length) if length <= request_bytes => Some(length),
+                _ => None,
+            }
+        })
+    }
 
-        if decoded.get_signature() != self.Get_Protocol_Id() || recv_port == 0 {
+    // Sends everything queued since the last flush in as few system calls as possible.
+    // Returns how many datagrams went out; any the socket would not take are dropped.
+    pub fn Flush(&mut self) -> usize {
+        if self.outgoing.is_empty() {
             return 0;
         }
 
-        let socket_ip_address;
+        let sent = self.socket.send_batch(&self.outgoing);
+        self.outgoing.clear();
+        sent
+    }
+
+    // Copies the next waiting datagram into `buffer`, reading a fresh batch off the socket
+    // once the last one has been used up.
+    fn NextDatagram(&mut self, buffer: &mut [u8]) -> Option<(usize, net::SocketAddr)> {
+        if self.incoming_read >= self.incoming.len() {
+            self.incoming.clear();
+            self.incoming_read = 0;
 
-        match recv_address {
-            net::IpAddr::V4(v4address) => {
-                socket_ip_address = v4address;
-            },
-            _ => {
-                panic!("I do not understand this type of addressing.");
+            if self.socket.receive_batch(&mut self.incoming) == 0 {
+                return None;
             }
         }
 
-        if (self.GetMode() == &Mode::Server) && !self.IsConnected() {
-            println!("Server accepts from client {}:{}", recv_address, recv_port );
-            self.state = State::Connected;
+        let (datagram, address) = self.incoming.get(self.incoming_read);
+        let length = cmp::min(datagram.len(), buffer.len());
 
-            self.address = Address::new(socket_ip_address, recv_port);
-            self.OnConnect();
+        buffer[..length].copy_from_slice(&datagram[..length]);
+        self.incoming_read += 1;
 
-        }
+        Some((length, *address))
+    }
+
+    // Like ReceivePacket, but accepts a packet from any sender and hands back who sent it.
+    // The connection state is left alone; the caller decides what a new sender means.
+    // Datagrams that are not ours are skipped, so None means the socket has been drained.
+    fn ReceivePacketFrom(&mut self, buffer: &mut [u8]) -> Option<(usize, Address)> {
+        assert!(self.IsRunning(), true);
+
+        loop {
+            let bytes_received;
+            let recv_address;
+
+            match self.NextDatagram(buffer) {
+                Some((amount, addr)) => {
+                    bytes_received = amount;
+                    recv_address = addr;
+                },
+                None => {
+                    return None;
+                },
+            }
+
+            if recv_address.port() == 0 {
+                continue;
+            }
 
-        // Double check this condition.
-        if self.address.getAddress() == socket_ip_address {
-            if (self.mode == Mode::Client) && (self.state == State::Connecting) {
-                println!("Client completed connection with server.");
-                self.state == State::Connected;
+            let sender = Address::from_socket_addr(recv_address);
+
+            if let Some(ref list) = self.access_list {
+                if !list.permits(&sender.get_address()) {
+                    self.denied_packets += 1;
+                    continue;
+                }
+            }
+
+            // Before we so much as look inside, so a flood costs us next to nothing.
+            if let Some(ref mut limiter) = self.limiter {
+                if !limiter.allow(&sender, self.clock.now()) {
+                    continue;
+                }
+            }
+
+            if !self.IsOurs(&buffer[..bytes_received]) {
+                self.NoteMalformed(&sender);
+                continue;
             }
 
-            self.timeout_accumulator = 0.0;
+            // Checked before anything else sees the packet, so a forged one cannot touch any
+            // connection or reliability state.
+            let verified = match self.key {
+                Some(ref mut key) => key.verify(&buffer[..bytes_received]),
+                None => Some(bytes_received),
+            };
+
+            let bytes_received = match verified {
+                Some(length) => length,
+                None => {
+                    self.Reject(&sender);
+                    continue;
+                }
+            };
+
+            return Some((bytes_received, sender));
+        }
+    }
+
+    fn Register(&self, poll: &mio::Poll, token: mio::Token) -> io::Result<()> {
+        self.socket.register(poll, token)
+    }
 
-            let immutable_bfr = databfr.clone();
-            mem::replace::<(Vec<u8>)>(data, immutable_bfr);
+    fn ReceivePacket(&mut self, buffer: &mut [u8]) -> usize {
+        let received = self.NextPacket(buffer);
 
-            return bytes_received;
+        if received.is_some() {
+            self.Heard();
         }
+        received.unwrap_or(0)
+    }
 
-        return 0;
+    // Our destination is still there: a packet from it got through every check we have. Only then
+    // does it hold off the timeout, so replays and forgeries cannot keep a dead peer alive.
+    fn Heard(&mut self) {
+        self.last_heard = self.clock.now();
     }
 
-    fn ClearData(&mut self) {
-        self.state = State::Disconnected;
-        self.timeout_accumulator = 0.0;
-        self.address = Address::new(Address::empty_address().clone(), 0);
+    // The next packet from our peer, skipping anything else that turned up on the socket.
+    // None once there is nothing left to read.
+    fn NextPacket(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        assert!(self.IsRunning(), true);
+
+        loop {
+            let bytes_received;
+            let sender;
+
+            match self.ReceivePacketFrom(buffer) {
+                Some((amount, address)) => {
+                    bytes_received = amount;
+                    sender = address;
+                },
+                None => {
+                    return None;
+                },
+            }
+
+            if (self.GetMode() == &Mode::Server) && !self.IsConnected() {
+                println!("Server accepts from client {}", sender);
+                self.state = State::Connected;
+
+                self.address = sender.clone();
+                self.last_heard = self.clock.now();
+                self.OnConnect();
+
+            }
+
+            // Double check this condition.
+            if self.address.get_address() == sender.get_address() {
+                if (self.mode == Mode::Client) && (self.state == State::Connecting) {
+                    println!("Client completed connection with server.");
+                    self.state = State::Connected;
+                    self.OnConnect();
+                }
+
+                if !self.validated {
+                    self.unanswered_bytes = self.unanswered_bytes.saturating_add(bytes_received);
+                }
+
+                return Some(bytes_received);
+            }
+        }
     }
 
-    // Up to user if they want to fill this in. Idea is to leave infrastructure alone.
-    fn OnStart(&mut self) {
+    fn PushEvent(&mut self, event: ConnectionEvent) {
+        push_event(&mut self.events, event);
+    }
 
+    // True if the datagram carries our protocol id.
+    fn IsOurs(&self, datagram: &[u8]) -> bool {
+        match Packet::UDPHeader::read_from(datagram) {
+            Some(header) => {
+                header.signature == self.Get_Protocol_Id()
+            },
+            None => {
+                false
+            }
+        }
     }
 
-    fn OnStop(&mut self) {
-        self.ClearData();
+    fn ClearData(&mut self) {
+        self.state = State::Disconnected;
+        self.last_heard = self.clock.now();
+        self.address = Address::new(Address::empty_address(), 0);
+        self.validated = false;
+        self.unanswered_bytes = 0;
     }
 
+    // Applications hear about these through poll_events rather than by editing the library.
     fn OnConnect(&mut self) {
+        self.PushEvent(ConnectionEvent::Connected);
+    }
 
+    fn OnDisconnect(&mut self, reason: DisconnectReason) {
+        self.ClearData();
+        self.PushEvent(ConnectionEvent::Disconnected { reason : reason });
     }
 
-    fn OnDisconnect(&mut self) {
+    fn OnTimeout(&mut self) {
         self.ClearData();
+        self.PushEvent(ConnectionEvent::TimedOut);
     }
 
     pub fn GetHeaderSize() -> usize {
@@ -492,52 +965,114 @@ impl Connection {
 
 
 
+// Sequence buffers only remember this many sequences back. It has to stay well under half the
+// sequence space, or we could no longer tell old sequences from new ones.
+const SEQUENCE_BUFFER_SIZE : usize = 1024;
+
+// How many sequences before the one being acked each packet also acks. A wider window rides out
+// longer bursts of loss, for 4 or 12 more bytes a packet. Both ends have to use the same one.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum AckWindow {
+    Bits32,
+    Bits64,
+    Bits128,
+}
+
+impl AckWindow {
+    pub fn bits(&self) -> u32 {
+        match *self {
+            AckWindow::Bits32 => 32,
+            AckWindow::Bits64 => 64,
+            AckWindow::Bits128 => 128,
+        }
+    }
+
+    // How many 32-bit words of ack bits go out with each packet.
+    pub fn words(&self) -> usize {
+        (self.bits() / 32) as usize
+    }
+}
+
+// Room for the widest window. Bit n stands for the sequence n + 1 before the ack, and lives in
+// word n / 32. Words beyond the window in use stay zero.
+pub type AckBits = [u32; 4];
+
+// Identifies one sent packet, so that its sender can tell which of its sends were acked or lost.
+// Unlike sequence numbers these never wrap, and carry on counting across resets.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
+pub struct MessageId(pub u64);
+
+// What became of a packet we sent.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Delivery {
+    Acked(MessageId),
+    Lost(MessageId),
+}
+
 pub struct ReliableSystem {
-    max_sequence : u32,
-    local_sequence : u32,
-    remote_sequence : u32,
+    ack_window : AckWindow,
+    local_sequence : Sequence16,
+    remote_sequence : Sequence16,
+    next_message_id : u64,
 
     sent_packets : u32,
     recv_packets : u32,
     lost_packets : u32,
     acked_packets: u32,
+    replayed_packets : u32,
 
     sent_bandwidth : f32,
     acked_bandwidth : f32,
     rtt : f32,
     rtt_maximum : f32,
 
-    acks : Vec<u32>,
+    acks : Vec<Sequence16>,
+    deliveries : Vec<Delivery>,  // Outcomes not yet collected with drain_deliveries
+
+    sentBuffer : SequenceBuffer<PacketData>,
+    pendingAckBuffer : SequenceBuffer<PacketData>,
+    receivedBuffer : SequenceBuffer<PacketData>,
+    ackedBuffer : SequenceBuffer<PacketData>,
 
-    sentQueue : PacketQueue,
-    pendingAckQueue : PacketQueue,
-    receivedQueue : PacketQueue,
-    ackedQueue : PacketQueue
+    clock : Arc<Clock>,
 }
 
 impl ReliableSystem {
-    pub fn new( max_sequence : u32) -> ReliableSystem {
+    pub fn new() -> ReliableSystem {
+        ReliableSystem::with_clock(clock::system_clock())
+    }
+
+    pub fn with_clock(clock : Arc<Clock>) -> ReliableSystem {
+        ReliableSystem::with_ack_window(AckWindow::Bits32, clock)
+    }
+
+    pub fn with_ack_window(ack_window : AckWindow, clock : Arc<Clock>) -> ReliableSystem {
         let mut reliability_system = ReliableSystem {
-            max_sequence : max_sequence,
-            local_sequence : 0,
-            remote_sequence : 0,
+            ack_window : ack_window,
+            local_sequence : Sequence16(0),
+            remote_sequence : Sequence16(0),
+            next_message_id : 0,
 
             sent_packets : 0,
             recv_packets : 0,
             lost_packets : 0,
             acked_packets: 0,
+            replayed_packets : 0,
 
             sent_bandwidth : 0.0,
             acked_bandwidth : 0.0,
             rtt : 0.0,
             rtt_maximum : 1.0,
 
-            acks : Vec::<u32>::new(),
+            acks : Vec::<Sequence16>::new(),
+            deliveries : Vec::new(),
 
-            sentQueue : PacketQueue::new(),
-            pendingAckQueue : PacketQueue::new(),
-            receivedQueue : PacketQueue::new(),
-            ackedQueue : PacketQueue::new()
+            sentBuffer : SequenceBuffer::new(SEQUENCE_BUFFER_SIZE, MAX_SEQUENCE16),
+            pendingAckBuffer : SequenceBuffer::new(SEQUENCE_BUFFER_SIZE, MAX_SEQUENCE16),
+            receivedBuffer : SequenceBuffer::new(SEQUENCE_BUFFER_SIZE, MAX_SEQUENCE16),
+            ackedBuffer : SequenceBuffer::new(SEQUENCE_BUFFER_SIZE, MAX_SEQUENCE16),
+
+            clock : clock,
         };
         reliability_system.reset();
 
@@ -545,186 +1080,214 @@ impl ReliableSystem {
     }
 
     pub fn reset(&mut self) {
-        self.local_sequence = 0;
-        self.remote_sequence = 0;
-        self.sentQueue.clear();
-        self.receivedQueue.clear();
-        self.pendingAckQueue.clear();
-        self.ackedQueue.clear();
+        self.local_sequence = Sequence16(0);
+        self.remote_sequence = Sequence16(0);
+        self.sentBuffer.clear();
+        self.receivedBuffer.clear();
+        self.pendingAckBuffer.clear();
+        self.ackedBuffer.clear();
+        self.deliveries.clear();
         self.sent_packets = 0;
         self.recv_packets = 0;
         self.lost_packets = 0;
         self.acked_packets = 0;
+        self.replayed_packets = 0;
         self.sent_bandwidth = 0.0;
         self.acked_bandwidth = 0.0;
         self.rtt = 0.0;
         self.rtt_maximum = 1.0;
     }
 
-    pub fn PacketSent(&mut self, size: usize) {
-        if self.sentQueue.exists(self.local_sequence) {
-            println!("Local sequence {} exists in Sent Queue!", self.local_sequence);
-        }
+    // Records a packet as sent with the current local sequence, and returns the id its ack or
+    // loss will be reported under.
+    pub fn PacketSent(&mut self, size: usize) -> MessageId {
+        let id = MessageId(self.next_message_id);
+        self.next_message_id += 1;
+
+        self.record_sent(size, Some(id));
+        id
+    }
 
-        assert_eq!(self.sentQueue.exists(self.local_sequence), false);
-        assert_eq!(self.pendingAckQueue.exists(self.local_sequence), false);
+    // A keep-alive takes a sequence number like any other packet, but nobody is waiting to hear
+    // whether it arrived, so no delivery is reported for it.
+    pub fn KeepAliveSent(&mut self) {
+        self.record_sent(0, None);
+    }
 
-        let mut data = PacketData {
+    fn record_sent(&mut self, size: usize, message: Option<MessageId>) {
+        let data = PacketData {
             sequence : self.local_sequence,
             size : size as u32,
-            time : 0.0,
+            time : self.now(),
+            message : message,
         };
 
-        self.sentQueue.push_back(data.clone());
-        self.pendingAckQueue.push_back(data.clone());
-        self.sent_packets += 1;
-        self.local_sequence += 1;
-        if self.local_sequence > self.max_sequence {
-            self.local_sequence = 0;
+        self.sentBuffer.insert(self.local_sequence.into(), data.clone());
+
+        // Whatever this displaces went a whole buffer of packets without an ack.
+        if let Some(displaced) = self.pendingAckBuffer.insert(self.local_sequence.into(), data) {
+            self.report(Delivery::Lost, &displaced);
+            self.lost_packets += 1;
         }
+
+        self.sent_packets += 1;
+        self.local_sequence = self.local_sequence.next();
     }
 
-    pub fn PacketReceived(&mut self, sequence: u32, size: usize) {
-        self.recv_packets += 1;
-        if self.receivedQueue.exists(sequence) {
-            return
+    // Records a packet from our peer. False, with nothing recorded, if it is a replay; the
+    // caller should then drop the packet rather than deliver it.
+    pub fn PacketReceived(&mut self, sequence: Sequence16, size: usize) -> bool {
+        if self.IsReplay(sequence) {
+            self.replayed_packets += 1;
+            return false;
         }
 
-        let mut data = PacketData {
+        self.recv_packets += 1;
+
+        let data = PacketData {
             sequence : sequence,
             size : size as u32,
-            time : 0.0,
+            time : self.now(),
+            message : None,
         };
 
-        self.receivedQueue.push_back(data.clone());
-        if sequence_more_recent(&sequence, &self.remote_sequence, &self.max_sequence ) {
+        self.receivedBuffer.insert(sequence.into(), data);
+        if sequence.more_recent_than(self.remote_sequence) {
             self.remote_sequence = sequence;
         }
+        true
+    }
+
+    // The received buffer doubles as our replay window: a sequence it still holds has been seen
+    // already, and one too far behind the newest for it to hold might have been. A datagram
+    // captured off the wire and sent again soon after is one or the other. Half the sequence
+    // space later it looks new again; only a Session, whose keys change every wrap, stops that.
+    pub fn IsReplay(&self, sequence: Sequence16) -> bool {
+        self.receivedBuffer.exists(sequence.into()) || self.receivedBuffer.is_too_old(sequence.into())
     }
 
-    pub fn GenerateAckBits(&mut self) -> u32 {
-        self.generate_ack_bits(self.get_remote_sequence(), &self.receivedQueue, self.max_sequence)
+    pub fn GenerateAckBits(&mut self) -> AckBits {
+        self.generate_ack_bits(self.get_remote_sequence(), &self.receivedBuffer)
     }
 
-    pub fn ProcessAck(&mut self, ack: u32, ack_bits: u32) {
+    // `ack_bits` holds one word per 32 sequences of our window. Any more are ignored, and any
+    // fewer cover only as much of the window as they reach.
+    pub fn ProcessAck(&mut self, ack: Sequence16, ack_bits: &[u32]) {
         self.process_ack(ack, ack_bits);
     }
 
-    pub fn Update(&mut self, deltaTime: f32) {
+    // Only kept for callers that still drive us with a frame delta. Packets are stamped with the
+    // clock when they come and go, so their ages no longer need advancing by hand.
+    pub fn Update(&mut self, _deltaTime: f32) {
+        self.Tick();
+    }
+
+    pub fn Tick(&mut self) {
         self.acks.clear();
-        self.AdvanceQueueTimes(deltaTime);
         self.UpdateQueues();
         self.UpdateStats();
-        self.Validate();
+
+        // Walking all four buffers is O(buffer), too slow to do every tick at high packet rates,
+        // so release builds leave it to the tests.
+        if cfg!(debug_assertions) {
+            self.Validate();
+        }
     }
 
     pub fn Validate(&self) {
-        let max_sequence = self.max_sequence;
-        self.sentQueue.verify_sequencing(max_sequence);
-        self.ackedQueue.verify_sequencing(max_sequence);
-        self.pendingAckQueue.verify_sequencing(max_sequence);
-        self.receivedQueue.verify_sequencing(max_sequence);
+        self.sentBuffer.verify_sequencing();
+        self.ackedBuffer.verify_sequencing();
+        self.pendingAckBuffer.verify_sequencing();
+        self.receivedBuffer.verify_sequencing();
     }
 
-    fn bit_index_for_sequence(&self, sequence: u32, ack: u32, max_sequence: u32) -> i32 {
+    fn bit_index_for_sequence(&self, sequence: Sequence16, ack: Sequence16) -> i32 {
         assert!(sequence != ack);
-        assert!(sequence_more_recent(&sequence, &ack, &max_sequence) == false);
+        assert!(sequence.more_recent_than(ack) == false);
 
-        if sequence > ack {
-            assert!(ack < 33);
-            assert!(max_sequence >= sequence);
-            return (ack + (max_sequence - sequence)) as i32
-        }
-        else {
-            assert!(ack >= 1);
-            assert!(sequence <= ack - 1);
-            return (ack - 1 - sequence) as i32
-        }
+        let bit_index = ack.distance_from(sequence) as u32 - 1;
+        assert!(bit_index < self.ack_window.bits());
+
+        bit_index as i32
     }
 
-    fn generate_ack_bits(&self, ack: u32, receive_queue: &PacketQueue, max_sequence: u32) -> u32 {
-        let mut ack_bits : u32 = 0;
-        {
-            let mut iterator = receive_queue.queue.iter();
+    // Each bit in the window stands for one of the sequences just before `ack`, so rather than
+    // walking everything we've received we only look those up.
+    fn generate_ack_bits(&self, ack: Sequence16, received: &SequenceBuffer<PacketData>) -> AckBits {
+        let mut ack_bits : AckBits = [0; 4];
 
-            loop {
-                match iterator.next() {
-                    Some(next_packet) => {
-                        if next_packet.sequence == ack || sequence_more_recent(&next_packet.sequence, &ack, &max_sequence) {
-                            break;
-                        }
+        for distance in 1..self.ack_window.bits() + 1 {
+            let sequence = ack.before(distance as u16);
 
-                        let bit_index = self.bit_index_for_sequence(next_packet.sequence, ack, max_sequence);
-                        if bit_index <= 31 {
-                            ack_bits |= 1 << bit_index;
-                        }
-                    },
-                    None => {break;},
-                }
+            if received.exists(sequence.into()) {
+                let bit_index = self.bit_index_for_sequence(sequence, ack) as u32;
+                bit_set(&mut ack_bits[(bit_index / 32) as usize], bit_index % 32);
             }
         }
         ack_bits
     }
 
-    fn process_ack(&mut self, ack: u32, ack_bits: u32) {
-
-        if self.pendingAckQueue.queue.is_empty() {
+    fn process_ack(&mut self, ack: Sequence16, ack_bits: &[u32]) {
+        if self.pendingAckBuffer.is_empty() {
             return;
         }
 
-        let mut packet_index = 0xFF;
-        {
-            let mut iterator = self.pendingAckQueue.queue.iter();
-            loop {
-                match iterator.next() {
-                    Some(packet_data) => {
-                        let mut acked = false;
-
-                        if packet_data.sequence == ack {
-                            acked = true;
-                        }
-                        else if !sequence_more_recent(&packet_data.sequence, &ack, &self.max_sequence) {
-                            let bit_index = self.bit_index_for_sequence(packet_data.sequence, ack, self.max_sequence);
-                            if bit_index <= 31 {
-                                acked = (1 & (ack_bits >> bit_index)) != 0;
-                            }
-                        }
+        self.acknowledge(ack);
 
-                        if acked {
-                            self.rtt += (packet_data.time - self.rtt) * 0.1;
+        let window = cmp::min(self.ack_window.bits(), 32 * ack_bits.len() as u32);
 
-                            self.ackedQueue.insert_sorted(packet_data.clone(), self.max_sequence);
-                            self.acks.push(packet_data.sequence);
-                            self.acked_packets += 1;
+        for distance in 1..window + 1 {
+            let sequence = ack.before(distance as u16);
+            let bit_index = self.bit_index_for_sequence(sequence, ack) as u32;
 
-                            packet_index = self.pendingAckQueue.find_index_for_sequence(packet_data.sequence);
-                        }
-                    },
-                    None => {break;},
-                }
+            if is_bit_set(ack_bits[(bit_index / 32) as usize], bit_index % 32) {
+                self.acknowledge(sequence);
             }
         }
+    }
 
-        if packet_index != 0xFF && self.pendingAckQueue.is_index_valid(packet_index) {
-            self.pendingAckQueue.queue.remove(packet_index);
+    // Moves `sequence` from pending to acked, if we were still waiting on it.
+    fn acknowledge(&mut self, sequence: Sequence16) {
+        match self.pendingAckBuffer.remove(sequence.into()) {
+            Some(packet_data) => {
+                let round_trip = (self.now() - packet_data.time) as f32;
+                self.rtt += (round_trip - self.rtt) * 0.1;
+
+                self.acks.push(sequence);
+                self.report(Delivery::Acked, &packet_data);
+                self.ackedBuffer.insert(sequence.into(), packet_data);
+                self.acked_packets += 1;
+            },
+            None => {},
         }
     }
 
-    pub fn get_local_sequence(&self) -> u32 {
+    pub fn get_local_sequence(&self) -> Sequence16 {
         self.local_sequence
     }
 
-    pub fn get_remote_sequence(&self) -> u32 {
+    pub fn get_remote_sequence(&self) -> Sequence16 {
         self.remote_sequence
     }
 
-    pub fn get_max_sequence(&self) -> u32 {
-        self.max_sequence
+    pub fn get_ack_window(&self) -> AckWindow {
+        self.ack_window
+    }
+
+    // Hands over every ack and loss since the last call, oldest first.
+    pub fn drain_deliveries(&mut self) -> vec::Drain<Delivery> {
+        self.deliveries.drain(..)
+    }
+
+    // The sequences acked since the last Tick, in the order the acks arrived.
+    pub fn get_acks(&self) -> &[Sequence16] {
+        &self.acks
     }
 
-    pub fn get_acks(&mut self, acks: &mut u32, count: &mut u32) {
-        *acks = self.acks[0];
-        *count = self.acks.len() as u32;
+    fn report<F>(&mut self, outcome: F, packet: &PacketData) where F: Fn(MessageId) -> Delivery {
+        if let Some(id) = packet.message {
+            self.deliveries.push(outcome(id));
+        }
     }
 
     pub fn get_sent_packets(&self) -> u32 {
@@ -743,6 +1306,11 @@ impl ReliableSystem {
         self.acked_packets
     }
 
+    // Packets dropped by PacketReceived as replays.
+    pub fn get_replayed_packets(&self) -> u32 {
+        self.replayed_packets
+    }
+
     pub fn get_sent_bandwidth(&self) -> f32 {
         self.sent_bandwidth
     }
@@ -756,237 +1324,50 @@ impl ReliableSystem {
     }
 
     pub fn GetHeaderSize() -> usize {
-        12
+        8
     }
 
-    pub fn AdvanceQueueTimes(&mut self, deltaTime: f32) {
-        for packet in &mut self.sentQueue.queue {
-            packet.time += deltaTime;
-        }
-
-        for packet in &mut self.receivedQueue.queue {
-            packet.time += deltaTime;
-        }
-
-        for packet in &mut self.pendingAckQueue.queue {
-            packet.time += deltaTime;
-        }
-
-        for packet in &mut self.ackedQueue.queue {
-            packet.time += deltaTime;
-        }
+    // Seconds on our clock. Packets are stamped with this when sent or received.
+    fn now(&self) -> f64 {
+        clock::as_seconds(self.clock.now())
     }
 
-    // TODO: The UpdateQueues method is not well thought out. My mostly-direct port looks pretty messy.
-    // I'm thinking I can use a macro for the loop blocks.
-    // Need to investigate later.
-/*
+    // Forgets sent and acked packets once they are too old to count towards bandwidth, and gives
+    // up on packets that have waited longer than rtt_maximum for an ack. The received buffer
+    // needs no trimming: it only answers "did this sequence arrive?" for the last
+    // SEQUENCE_BUFFER_SIZE sequences, and anything older is pushed out as newer sequences come in.
     pub fn UpdateQueues(&mut self) {
         let epsilon : f32 = 0.0001;
+        let now = self.now();
+        let rtt_maximum = self.rtt_maximum;
 
-        loop {
-            match self.sentQueue.front() {
-                Some(sent_packet) => {
-                    if sent_packet.time > self.rtt_maximum + epsilon {
-                        let _ = self.sentQueue.queue.pop_front();
-                    }
-                },
-                None => {break;}
-            }
-
-            if self.sentQueue.queue.len() == 0 {
-                break;
-            }
-            println!("SentQueue");
-        }
-
-        if self.receivedQueue.queue.len() != 0 {
-            match self.receivedQueue.back() {
-                Some(received_packet) => {
-                    let latest_sequence = received_packet.sequence;
-                    let minimum_sequence = if latest_sequence >= 34  {latest_sequence - 34} else { self.max_sequence - (34 - latest_sequence)};
-
-                    loop {
-                        match self.receivedQueue.front() {
-                            Some(recv_front_packet) => {
-                                // TODO need to figure out why this things it smore recent
-                                let is_more_recent = sequence_more_recent(&recv_front_packet.sequence, &minimum_sequence, &self.max_sequence);
-                                println!("{}, {}, {}, {}", &recv_front_packet.sequence, &minimum_sequence, &self.max_sequence, is_more_recent );
-                                if !is_more_recent {
-                                    let _ = self.receivedQueue.queue.pop_front();
-                                    println!("ReceivedQueue");
-                                }
-                                println!("ReceivedQueue Packet {}", recv_front_packet);
-                            },
-                            None => {break;},
-                        }
-
-                        if self.receivedQueue.queue.len() == 0 {
-                            break;
-                        }
-                    }
-                },
-                None => {},
-            }
-        }
-
-        loop {
-            match self.ackedQueue.front() {
-                Some(acked_packet) => {
-                    if acked_packet.time > (self.rtt_maximum*2.0) - epsilon {
-                        let _ = self.ackedQueue.queue.pop_front();
-                    }
-                },
-                None => {break;},
-            }
-
-            if self.ackedQueue.queue.len() == 0 {
-                break;
-            }
-            println!("AckedQueue");
-        }
-
-        loop {
-            match self.pendingAckQueue.front() {
-                Some(pending_ack_packet) => {
-                    if pending_ack_packet.time > self.rtt_maximum + epsilon {
-                        let _ = self.pendingAckQueue.queue.pop_front();
-                        self.lost_packets += 1;
-                    }
-                },
-                None => {break;},
-            }
+        self.sentBuffer.retain(|_, sent_packet| (now - sent_packet.time) as f32 <= rtt_maximum + epsilon);
+        self.ackedBuffer.retain(|_, acked_packet| (now - acked_packet.time) as f32 <= (rtt_maximum*2.0) - epsilon);
 
-            if self.pendingAckQueue.queue.len() == 0 {
-                break;
+        let deliveries = &mut self.deliveries;
+        let lost = self.pendingAckBuffer.retain(|_, pending_ack_packet| {
+            let waiting = (now - pending_ack_packet.time) as f32 <= rtt_maximum + epsilon;
+            if let (false, Some(id)) = (waiting, pending_ack_packet.message) {
+                deliveries.push(Delivery::Lost(id));
             }
-            println!("PendingAckedQueue");
-        }
-
-    }
-*/
-
-pub fn UpdateQueues(&mut self) {
-    let epsilon : f32 = 0.0001;
-
-    /* println!("Sent: {}", self.sentQueue.size());
-    println!("Recv: {}", self.receivedQueue.size());
-    println!("Ack:  {}", self.receivedQueue.size());
-    println!("PAck: {}", self.receivedQueue.size());
-*/
-    loop {
-        match self.sentQueue.front() {
-            Some(sent_packet) => {
-                if sent_packet.time > self.rtt_maximum + epsilon {
-                    let _ = self.sentQueue.queue.pop_front();
-                } else {
-                    break;
-                }
-            },
-            None => {break;}
-        }
-
-        if self.sentQueue.queue.len() == 0 {
-            break;
-        }
-    //    println!("SentQueue");
-    }
-
-    if self.receivedQueue.queue.len() != 0 {
-        match self.receivedQueue.back() {
-            Some(received_packet) => {
-                let latest_sequence = received_packet.sequence;
-
-                let minimum_sequence = if latest_sequence >= 34  {
-                    latest_sequence - 34
-                } else {
-                    self.max_sequence - (34 - latest_sequence)
-                 };
-
-                let mut i=0;
-                loop {
-
-                    match self.receivedQueue.front() {
-                        Some(recv_front_packet) => {
-                            // TODO need to figure out why this things it smore recent
-                            let is_more_recent = !sequence_more_recent(&recv_front_packet.sequence, &minimum_sequence, &self.max_sequence);
-                            //println!("{}, {}, {}, {}", &recv_front_packet.sequence, &minimum_sequence, &self.max_sequence, is_more_recent );
-
-                            if is_more_recent {
-                                let _ = self.receivedQueue.queue.pop_front();
-                                //println!("ReceivedQueue");
-                            }
-                            else {
-                                break;
-                            }
-                            //println!("ReceivedQueue Packet {}", recv_front_packet);
-                        },
-                        None => {break;},
-                    }
-
-                    if self.receivedQueue.queue.len() == 0 {
-                        break;
-                    }
-
-                }
-            },
-            None => {},
-        }
-    }
-
-    loop {
-        match self.ackedQueue.front() {
-            Some(acked_packet) => {
-                if acked_packet.time > (self.rtt_maximum*2.0) - epsilon {
-                    let _ = self.ackedQueue.queue.pop_front();
-                }
-                else {
-                    break;
-                }
-            },
-            None => {break;},
-        }
-
-        if self.ackedQueue.queue.len() == 0 {
-            break;
-        }
-        //println!("AckedQueue");
-    }
-
-    loop {
-        match self.pendingAckQueue.front() {
-            Some(pending_ack_packet) => {
-                if pending_ack_packet.time > self.rtt_maximum + epsilon {
-                    let _ = self.pendingAckQueue.queue.pop_front();
-                    self.lost_packets += 1;
-                }
-                else {
-                    break;
-                }
-            },
-            None => {break;},
-        }
-
-        if self.pendingAckQueue.queue.len() == 0 {
-            break;
-        }
-        //println!("PendingAckedQueue");
+            waiting
+        });
+        self.lost_packets += lost as u32;
     }
 
-}
-
     pub fn UpdateStats(&mut self) {
+        let now = self.now();
         let mut sent_bytes_per_second: f32 = 0.0;
 
-        for sent_packet in &self.sentQueue.queue {
+        for (_, sent_packet) in self.sentBuffer.iter() {
             sent_bytes_per_second += sent_packet.size as f32;
         }
 
         let mut acked_packets_per_second = 0;
         let mut acked_bytes_per_second: f32 = 0.0;
 
-        for acked_packet in &self.ackedQueue.queue {
-            if acked_packet.time >= self.rtt_maximum {
+        for (_, acked_packet) in self.ackedBuffer.iter() {
+            if (now - acked_packet.time) as f32 >= self.rtt_maximum {
                 acked_packets_per_second += 1;
                 acked_bytes_per_second += acked_packet.size as f32;
             }
@@ -1029,86 +1410,377 @@ pub struct ReliableConnection {
     connection : Connection,
     reliability_system : ReliableSystem,
     packet_loss_mask : u32,
+    keep_alive_interval : f32,
+    last_sent : Duration,
+    encrypted : bool,
+    key_pair : Option<KeyPair>,  // Ours, while we wait for the server's half of the handshake
+    session : Option<Session>,  // Once the handshake is done, when encrypted
+    accepted : bool,  // The handshake is done: the server answered our request, or we answered the client's
+    answered_key : Option<[u8; PUBLIC_KEY_SIZE]>,  // Listening, the public key in the request we last answered
+    connect_token : Option<ConnectToken>,  // Presented in our ConnectionRequest, if we have one
+    client_id : u64,  // Given to us by the server; 0 until it has
+    cookie : [u8; COOKIE_SIZE],  // From the server's ConnectionChallenge, to send back in our request
 }
 
 impl ReliableConnection {
-    pub fn new(protocol_id: u32, timeout: f32, max_sequence : u32, port: u16) -> ReliableConnection {
+    pub fn new(protocol_id: u32, timeout: f32, port: u16) -> ReliableConnection {
+        ReliableConnection::with_clock(protocol_id, timeout, port, clock::system_clock())
+    }
+
+    pub fn with_clock(protocol_id: u32, timeout: f32, port: u16, clock: Arc<Clock>) -> ReliableConnection {
+        ReliableConnection::bind(protocol_id, timeout, any_address(port), clock)
+    }
+
+    // Binds a specific address, IPv4 or IPv6, instead of every IPv4 interface.
+    pub fn bind(protocol_id: u32, timeout: f32, listen_on: net::SocketAddr, clock: Arc<Clock>) -> ReliableConnection {
         let mut reliableConnection = ReliableConnection {
-            connection : Connection::new(protocol_id, timeout, port),
-            reliability_system : ReliableSystem::new(max_sequence),
+            connection : Connection::bind(protocol_id, timeout, listen_on, clock.clone()),
+            reliability_system : ReliableSystem::with_clock(clock.clone()),
             packet_loss_mask : 0,
+            keep_alive_interval : DEFAULT_KEEP_ALIVE_INTERVAL,
+            last_sent : clock.now(),
+            encrypted : false,
+            key_pair : None,
+            session : None,
+            accepted : false,
+            answered_key : None,
+            connect_token : None,
+            client_id : 0,
+            cookie : [0; COOKIE_SIZE],
         };
         reliableConnection.connection.ClearData();
         reliableConnection
     }
 
 
-    pub fn SendPacket(&mut self, data: Vec<u8>, size: usize) -> bool {
-        let mut packet_to_send = Packet::Packet::new();
+    // Queues the payload to go out with the next Flush, which Tick does for us. Returns the id
+    // poll_events will report its MessageAcked or MessageLost under, or None if it could not be
+    // queued. Empty payloads are refused: on the wire they are indistinguishable from keep-alives.
+    // Nothing can be sent until the handshake is done.
+    pub fn SendPacket(&mut self, data: &[u8]) -> Option<MessageId> {
+        if data.is_empty() || !self.HandshakeDone() {
+            return None;
+        }
+
+        let protocol_id = self.connection.Get_Protocol_Id();
+        let client_id = self.client_id;
 
-        packet_to_send.set_signature(self.connection.Get_Protocol_Id());
-        packet_to_send.set_sequence_number(self.reliability_system.get_local_sequence());
-        packet_to_send.set_ack(self.reliability_system.get_remote_sequence());
-        packet_to_send.set_ackbit(self.reliability_system.GenerateAckBits());
+        let successful = {
+            let reliability_system = &mut self.reliability_system;
+            let session = self.session.as_mut();
+            self.connection.QueuePacket(|buffer| encode_reliable_packet(protocol_id, client_id, reliability_system, session, data, buffer))
+        };
 
-        packet_to_send.set_data(data);
+        if !successful {
+            return None;
+        }
 
-        let encoded_packet : Vec<u8>;
+        let id = self.reliability_system.PacketSent(data.len());
+        self.last_sent = self.connection.clock.now();
+        self.CollectDeliveries();
+        Some(id)
+    }
 
-        match bincode::rustc_serialize::encode(&packet_to_send, bincode::SizeLimit::Infinite) {
-            Ok(msg) => {
-                encoded_packet = msg;
-            },
-            Err(_) => {
-                panic!("Could not encode packet!");
+    // Queues a header-only packet, which carries our acks and keeps the peer from timing us out.
+    fn SendKeepAlive(&mut self) {
+        let protocol_id = self.connection.Get_Protocol_Id();
+        let client_id = self.client_id;
+
+        let successful = {
+            let reliability_system = &mut self.reliability_system;
+            let session = self.session.as_mut();
+            self.connection.QueuePacket(|buffer| encode_reliable_packet(protocol_id, client_id, reliability_system, session, &[], buffer))
+        };
+
+        if successful {
+            self.reliability_system.KeepAliveSent();
+            self.CollectDeliveries();
+        }
+        self.last_sent = self.connection.clock.now();
+    }
+
+    // Offers the server our public key. Tick repeats it until the server answers with its own.
+    fn SendConnectionRequest(&mut self) {
+        let protocol_id = self.connection.Get_Protocol_Id();
+
+        if let Some(ref key_pair) = self.key_pair {
+            let public_key = key_pair.public_key();
+            let cookie = &self.cookie;
+            let token = self.connect_token.as_ref();
+            self.connection.QueuePacket(|buffer| encode_connection_request(protocol_id, cookie, token, public_key, buffer));
+        }
+        self.last_sent = self.connection.clock.now();
+    }
+
+    // Listening for a single peer: answer whoever asks with a client id, agreeing a session with
+    // them and sending back our half when encrypted. A repeated request, because our answer went
+    // missing, gets the same answer again. Nobody has checked the sender's address, so the answer
+    // comes out of what it sent us, and the first packet echoing the id is what validates it.
+    // Connect tokens are for a ReliableServer to check; requests carrying one are ignored, and so
+    // are requests too short to answer.
+    fn AnswerConnectionRequest(&mut self, datagram: &[u8]) {
+        if self.connection.GetMode() != &Mode::Server {
+            return;
+        }
+
+        if datagram.len() < MIN_REQUEST_SIZE {
+            self.connection.rejected_packets += 1;
+            return;
+        }
+
+        let public_key = match decode_connection_request(datagram) {
+            Some((_, None, public_key)) => public_key,
+            _ => {
+                return;
+            }
+        };
+
+        let repeated = match self.answered_key {
+            Some(ref answered) => &answered[..] == public_key,
+            None => false,
+        };
+
+        if !repeated {
+            if self.encrypted {
+                match Session::new(&KeyPair::generate(), public_key, false) {
+                    Some(session) => {
+                        self.session = Some(session);
+                    },
+                    None => {
+                        return;
+                    }
+                }
             }
+
+            let mut answered = [0u8; PUBLIC_KEY_SIZE];
+            answered.copy_from_slice(public_key);
+            self.answered_key = Some(answered);
+            self.accepted = true;
+            self.client_id = new_client_id();
+            self.reliability_system.reset();
         }
 
-        let successful = self.connection.SendPacket(&encoded_packet, size);
+        let protocol_id = self.connection.Get_Protocol_Id();
+        let client_id = self.client_id;
+        let public_key : &[u8] = match self.session {
+            Some(ref session) => &session.local_public_key()[..],
+            None => &[],
+        };
 
-        if !successful {
-            return false;
+        self.connection.QueuePacket(|buffer| encode_handshake(protocol_id, client_id, Packet::PacketType::ConnectionResponse, public_key, buffer));
+    }
+
+    // The server wants proof that we are at the address we send from before it does any work for
+    // us. Ask again straight away, with its cookie.
+    fn AcceptChallenge(&mut self, datagram: &[u8]) {
+        if self.accepted || self.key_pair.is_none() {
+            return;
         }
 
-        self.reliability_system.PacketSent(size);
-        true
+        match decode_challenge(datagram) {
+            Some(cookie) => {
+                self.cookie.copy_from_slice(cookie);
+            },
+            None => {
+                return;
+            }
+        }
+
+        self.SendConnectionRequest();
     }
 
-    pub fn ReceivePacket(&mut self, data: &mut Vec<u8>, size: usize) -> usize {
-        let mut buffer = Vec::<u8>::new();
-        let header_size = mem::size_of::<Packet::UDPHeader>();
-        let received_bytes = self.connection.ReceivePacket(&mut buffer, size);
+    // The server's answer to our request, which also tells us our client id, and when encrypted
+    // carries its public key. Any repeats after the first are ignored.
+    fn CompleteHandshake(&mut self, datagram: &[u8]) {
+        if self.accepted || self.key_pair.is_none() {
+            return;
+        }
 
-        let buffer_arr = buffer.as_slice();
+        if self.encrypted {
+            let session = match (self.key_pair.as_ref(), decode_handshake(datagram), self.connect_token.as_ref()) {
+                (Some(key_pair), Some(public_key), Some(token)) => {
+                    Session::with_token_keys(key_pair, public_key, true, &token.client_to_server_key, &token.server_to_client_key)
+                },
+                (Some(key_pair), Some(public_key), None) => {
+                    Session::new(key_pair, public_key, true)
+                },
+                _ => None,
+            };
 
-        if received_bytes <= 12 {
-            return 0;
+            if session.is_none() {
+                return;
+            }
+            self.session = session;
+        }
+
+        // The server starts afresh from sequence 0, and so do we, or our replay window would turn
+        // its packets away.
+        self.accepted = true;
+        self.key_pair = None;
+        self.client_id = client_id_of(datagram).unwrap_or(0);
+        self.reliability_system.reset();
+        self.connection.Heard();
+    }
+
+    // The server has kicked us. A Disconnect has to open and be in sequence like any payload, so
+    // nobody but the server can send one, nor send one of its old ones again.
+    fn AcceptDisconnect(&mut self, datagram: &[u8]) {
+        if self.connection.GetMode() != &Mode::Client || !(self.connection.IsConnected() || self.connection.IsConnecting()) {
+            return;
+        }
+
+        let mut code = [0u8; 1];
+        match decode_sequenced_packet(Packet::PacketType::Disconnect, &mut self.reliability_system, self.session.as_mut(), datagram, &mut code) {
+            Some(1) => {},
+            _ => {
+                return;
+            }
         }
 
-        let decoded_packet: Packet::Packet;
+        self.session = None;
+        self.accepted = false;
+        self.key_pair = None;
+        self.client_id = 0;
+        self.CollectDeliveries();
+        self.connection.OnDisconnect(DisconnectReason::Kicked { code : code[0] });
+    }
 
-        // TODO : Move this into a 'Packet' interpreter from [u8] to Packet
-        match bincode::rustc_serialize::decode(&buffer_arr[..]) {
-            Ok(msg) => {
-                decoded_packet = msg;
-            },
-            Err(_) => {
-                panic!("Lets just panic for now...why could we not receieve a packet?");
+    // Until an id has been given out, by the server or by us when listening, we take whatever
+    // arrives; after that, only packets carrying it.
+    fn IsOurClientId(&self, datagram: &[u8]) -> bool {
+        self.client_id == 0 || client_id_of(datagram) == Some(self.client_id)
+    }
+
+    // Whether payloads can flow: once the handshake is done, whether or not it agreed keys.
+    fn HandshakeDone(&self) -> bool {
+        self.accepted
+    }
+
+    // Copies the next payload into `data` and returns its size, or 0 if nothing arrived.
+    pub fn ReceivePacket(&mut self, data: &mut [u8]) -> usize {
+        self.receive(data).unwrap_or(0)
+    }
+
+    // Reads everything waiting on the socket and returns it as Message events, along with
+    // whatever else has happened since the last poll. Use this or ReceivePacket, not both:
+    // each takes the packets the other would have seen.
+    pub fn poll_events(&mut self) -> Vec<ConnectionEvent> {
+        let mut events = Vec::new();
+        let mut data = self.connection.buffers.acquire();
+
+        // Once stopped there is no socket to read, but the events from stopping are still owed.
+        while self.connection.IsRunning() {
+            match self.receive(&mut data) {
+                Some(size) => {
+                    events.extend(self.connection.events.drain(..));
+                    events.push(ConnectionEvent::Message { channel : DEFAULT_CHANNEL, data : data[..size].to_vec() });
+                },
+                None => {
+                    break;
+                }
             }
         }
 
-        let data_bytes = received_bytes - header_size;
+        self.connection.buffers.release(data);
+        events.extend(self.connection.events.drain(..));
+        events
+    }
+
+    // The next payload from our peer. None once the socket has been drained.
+    fn receive(&mut self, data: &mut [u8]) -> Option<usize> {
+        let mut buffer = self.connection.buffers.acquire();
+        let mut data_bytes = None;
+
+        while let Some(received_bytes) = self.connection.NextPacket(&mut buffer) {
+            let datagram = &buffer[..received_bytes];
+
+            match packet_type_of(datagram) {
+                Some(Packet::PacketType::ConnectionRequest) => {
+                    self.AnswerConnectionRequest(datagram);
+                    continue;
+                },
+                Some(Packet::PacketType::ConnectionChallenge) => {
+                    self.AcceptChallenge(datagram);
+                    continue;
+                },
+                Some(Packet::PacketType::ConnectionResponse) => {
+                    self.CompleteHandshake(datagram);
+                    continue;
+                },
+                _ => {
+                    if !self.HandshakeDone() {
+                        continue;
+                    }
+                }
+            }
+
+            if !self.IsOurClientId(datagram) {
+                self.connection.rejected_packets += 1;
+                continue;
+            }
+
+            if packet_type_of(datagram) == Some(Packet::PacketType::Disconnect) {
+                self.AcceptDisconnect(datagram);
+                continue;
+            }
+
+            let decoded = decode_reliable_packet(&mut self.reliability_system, self.session.as_mut(), datagram, data);
 
-        self.reliability_system.PacketReceived(decoded_packet.get_sequence_num(), data_bytes);
-        self.reliability_system.ProcessAck(decoded_packet.get_ack(), decoded_packet.get_ackbits());
+            if decoded.is_none() {
+                continue;
+            }
+            self.connection.Heard();
+
+            // Past the handshake, only a packet with the id we gave out gets this far.
+            self.connection.Validate();
+
+            if decoded != Some(0) {
+                data_bytes = decoded;
+                break;
+            }
+        }
 
-        mem::replace::<(Vec<u8>)>(data, decoded_packet.get_data().raw_data.clone());
+        self.connection.buffers.release(buffer);
+        self.CollectDeliveries();
         data_bytes
     }
 
-    pub fn Update(&mut self, deltaTime: f32) {
-        self.connection.Update(deltaTime);
-        self.reliability_system.Update(deltaTime);
+    // Turns acks and losses the reliability system has seen into events.
+    fn CollectDeliveries(&mut self) {
+        for delivery in self.reliability_system.drain_deliveries() {
+            self.connection.PushEvent(delivery_event(delivery));
+        }
+    }
+
+    // Only kept for callers that still drive us with a frame delta; see Tick.
+    pub fn Update(&mut self, _deltaTime: f32) {
+        self.Tick();
+    }
+
+    pub fn Tick(&mut self) {
+        self.connection.Tick();
+        self.reliability_system.Tick();
+        self.CollectDeliveries();
+
+        let talking = self.connection.IsConnected() || self.connection.IsConnecting();
+        if talking && keep_alive_due(self.last_sent, self.connection.clock.now(), self.keep_alive_interval) {
+            if self.HandshakeDone() {
+                // Keep-alives are not answers, so an address that has not been validated gets none.
+                if self.connection.IsValidated() {
+                    self.SendKeepAlive();
+                }
+            }
+            else if self.key_pair.is_some() {
+                self.SendConnectionRequest();
+            }
+        }
+
+        self.connection.Flush();
+    }
+
+    // Sends everything queued by SendPacket since the last flush. Tick does this once a tick.
+    pub fn Flush(&mut self) -> usize {
+        self.connection.Flush()
     }
 
 
@@ -1124,6 +1796,58 @@ impl ReliableConnection {
         self.packet_loss_mask = mask;
     }
 
+    // How long Tick lets us stay quiet before it sends a keep-alive, in seconds. 0, or anything
+    // else that is not a finite positive number, turns them off.
+    pub fn SetKeepAliveInterval(&mut self, seconds: f32) {
+        self.keep_alive_interval = keep_alive_interval(seconds);
+    }
+
+    // Must match the server's, and be set before anything is sent: it starts reliability afresh.
+    pub fn SetAckWindow(&mut self, ack_window: AckWindow) {
+        self.reliability_system = ReliableSystem::with_ack_window(ack_window, self.connection.clock.clone());
+    }
+
+    // Authenticates every packet with `secret`, which the server must share. See Connection.
+    pub fn SetSecretKey(&mut self, secret: &[u8]) {
+        self.connection.SetSecretKey(secret);
+    }
+
+    // Seals every payload with keys agreed in a handshake when we connect, so nobody on the path
+    // can read or alter them. The other end has to turn it on too, and it has to be done before
+    // Connect or Listen. See encryption.rs.
+    pub fn EnableEncryption(&mut self) {
+        self.encrypted = true;
+    }
+
+    // For servers that only take clients with a token from their authority; see connect_token.rs.
+    // This turns on encryption and makes the token's first server our destination. Connect next.
+    // A token that names no server is refused, leaving everything as it was, and we return false.
+    pub fn SetConnectToken(&mut self, token: ConnectToken) -> bool {
+        let destination = match token.server_addresses.first() {
+            Some(address) => address.clone(),
+            None => {
+                return false;
+            }
+        };
+
+        self.connection.SetAddress(destination);
+        self.encrypted = true;
+        self.connect_token = Some(token);
+        true
+    }
+
+    // The id the server gave us when we connected, or 0 if it has not yet.
+    pub fn get_client_id(&self) -> u64 {
+        self.client_id
+    }
+
+    // Failed the MAC check, carried someone else's client id or, when encrypted, would not open
+    // under our session.
+    pub fn get_rejected_packets(&self) -> u32 {
+        let unopened = self.session.as_ref().map_or(0, |session| session.get_rejected_packets());
+        self.connection.get_rejected_packets() + unopened
+    }
+
     fn ClearData(&mut self) {
         self.reliability_system.reset();
     }
@@ -1132,23 +1856,48 @@ impl ReliableConnection {
         self.connection.SetAddress(addr);
     }
 
+    // The address we send from, including the port when we were bound to port 0.
+    pub fn local_addr(&self) -> Address {
+        self.connection.local_addr()
+    }
+
     pub fn Start(&mut self) -> bool {
         self.connection.Start()
     }
 
     pub fn Listen(&mut self) {
+        self.session = None;
+        self.accepted = false;
+        self.answered_key = None;
+        self.client_id = 0;
         self.connection.Listen()
     }
 
+    // Starts the handshake; IsConnected turns true, and payloads can be sent, once the server
+    // answers. Encrypted, the handshake agrees our keys as well.
     pub fn Connect(&mut self) {
         let address = &self.connection.GetAddress();
-        self.connection.Connect(address)
+        self.connection.Connect(address);
+        self.session = None;
+        self.accepted = false;
+        self.client_id = 0;
+        self.cookie = [0; COOKIE_SIZE];
+        self.key_pair = Some(KeyPair::generate());
+
+        if self.connection.IsRunning() {
+            self.SendConnectionRequest();
+        }
+    }
+
+    pub fn IsConnected(&self) -> bool {
+        self.connection.IsConnected() && self.HandshakeDone()
     }
 
     pub fn PrintStats(&self) {
         let sent_packets = self.reliability_system.get_acked_packets();
         let acked_packets = self.reliability_system.get_acked_packets();
         let lost_packets = self.reliability_system.get_lost_packets();
+        let replayed_packets = self.reliability_system.get_replayed_packets();
 
         let rtt = self.reliability_system.get_round_trip_time();
         let sent_bandwidth = self.reliability_system.get_sent_bandwidth();
@@ -1161,8 +1910,8 @@ impl ReliableConnection {
             0.0
         };
 
-        println!("rtt {}ms, sent {}, acked {}, lost {} ({}), sent bandwidth = {}kbps, acked bandwidth = {}kbps\n",
-                rtt*1000.0, sent_packets, acked_packets, lost_packets, lost, sent_bandwidth, acked_packets);
+        println!("rtt {}ms, sent {}, acked {}, lost {} ({}), replayed {}, sent bandwidth = {}kbps, acked bandwidth = {}kbps\n",
+                rtt*1000.0, sent_packets, acked_packets, lost_packets, lost, replayed_packets, sent_bandwidth, acked_packets);
     }
 
 }
@@ -1173,7 +1922,1042 @@ impl ReliableConnection {
 
 
 
-///    ######                                        #####
+// Queues an event for the application to pick up. If it never does, the oldest ones go.
+fn push_event<T>(events: &mut VecDeque<T>, event: T) {
+    if events.len() >= MAX_QUEUED_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(event);
+}
+
+fn delivery_event(delivery: Delivery) -> ConnectionEvent {
+    match delivery {
+        Delivery::Acked(id) => ConnectionEvent::MessageAcked { id : id },
+        Delivery::Lost(id) => ConnectionEvent::MessageLost { id : id },
+    }
+}
+
+// How long a connection goes without sending before it sends a keep-alive, in seconds. This keeps
+// the peer's timeout from running out, and gets acks back to it even when we have nothing to say.
+// It wants to be well inside rtt_maximum, or the peer will give up on packets we did receive.
+pub const DEFAULT_KEEP_ALIVE_INTERVAL : f32 = 0.1;
+
+// The interval to keep for `seconds`: itself if it is a finite positive number, or 0 for off.
+fn keep_alive_interval(seconds: f32) -> f32 {
+    if seconds.is_finite() && seconds > 0.0 {
+        seconds
+    } else {
+        0.0
+    }
+}
+
+// True if nothing has been sent for `interval` seconds. An interval of 0 turns keep-alives off.
+fn keep_alive_due(last_sent: Duration, now: Duration, interval: f32) -> bool {
+    if interval <= 0.0 {
+        return false;
+    }
+
+    match now.checked_sub(last_sent) {
+        Some(quiet) => quiet >= clock::seconds_to_duration(interval),
+        None => false,
+    }
+}
+
+// Writes the payload into `buffer` behind our reliability header, ready for the wire.
+// A header with no payload after it is a keep-alive. With a session the payload is sealed, and
+// keep-alives carry just the tag.
+// Returns the datagram's length, or None if the payload is too big for one.
+fn encode_reliable_packet(protocol_id: u32, client_id: u64, reliability_system: &mut ReliableSystem, session: Option<&mut Session>, data: &[u8], buffer: &mut [u8]) -> Option<usize> {
+    encode_sequenced_packet(protocol_id, client_id, Packet::PacketType::Payload, reliability_system, session, data, buffer)
+}
+
+// encode_reliable_packet for packets of other types that need the same protection, like Disconnect.
+fn encode_sequenced_packet(protocol_id: u32, client_id: u64, packet_type: Packet::PacketType, reliability_system: &mut ReliableSystem, session: Option<&mut Session>, data: &[u8], buffer: &mut [u8]) -> Option<usize> {
+    let mut header = Packet::UDPHeader::new(protocol_id);
+    header.packet_type = packet_type as u8;
+    header.client_id = client_id;
+
+    let ack_bits = reliability_system.GenerateAckBits();
+    let words = reliability_system.get_ack_window().words();
+
+    header.sequence_number = reliability_system.get_local_sequence();
+    header.ack_num = reliability_system.get_remote_sequence();
+    header.ack_bits = ack_bits[0];
+
+    match session {
+        Some(session) => {
+            let header_bytes = match Packet::write_datagram_with_acks(&header, &ack_bits[1..words], &[], buffer) {
+                Some(length) => length,
+                None => {
+                    return None;
+                }
+            };
+
+            let (written, rest) = buffer.split_at_mut(header_bytes);
+            session.seal(header.sequence_number, written, data, rest).map(|sealed| header_bytes + sealed)
+        },
+        None => {
+            Packet::write_datagram_with_acks(&header, &ack_bits[1..words], data, buffer)
+        }
+    }
+}
+
+// Feeds a received datagram through the reliability system and copies its payload into `data`,
+// cutting it short if `data` is too small. Some(0) for a keep-alive, which had nothing for the
+// application. None if the datagram is malformed or is not a payload packet, if it does not open
+// under our session, or if it was a replay; only Some means the peer was really heard from.
+// Packets that fail to open never reach the reliability system, and replays go no further than it.
+fn decode_reliable_packet(reliability_system: &mut ReliableSystem, session: Option<&mut Session>, datagram: &[u8], data: &mut [u8]) -> Option<usize> {
+    decode_sequenced_packet(Packet::PacketType::Payload, reliability_system, session, datagram, data)
+}
+
+// decode_reliable_packet for packets of `packet_type` rather than payloads.
+fn decode_sequenced_packet(packet_type: Packet::PacketType, reliability_system: &mut ReliableSystem, session: Option<&mut Session>, datagram: &[u8], data: &mut [u8]) -> Option<usize> {
+    let mut ack_bits : AckBits = [0; 4];
+    let mut opened = [0u8; Packet::MAX_PACKET_SIZE];
+    let words = reliability_system.get_ack_window().words();
+
+    match Packet::read_datagram_with_acks(datagram, &mut ack_bits[1..words]) {
+        Some((header, payload)) => {
+            if Packet::PacketType::from_byte(header.packet_type) != Some(packet_type) {
+                return None;
+            }
+
+            let payload = match session {
+                Some(session) => {
+                    let header_bytes = datagram.len() - payload.len();
+
+                    match session.open(header.sequence_number, &datagram[..header_bytes], payload, &mut opened) {
+                        Some(length) => &opened[..length],
+                        None => {
+                            return None;
+                        }
+                    }
+                },
+                None => {
+                    payload
+                }
+            };
+
+            ack_bits[0] = header.ack_bits;
+
+            if !reliability_system.PacketReceived(header.sequence_number, payload.len()) {
+                return None;
+            }
+            reliability_system.ProcessAck(header.ack_num, &ack_bits[..words]);
+
+            let data_bytes = cmp::min(payload.len(), data.len());
+            data[..data_bytes].copy_from_slice(&payload[..data_bytes]);
+
+            Some(data_bytes)
+        },
+        None => {
+            None
+        }
+    }
+}
+
+// The type of a datagram that has already passed IsOurs. None if it is not one we know.
+fn packet_type_of(datagram: &[u8]) -> Option<Packet::PacketType> {
+    match Packet::UDPHeader::read_from(datagram) {
+        Some(header) => Packet::PacketType::from_byte(header.packet_type),
+        None => None,
+    }
+}
+
+// The client id in a datagram's header. None if it is too short to have one.
+fn client_id_of(datagram: &[u8]) -> Option<u64> {
+    Packet::UDPHeader::read_from(datagram).map(|header| header.client_id)
+}
+
+// A random id for a new peer. Never 0, which is what a client sends before it has been given one.
+fn new_client_id() -> u64 {
+    loop {
+        let client_id = rand::random::<u64>();
+        if client_id != 0 {
+            return client_id;
+        }
+    }
+}
+
+// Writes a ConnectionResponse, which carries nothing but our public key and the client's id, or
+// a ConnectionChallenge, which carries nothing but a cookie.
+fn encode_handshake(protocol_id: u32, client_id: u64, packet_type: Packet::PacketType, payload: &[u8], buffer: &mut [u8]) -> Option<usize> {
+    let mut header = Packet::UDPHeader::new(protocol_id);
+    header.packet_type = packet_type as u8;
+    header.client_id = client_id;
+
+    Packet::write_datagram(&header, payload, buffer)
+}
+
+// The public key in a ConnectionResponse, or a ConnectionRequest without a connect token.
+fn decode_handshake(datagram: &[u8]) -> Option<&[u8]> {
+    match Packet::read_datagram(datagram) {
+        Some((_, payload)) if payload.len() == PUBLIC_KEY_SIZE => Some(payload),
+        _ => None,
+    }
+}
+
+// Our side of a session with a client, mixing in the keys from its connect token if it had one.
+fn new_server_session(public_key: &[u8], private: Option<&PrivateConnectToken>) -> Option<Session> {
+    match private {
+        Some(private) => {
+            Session::with_token_keys(&KeyPair::generate(), public_key, false, &private.client_to_server_key, &private.server_to_client_key)
+        },
+        None => {
+            Session::new(&KeyPair::generate(), public_key, false)
+        }
+    }
+}
+
+// Whether a connect token listing `address` means us, listening on `local`. Bound to every
+// interface we answer on any of them, so only the port has to agree.
+fn is_address_of(address: &Address, local: &Address) -> bool {
+    address.get_port() == local.get_port() && (local.is_empty() || address.get_address() == local.get_address())
+}
+
+// Whether a ban on `target` takes in a peer at `address` that connected with `token_client_id`.
+// Peers without a connect token have 0 there, which no ban takes in.
+fn ban_covers(target: &BanTarget, address: &Address, token_client_id: u64) -> bool {
+    match *target {
+        BanTarget::Address(banned) => address.get_address() == banned,
+        BanTarget::ClientId(banned) => token_client_id != 0 && token_client_id == banned,
+    }
+}
+
+// A ConnectionRequest starts with the cookie the server gave us, or zeroes if it has not yet. A
+// byte saying whether we have a connect token comes next, then our public key, then the request
+// part of the token if we have one. Zeroes pad it out to MIN_REQUEST_SIZE.
+fn encode_connection_request(protocol_id: u32, cookie: &[u8], token: Option<&ConnectToken>, public_key: &[u8], buffer: &mut [u8]) -> Option<usize> {
+    let mut header = Packet::UDPHeader::new(protocol_id);
+    header.packet_type = Packet::PacketType::ConnectionRequest as u8;
+
+    let mut length = match Packet::write_datagram(&header, cookie, buffer) {
+        Some(length) => length,
+        None => {
+            return None;
+        }
+    };
+
+    if length + 1 + PUBLIC_KEY_SIZE > buffer.len() {
+        return None;
+    }
+
+    buffer[length] = if token.is_some() { 1 } else { 0 };
+    buffer[length + 1..length + 1 + PUBLIC_KEY_SIZE].copy_from_slice(public_key);
+    length += 1 + PUBLIC_KEY_SIZE;
+
+    if let Some(token) = token {
+        match token.write_request(&mut buffer[length..]) {
+            Some(token_bytes) => {
+                length += token_bytes;
+            },
+            None => {
+                return None;
+            }
+        }
+    }
+
+    if length < MIN_REQUEST_SIZE {
+        if MIN_REQUEST_SIZE > buffer.len() {
+            return None;
+        }
+
+        for byte in &mut buffer[length..MIN_REQUEST_SIZE] {
+            *byte = 0;
+        }
+        length = MIN_REQUEST_SIZE;
+    }
+
+    Some(length)
+}
+
+// Splits a ConnectionRequest into its cookie, its connect token if it has one, and the client's
+// public key. Whatever padding follows is left alone.
+fn decode_connection_request(datagram: &[u8]) -> Option<(&[u8], Option<&[u8]>, &[u8])> {
+    let payload = match Packet::read_datagram(datagram) {
+        Some((_, payload)) if payload.len() >= COOKIE_SIZE + 1 + PUBLIC_KEY_SIZE => payload,
+        _ => {
+            return None;
+        }
+    };
+
+    let (cookie, rest) = payload.split_at(COOKIE_SIZE);
+    let public_key = &rest[1..1 + PUBLIC_KEY_SIZE];
+    let rest = &rest[1 + PUBLIC_KEY_SIZE..];
+
+    match payload[COOKIE_SIZE] {
+        0 => Some((cookie, None, public_key)),
+        1 if rest.len() >= CONNECT_TOKEN_REQUEST_SIZE => Some((cookie, Some(&rest[..CONNECT_TOKEN_REQUEST_SIZE]), public_key)),
+        _ => None,
+    }
+}
+
+// The cookie in a ConnectionChallenge.
+fn decode_challenge(datagram: &[u8]) -> Option<&[u8]> {
+    match Packet::read_datagram(datagram) {
+        Some((_, payload)) if payload.len() == COOKIE_SIZE => Some(payload),
+        _ => None,
+    }
+}
+
+
+
+
+
+
+
+///     ######                                                  #####
+///     #     # ###### #      #   ##   #####  #      ######    #     # ###### #####  #    # ###### #####
+///     #     # #      #      #  #  #  #    # #      #         #       #      #    # #    # #      #    #
+///     ######  #####  #      # #    # #####  #      #####      #####  #####  #    # #    # #####  #    #
+///     #   #   #      #      # ###### #    # #      #               # #      #####  #    # #      #####
+///     #    #  #      #      # #    # #    # #      #         #     # #      #   #   #  #  #      #   #
+///     #     # ###### ###### # #    # #####  ###### ######     #####  ###### #    #   ##   ###### #    #
+
+// A ReliableConnection only ever talks to one destination. The server needs to juggle every
+// client that reaches it, so it keeps one ReliableSystem per peer behind a single listening
+// Connection.
+
+pub const MAX_PEERS: usize = 64;
+
+#[derive(Clone, Debug, Default)]
+pub struct ServerStats {
+    pub peers : usize,
+    pub sent_packets : u64,
+    pub acked_packets : u64,
+    pub lost_packets : u64,
+    pub rejected_packets : u64,
+    pub replayed_packets : u64,
+    pub dropped_packets : u64,  // Over the rate limit, or from a banned source
+    pub bans : u64,
+    pub denied_packets : u64,  // From addresses outside the access list
+    pub refused_connections : u64,  // Attempts to connect while banned
+    pub sent_bandwidth : f32,
+    pub acked_bandwidth : f32,
+}
+
+impl ServerStats {
+    pub fn merge(&mut self, other: &ServerStats) {
+        self.peers += other.peers;
+        self.sent_packets += other.sent_packets;
+        self.acked_packets += other.acked_packets;
+        self.lost_packets += other.lost_packets;
+        self.rejected_packets += other.rejected_packets;
+        self.replayed_packets += other.replayed_packets;
+        self.dropped_packets += other.dropped_packets;
+        self.bans += other.bans;
+        self.denied_packets += other.denied_packets;
+        self.refused_connections += other.refused_connections;
+        self.sent_bandwidth += other.sent_bandwidth;
+        self.acked_bandwidth += other.acked_bandwidth;
+    }
+}
+
+impl fmt::Display for ServerStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lost = if self.sent_packets > 0 {
+            (self.lost_packets as f32 / self.sent_packets as f32) * 100.0
+        }
+        else {
+            0.0
+        };
+
+        write!(f, "peers {}, sent {}, acked {}, lost {} ({}), rejected {}, replayed {}, dropped {}, bans {}, denied {}, refused {}, sent bandwidth = {}kbps, acked bandwidth = {}kbps",
+               self.peers, self.sent_packets, self.acked_packets, self.lost_packets, lost, self.rejected_packets,
+               self.replayed_packets, self.dropped_packets, self.bans, self.denied_packets, self.refused_connections, self.sent_bandwidth, self.acked_bandwidth)
+    }
+}
+
+// Someone the server is talking to. Only an address that brought back one of our cookies becomes a
+// peer, so every peer is validated; an address that has not done that has nothing kept for it, and
+// gets nothing but challenges sent through QueueReplyTo.
+struct Peer {
+    address : Address,
+    reliability_system : ReliableSystem,
+    last_heard : Duration,
+    last_sent : Duration,
+    session : Option<Session>,  // Agreed in the handshake, when the server is encrypted
+    public_key : [u8; PUBLIC_KEY_SIZE],  // From the peer's request, to know a repeat of it
+    client_id : u64,  // Ours to give; every packet from the peer has to carry it
+    token_client_id : u64,  // From the peer's connect token; 0 without one
+}
+
+pub struct ReliableServer {
+    connection : Connection,
+    peers : Vec<Peer>,
+    timeout : f32,
+    clock : Arc<Clock>,
+    events : VecDeque<(Address, ConnectionEvent)>,
+    keep_alive_interval : f32,
+    ack_window : AckWindow,
+    encrypted : bool,
+    token_key : Option<Vec<u8>>,  // Shared with the authority that issues connect tokens
+    used_tokens : TokenRegistry,
+    cookie_key : CookieKey,  // Ours alone, for the cookies in ConnectionChallenges
+    banned : HashMap<BanTarget, Duration>,  // Until when
+    refused_connections : u64,  // Attempts to connect while banned
+}
+
+impl ReliableServer {
+    pub fn new(protocol_id: u32, timeout: f32, port: u16) -> ReliableServer {
+        ReliableServer::with_clock(protocol_id, timeout, port, clock::system_clock())
+    }
+
+    pub fn with_clock(protocol_id: u32, timeout: f32, port: u16, clock: Arc<Clock>) -> ReliableServer {
+        let connection = Connection::with_clock(protocol_id, timeout, port, clock.clone());
+        ReliableServer::with_connection(connection, timeout, clock)
+    }
+
+    // One shard of a multi-threaded server. Every shard binds the same address with SO_REUSEPORT and
+    // keeps its own peers; the kernel sends each client's datagrams to the same shard every time.
+    // Give every shard a fixed port: with port 0 each one would be handed a different port.
+    pub fn with_reuse_port(protocol_id: u32, timeout: f32, listen_on: net::SocketAddr, clock: Arc<Clock>) -> ReliableServer {
+        let connection = Connection::with_reuse_port(protocol_id, timeout, listen_on, clock.clone());
+        ReliableServer::with_connection(connection, timeout, clock)
+    }
+
+    // Binds a specific address. `[::]` serves IPv4 and IPv6 clients from the one socket.
+    pub fn bind(protocol_id: u32, timeout: f32, listen_on: net::SocketAddr, clock: Arc<Clock>) -> ReliableServer {
+        let connection = Connection::bind(protocol_id, timeout, listen_on, clock.clone());
+        ReliableServer::with_connection(connection, timeout, clock)
+    }
+
+    fn with_connection(connection: Connection, timeout: f32, clock: Arc<Clock>) -> ReliableServer {
+        ReliableServer {
+            connection : connection,
+            peers : Vec::new(),
+            timeout : timeout,
+            clock : clock,
+            events : VecDeque::new(),
+            keep_alive_interval : DEFAULT_KEEP_ALIVE_INTERVAL,
+            ack_window : AckWindow::Bits32,
+            encrypted : false,
+            token_key : None,
+            used_tokens : TokenRegistry::new(),
+            cookie_key : CookieKey::generate(),
+            banned : HashMap::new(),
+            refused_connections : 0,
+        }
+    }
+
+    pub fn Start(&mut self) -> bool {
+        self.connection.Start()
+    }
+
+    pub fn Listen(&mut self) {
+        self.connection.Listen()
+    }
+
+    pub fn Stop(&mut self) {
+        for index in 0..self.peers.len() {
+            let address = self.peers[index].address.clone();
+            self.PushEvent(address, ConnectionEvent::Disconnected { reason : DisconnectReason::Stopped });
+        }
+
+        self.peers.clear();
+        self.connection.Stop()
+    }
+
+    // Like ReliableConnection::SendPacket, for one of our peers. None if `address` is not one.
+    pub fn SendPacket(&mut self, address: &Address, data: &[u8]) -> Option<MessageId> {
+        if data.is_empty() {
+            return None;
+        }
+
+        let protocol_id = self.connection.Get_Protocol_Id();
+
+        let peer_index;
+
+        match self.find_peer(address) {
+            Some(index) => {
+                peer_index = index;
+            },
+            None => {
+                return None;
+            }
+        }
+
+        let successful = {
+            let peer = &mut self.peers[peer_index];
+            let client_id = peer.client_id;
+            let reliability_system = &mut peer.reliability_system;
+            let session = peer.session.as_mut();
+            self.connection.QueuePacketTo(address, |buffer| encode_reliable_packet(protocol_id, client_id, reliability_system, session, data, buffer))
+        };
+
+        if !successful {
+            return None;
+        }
+
+        let id = self.peers[peer_index].reliability_system.PacketSent(data.len());
+        self.peers[peer_index].last_sent = self.clock.now();
+        self.CollectDeliveries(peer_index);
+        Some(id)
+    }
+
+    fn SendKeepAlive(&mut self, peer_index: usize) {
+        let protocol_id = self.connection.Get_Protocol_Id();
+
+        let successful = {
+            let peer = &mut self.peers[peer_index];
+            let client_id = peer.client_id;
+            let reliability_system = &mut peer.reliability_system;
+            let session = peer.session.as_mut();
+            self.connection.QueuePacketTo(&peer.address, |buffer| encode_reliable_packet(protocol_id, client_id, reliability_system, session, &[], buffer))
+        };
+
+        if successful {
+            self.peers[peer_index].reliability_system.KeepAliveSent();
+            self.CollectDeliveries(peer_index);
+        }
+        self.peers[peer_index].last_sent = self.clock.now();
+    }
+
+    // How long Tick lets a peer go without hearing from us before it sends a keep-alive, in
+    // seconds. 0, or anything else that is not a finite positive number, turns them off.
+    pub fn SetKeepAliveInterval(&mut self, seconds: f32) {
+        self.keep_alive_interval = keep_alive_interval(seconds);
+    }
+
+    // Every client has to use the same window. Peers already connected keep the one they had.
+    pub fn SetAckWindow(&mut self, ack_window: AckWindow) {
+        self.ack_window = ack_window;
+    }
+
+    // Authenticates every packet with `secret`, which every client must share. Packets that fail
+    // the check never reach a peer, or make a new one.
+    pub fn SetSecretKey(&mut self, secret: &[u8]) {
+        self.connection.SetSecretKey(secret);
+    }
+
+    pub fn get_rejected_packets(&self) -> u32 {
+        self.connection.get_rejected_packets()
+    }
+
+    // Every payload is then sealed under keys agreed in the handshake clients complete before they
+    // become peers. Every client has to turn it on as well.
+    pub fn EnableEncryption(&mut self) {
+        self.encrypted = true;
+    }
+
+    // Holds every client to `limit` before its datagrams are even decoded. See Connection.
+    pub fn SetRateLimit(&mut self, limit: RateLimit) -> bool {
+        self.connection.SetRateLimit(limit)
+    }
+
+    // Ignores every client outside `list`. Call again with a fresh list to reload it.
+    pub fn SetAccessList(&mut self, list: AccessList) {
+        self.connection.SetAccessList(list);
+    }
+
+    // Only clients presenting a connect token minted with `private_key` get a peer slot, and only
+    // if the token has not expired and names this server. Turns on encryption as well.
+    pub fn SetConnectTokenKey(&mut self, private_key: &[u8]) {
+        assert_eq!(private_key.len(), CONNECT_TOKEN_KEY_SIZE, "Connect token keys are 32 bytes.");
+
+        self.token_key = Some(private_key.to_vec());
+        self.encrypted = true;
+    }
+
+    // Copies the next payload into `data` and returns its sender and size, if any. Packets from
+    // unknown senders register them as a new peer, as long as there is room for one.
+    // None means there is nothing left to read this time around.
+    pub fn ReceivePacket(&mut self, data: &mut [u8]) -> Option<(Address, usize)> {
+        let mut buffer = self.connection.buffers.acquire();
+        let received = self.receive_into(&mut buffer, data);
+
+        self.connection.buffers.release(buffer);
+        received
+    }
+
+    fn receive_into(&mut self, buffer: &mut [u8], data: &mut [u8]) -> Option<(Address, usize)> {
+        loop {
+            let received_bytes;
+            let sender;
+
+            match self.connection.ReceivePacketFrom(buffer) {
+                Some((amount, address)) => {
+                    received_bytes = amount;
+                    sender = address;
+                },
+                None => {
+                    return None;
+                }
+            }
+
+            if packet_type_of(&buffer[..received_bytes]) == Some(Packet::PacketType::ConnectionRequest) {
+                self.AcceptConnectionRequest(&sender, &buffer[..received_bytes]);
+                continue;
+            }
+
+            let peer_index;
+
+            match self.find_peer(&sender) {
+                Some(index) => {
+                    peer_index = index;
+                },
+                None => {
+                    // Only a ConnectionRequest, with a cookie to show it came from where it says,
+                    // makes someone a peer.
+                    continue;
+                }
+            }
+
+            if !self.CheckClientId(peer_index, &buffer[..received_bytes]) {
+                self.connection.Reject(&sender);
+                continue;
+            }
+
+            let decoded = {
+                let peer = &mut self.peers[peer_index];
+                let decoded = decode_reliable_packet(&mut peer.reliability_system, peer.session.as_mut(), &buffer[..received_bytes], data);
+
+                if decoded.is_some() {
+                    peer.last_heard = self.clock.now();
+                }
+                decoded
+            };
+            self.CollectDeliveries(peer_index);
+
+            match decoded {
+                Some(data_bytes) if data_bytes > 0 => {
+                    return Some((sender, data_bytes));
+                },
+                _ => {
+                    continue;
+                }
+            }
+        }
+    }
+
+    // Whether a datagram from a peer carries the client id we gave it in the handshake.
+    fn CheckClientId(&self, peer_index: usize, datagram: &[u8]) -> bool {
+        client_id_of(datagram) == Some(self.peers[peer_index].client_id)
+    }
+
+    // Registers a new peer under a fresh client id, unless we are already serving as many as we
+    // can. Peers learn their id in the handshake, so they must use it from the start.
+    fn AddPeer(&mut self, address: &Address, session: Option<Session>, token_client_id: u64, public_key: &[u8]) -> Option<usize> {
+        if self.peers.len() >= MAX_PEERS {
+            println!("Server full, ignoring {}", address);
+            return None;
+        }
+
+        println!("Server accepts from client {}", address);
+
+        self.peers.push(Peer {
+            address : address.clone(),
+            reliability_system : ReliableSystem::with_ack_window(self.ack_window, self.clock.clone()),
+            last_heard : self.clock.now(),
+            last_sent : self.clock.now(),
+            session : session,
+            public_key : [0u8; PUBLIC_KEY_SIZE],
+            client_id : new_client_id(),
+            token_client_id : token_client_id,
+        });
+        self.PushEvent(address.clone(), ConnectionEvent::Connected);
+
+        let index = self.peers.len() - 1;
+        self.peers[index].public_key.copy_from_slice(public_key);
+        Some(index)
+    }
+
+    // Takes a client on as a peer and tells it its client id; encrypted, we agree a session as well
+    // and answer with our public key. The same request again, because our answer went missing,
+    // gets the same answer; a new key from a peer we already have means it has started over, and
+    // so do we. Until the request brings back a cookie we made for the sender, all it gets is a
+    // ConnectionChallenge with one, and we keep nothing. With a connect token key, the token is
+    // checked next, still before anything is allocated for the sender.
+    fn AcceptConnectionRequest(&mut self, sender: &Address, datagram: &[u8]) {
+        if datagram.len() < MIN_REQUEST_SIZE {
+            self.connection.Reject(sender);
+            return;
+        }
+
+        let (cookie, token, public_key) = match decode_connection_request(datagram) {
+            Some(request) => request,
+            None => {
+                return;
+            }
+        };
+
+        if self.IsRefused(sender, 0) {
+            return;
+        }
+
+        let now = self.clock.now();
+
+        if !self.cookie_key.check(sender, cookie, now) {
+            self.SendChallenge(sender, datagram.len(), now);
+            return;
+        }
+
+        let private = match (token, self.token_key.is_some()) {
+            (Some(token), true) => {
+                match self.CheckConnectToken(sender, token) {
+                    Some(private) => Some(private),
+                    None => {
+                        self.connection.Reject(sender);
+                        return;
+                    }
+                }
+            },
+            (None, false) => None,
+            _ => {
+                self.connection.Reject(sender);
+                return;
+            }
+        };
+
+        let token_client_id = match private {
+            Some(ref private) => private.client_id,
+            None => 0,
+        };
+
+        if self.IsRefused(sender, token_client_id) {
+            return;
+        }
+
+        let existing = self.find_peer(sender);
+
+        let peer_index = match existing {
+            Some(index) if &self.peers[index].public_key[..] == public_key => index,
+            _ => {
+                let session = if self.encrypted {
+                    match new_server_session(public_key, private.as_ref()) {
+                        Some(session) => Some(session),
+                        None => {
+                            return;
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                match existing {
+                    Some(index) => {
+                        let peer = &mut self.peers[index];
+                        peer.session = session;
+                        peer.public_key.copy_from_slice(public_key);
+                        peer.client_id = new_client_id();
+                        peer.token_client_id = token_client_id;
+                        peer.reliability_system.reset();
+                        index
+                    },
+                    None => {
+                        match self.AddPeer(sender, session, token_client_id, public_key) {
+                            Some(index) => index,
+                            None => {
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        };
+
+        let protocol_id = self.connection.Get_Protocol_Id();
+        let peer = &mut self.peers[peer_index];
+        peer.last_heard = self.clock.now();
+
+        let client_id = peer.client_id;
+        let public_key : &[u8] = match peer.session {
+            Some(ref session) => &session.local_public_key()[..],
+            None => &[],
+        };
+        self.connection.QueuePacketTo(sender, |buffer| encode_handshake(protocol_id, client_id, Packet::PacketType::ConnectionResponse, public_key, buffer));
+    }
+
+    // The address has not shown it can receive yet, so the challenge may be no bigger than the
+    // request that asked for it.
+    fn SendChallenge(&mut self, address: &Address, request_bytes: usize, now: Duration) {
+        let protocol_id = self.connection.Get_Protocol_Id();
+        let mut cookie = [0u8; COOKIE_SIZE];
+        self.cookie_key.make(address, now, &mut cookie);
+
+        self.connection.QueueReplyTo(address, request_bytes, |buffer| encode_handshake(protocol_id, 0, Packet::PacketType::ConnectionChallenge, &cookie, buffer));
+    }
+
+    // A token is good if it opens under our key, has not expired, lists this server, and is not
+    // already in use by someone at another address.
+    fn CheckConnectToken(&mut self, sender: &Address, token: &[u8]) -> Option<PrivateConnectToken> {
+        let now = unix_time();
+
+        let private = match self.token_key {
+            Some(ref key) => {
+                match open_connect_token(key, self.connection.Get_Protocol_Id(), token, now) {
+                    Some(private) => private,
+                    None => {
+                        return None;
+                    }
+                }
+            },
+            None => {
+                return None;
+            }
+        };
+
+        let local = self.connection.local_addr();
+        if !private.server_addresses.iter().any(|address| is_address_of(address, &local)) {
+            return None;
+        }
+
+        if !self.used_tokens.claim(token, sender, now) {
+            return None;
+        }
+
+        Some(private)
+    }
+
+    // The id we gave `address` when it connected, if it is one of our peers.
+    pub fn GetClientId(&self, address: &Address) -> Option<u64> {
+        self.find_peer(address).map(|index| self.peers[index].client_id)
+    }
+
+    // The client id from the connect token `address` connected with, if it is one of our peers.
+    pub fn GetTokenClientId(&self, address: &Address) -> Option<u64> {
+        self.find_peer(address).map(|index| self.peers[index].token_client_id)
+    }
+
+    // Sends `address` a few Disconnects carrying `code` and forgets it, along with its reliability
+    // state and session. They go out with the next Flush. False if `address` is not one of our
+    // peers. Nothing stops the client connecting again; Ban for that.
+    pub fn Kick(&mut self, address: &Address, code: u8) -> bool {
+        let peer_index = match self.find_peer(address) {
+            Some(index) => index,
+            None => {
+                return false;
+            }
+        };
+
+        let protocol_id = self.connection.Get_Protocol_Id();
+
+        for _ in 0..DISCONNECT_PACKETS {
+            let peer = &mut self.peers[peer_index];
+            let client_id = peer.client_id;
+            let successful = {
+                let reliability_system = &mut peer.reliability_system;
+                let session = peer.session.as_mut();
+                self.connection.QueuePacketTo(address, |buffer| encode_sequenced_packet(protocol_id, client_id, Packet::PacketType::Disconnect, reliability_system, session, &[code], buffer))
+            };
+
+            // A fresh sequence for each, so the copies after the first are not taken for replays.
+            if successful {
+                peer.reliability_system.KeepAliveSent();
+            }
+        }
+
+        println!("Kicked client {}", address);
+
+        self.peers.remove(peer_index);
+        self.PushEvent(address.clone(), ConnectionEvent::Disconnected { reason : DisconnectReason::Kicked { code : code } });
+        true
+    }
+
+    // Refuses every attempt by `target` to connect for the next `seconds`, and kicks any of our peers
+    // it covers with BANNED_CODE. Banning a target again replaces the old ban. Returns how many
+    // peers were kicked, or None if `seconds` is not a positive number up to MAX_BAN_SECONDS, in
+    // which case nobody is banned.
+    pub fn Ban(&mut self, target: BanTarget, seconds: f64) -> Option<usize> {
+        if !(seconds > 0.0 && seconds <= MAX_BAN_SECONDS) {
+            return None;
+        }
+
+        let until = clock::deadline_after(self.clock.now(), seconds);
+        self.banned.insert(target, until);
+
+        let covered : Vec<Address> = self.peers.iter()
+            .filter(|peer| ban_covers(&target, &peer.address, peer.token_client_id))
+            .map(|peer| peer.address.clone())
+            .collect();
+
+        for address in &covered {
+            self.Kick(address, BANNED_CODE);
+        }
+        Some(covered.len())
+    }
+
+    // False if `target` was not banned.
+    pub fn Unban(&mut self, target: &BanTarget) -> bool {
+        self.banned.remove(target).is_some()
+    }
+
+    pub fn IsBanned(&self, target: &BanTarget) -> bool {
+        match self.banned.get(target) {
+            Some(until) => self.clock.now() < *until,
+            None => false,
+        }
+    }
+
+    // Whether a client at `address`, with `token_client_id` from its connect token or 0 without
+    // one, is banned. Counted as refused if it is.
+    fn IsRefused(&mut self, address: &Address, token_client_id: u64) -> bool {
+        if self.banned.is_empty() {
+            return false;
+        }
+
+        let refused = self.IsBanned(&BanTarget::Address(address.get_address())) ||
+                      (token_client_id != 0 && self.IsBanned(&BanTarget::ClientId(token_client_id)));
+
+        if refused {
+            self.refused_connections += 1;
+        }
+        refused
+    }
+
+    // Lets a mio::Poll wake us up when datagrams arrive, instead of spinning on ReceivePacket.
+    pub fn Register(&self, poll: &mio::Poll, token: mio::Token) -> io::Result<()> {
+        self.connection.Register(poll, token)
+    }
+
+    // Where clients should send to, including the port when we were bound to port 0.
+    pub fn local_addr(&self) -> Address {
+        self.connection.local_addr()
+    }
+
+    // Only kept for callers that still drive us with a frame delta; see Tick.
+    pub fn Update(&mut self, _deltaTime: f32) {
+        self.Tick();
+    }
+
+    pub fn Tick(&mut self) {
+        self.connection.Tick();
+
+        for index in 0..self.peers.len() {
+            self.peers[index].reliability_system.Tick();
+            self.CollectDeliveries(index);
+        }
+
+        let now = self.clock.now();
+        let timeout = self.timeout as f64;
+        let timed_out = |peer: &Peer| clock::seconds_between(peer.last_heard, now) > timeout;
+
+        let expired : Vec<Address> = self.peers.iter().filter(|peer| timed_out(peer)).map(|peer| peer.address.clone()).collect();
+        for address in expired {
+            println!("Client {} timed out", address);
+            self.PushEvent(address, ConnectionEvent::TimedOut);
+        }
+
+        self.peers.retain(|peer| !timed_out(peer));
+
+        let lifted : Vec<BanTarget> = self.banned.iter().filter(|&(_, until)| now >= *until).map(|(target, _)| *target).collect();
+        for target in lifted {
+            self.banned.remove(&target);
+        }
+
+        // Peers are all validated, so they may have keep-alives nobody asked for.
+        for index in 0..self.peers.len() {
+            if keep_alive_due(self.peers[index].last_sent, now, self.keep_alive_interval) {
+                self.SendKeepAlive(index);
+            }
+        }
+
+        self.connection.Flush();
+    }
+
+    // Sends everything queued by SendPacket since the last flush. Tick does this once a tick.
+    pub fn Flush(&mut self) -> usize {
+        self.connection.Flush()
+    }
+
+    pub fn GetPeerAddresses(&self) -> Vec<Address> {
+        self.peers.iter().map(|peer| peer.address.clone()).collect()
+    }
+
+    pub fn GetPeerCount(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn GetReliabilitySystem(&self, address: &Address) -> Option<&ReliableSystem> {
+        match self.find_peer(address) {
+            Some(index) => {
+                Some(&self.peers[index].reliability_system)
+            },
+            None => {
+                None
+            }
+        }
+    }
+
+    // Totals across every peer. Shards of the same server can merge theirs together.
+    pub fn GetStats(&self) -> ServerStats {
+        let mut stats = ServerStats::default();
+
+        stats.peers = self.peers.len();
+        stats.rejected_packets = self.connection.get_rejected_packets() as u64;
+        stats.denied_packets = self.connection.get_denied_packets();
+        stats.refused_connections = self.refused_connections;
+
+        if let Some(limiter) = self.connection.GetRateLimiter() {
+            stats.dropped_packets = limiter.get_dropped_packets();
+            stats.bans = limiter.get_bans();
+        }
+
+        for peer in &self.peers {
+            stats.rejected_packets += peer.session.as_ref().map_or(0, |session| session.get_rejected_packets()) as u64;
+            stats.sent_packets += peer.reliability_system.get_sent_packets() as u64;
+            stats.acked_packets += peer.reliability_system.get_acked_packets() as u64;
+            stats.lost_packets += peer.reliability_system.get_lost_packets() as u64;
+            stats.replayed_packets += peer.reliability_system.get_replayed_packets() as u64;
+            stats.sent_bandwidth += peer.reliability_system.get_sent_bandwidth();
+            stats.acked_bandwidth += peer.reliability_system.get_acked_bandwidth();
+        }
+        stats
+    }
+
+    pub fn PrintStats(&self) {
+        println!("{}\n", self.GetStats());
+    }
+
+    // Reads everything waiting on the socket and returns it as Message events, each with the
+    // client it came from, along with whatever else has happened since the last poll. Use this or
+    // ReceivePacket, not both.
+    pub fn poll_events(&mut self) -> Vec<(Address, ConnectionEvent)> {
+        let mut events = Vec::new();
+        let mut data = self.connection.buffers.acquire();
+
+        while self.connection.IsRunning() {
+            match self.ReceivePacket(&mut data) {
+                Some((sender, size)) => {
+                    events.extend(self.events.drain(..));
+                    events.push((sender, ConnectionEvent::Message { channel : DEFAULT_CHANNEL, data : data[..size].to_vec() }));
+                },
+                None => {
+                    break;
+                }
+            }
+        }
+
+        self.connection.buffers.release(data);
+        events.extend(self.events.drain(..));
+        events
+    }
+
+    fn PushEvent(&mut self, address: Address, event: ConnectionEvent) {
+        push_event(&mut self.events, (address, event));
+    }
+
+    fn CollectDeliveries(&mut self, peer_index: usize) {
+        let peer = &mut self.peers[peer_index];
+
+        for delivery in peer.reliability_system.drain_deliveries() {
+            push_event(&mut self.events, (peer.address.clone(), delivery_event(delivery)));
+        }
+    }
+
+    fn find_peer(&self, address: &Address) -> Option<usize> {
+        self.peers.iter().position(|peer| peer.address == *address)
+    }
+}
+
+
+
+
+
+
+
+///    ######                                        #####
 ///    #     #   ##    ####  #    # ###### #####    #     # #    # ###### #    # ######
 ///    #     #  #  #  #    # #   #  #        #      #     # #    # #      #    # #
 ///    ######  #    # #      ####   #####    #      #     # #    # #####  #    # #####
@@ -1187,9 +2971,10 @@ impl ReliableConnection {
 
 #[derive(Clone, Debug)]
 struct PacketData {
-    sequence: u32,
+    sequence: Sequence16,
     size: u32,
-    time: f32,
+    time: f64,  // When the packet was sent or received, in seconds on the connection's clock
+    message: Option<MessageId>,  // Set on packets we sent
 }
 
 impl fmt::Display for PacketData {
@@ -1200,676 +2985,1484 @@ impl fmt::Display for PacketData {
     }
 }
 
-impl PartialEq  for PacketData {
 
-    fn eq(&self, other: &PacketData) -> bool {
-        self.sequence == other.sequence && self.size == other.size && self.time == other.time
+//================================================================================================
+//
+//                                      Net.rs Unit Tests
+//
+//  All tests should fall into this module. Eventually this will be migrated into a common "tests/"
+//  folder with all of the other tests.
+//
+//  The goal of this is to test each object defined above and their functionality.
+//
+//  Any bugs found within this code and are not covered by an existing test will need their own.
+//
+//================================================================================================
+
+
+
+#[cfg(test)]
+mod test {
+
+    use net;
+    use rand;
+    use std::f32;
+    use std::f64;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+    use std::collections::HashMap;
+    use std::thread;
+    use clock::MockClock;
+    use sequence::{Sequence16, MAX_SEQUENCE16};
+    use sequence_buffer::SequenceBuffer;
+    use net::{ConnectionEvent, DisconnectReason, Delivery, MessageId, AckWindow};
+    use encryption::{KeyPair, Session};
+    use connect_token::{generate_connect_token, CONNECT_TOKEN_KEY_SIZE};
+    use cookie::{COOKIE_SIZE, COOKIE_LIFETIME};
+    use rate_limit::RateLimit;
+    use access_list::AccessList;
+    use packet::PacketType;
+
+    #[test]
+    fn TestSequenceMoreRecent() {
+        assert_eq!(true, net::sequence_more_recent(&4, &1, &10));
+        assert_eq!(false, net::sequence_more_recent(&1, &4, &10));
+    }
+
+    #[test]
+    fn TestReliabilitySystem_CheckBitIndexForSequence() {
+        println!("---------------Check Bit Index for Sequence---------------");
+
+        let reliability_system = net::ReliableSystem::new();
+
+
+        assert_eq!( reliability_system.bit_index_for_sequence( Sequence16(99), Sequence16(100) ), 0 );
+        assert_eq!( reliability_system.bit_index_for_sequence( Sequence16(90), Sequence16(100) ), 9 );
+        assert_eq!( reliability_system.bit_index_for_sequence( Sequence16(0), Sequence16(1) ), 0 );
+        assert_eq!( reliability_system.bit_index_for_sequence( Sequence16(0xFFFF), Sequence16(0) ), 0 );
+        assert_eq!( reliability_system.bit_index_for_sequence( Sequence16(0xFFFF), Sequence16(1) ), 1 );
+        assert_eq!( reliability_system.bit_index_for_sequence( Sequence16(0xFFFE), Sequence16(1) ), 2 );
+        assert_eq!( reliability_system.bit_index_for_sequence( Sequence16(0xFFFE), Sequence16(2) ), 3 );
+
+    }
+
+    #[test]
+    fn TestReliabilitySystem_CheckGenerateAckBit() {
+        println!("---------------Check Generate Ack Bit---------------");
+
+        let mut received = SequenceBuffer::new(128, MAX_SEQUENCE16);
+        let reliability_system = net::ReliableSystem::new();
+
+        for i in 0..32 {
+            let packed_data = net::PacketData {
+                    sequence: Sequence16(i as u16),
+                    size: 3,
+                    time: 3.14,
+                    message: None,
+            };
+
+            received.insert( i, packed_data );
+            received.verify_sequencing();
+        }
+
+        assert_eq!( received.len(), 32 );
+        assert_eq!( reliability_system.generate_ack_bits( Sequence16(32), &received )[0], 0xFFFFFFFF );
+        assert_eq!( reliability_system.generate_ack_bits( Sequence16(31), &received )[0], 0x7FFFFFFF );
+        assert_eq!( reliability_system.generate_ack_bits( Sequence16(33), &received )[0], 0xFFFFFFFE );
+        assert_eq!( reliability_system.generate_ack_bits( Sequence16(16), &received )[0], 0x0000FFFF );
+        assert_eq!( reliability_system.generate_ack_bits( Sequence16(48), &received )[0], 0xFFFF0000 );
+
     }
 
-    fn ne(&self, other: &PacketData) -> bool {
-        self.sequence != other.sequence && self.size != other.size && self.time != other.time
+    #[test]
+    fn TestReliabilitySystem_CheckGenerateAckBitWithWrap() {
+        println!("---------------Check Generate Ack Bit---------------");
+
+        let mut received = SequenceBuffer::new(128, MAX_SEQUENCE16);
+        let reliability_system = net::ReliableSystem::new();
+
+        for i in 0xFFE0..0x10000 {
+            let packed_data = net::PacketData {
+                    sequence: Sequence16(i as u16),
+                    size: 3,
+                    time: 3.14,
+                    message: None,
+            };
+
+            received.insert( i, packed_data );
+            received.verify_sequencing();
+        }
+
+        assert_eq!( received.len(), 32 );
+        assert_eq!( reliability_system.generate_ack_bits( Sequence16(0), &received )[0], 0xFFFFFFFF );
+        assert_eq!( reliability_system.generate_ack_bits( Sequence16(0xFFFF), &received )[0], 0x7FFFFFFF );
+        assert_eq!( reliability_system.generate_ack_bits( Sequence16(1), &received )[0], 0xFFFFFFFE );
+        assert_eq!( reliability_system.generate_ack_bits( Sequence16(0xFFF0), &received )[0], 0x0000FFFF );
+        assert_eq!( reliability_system.generate_ack_bits( Sequence16(16), &received )[0], 0xFFFF0000 );
+
     }
-}
 
-pub fn sequence_more_recent( s1: &u32, s2: &u32, max_sequence: &u32 ) -> bool
-{
-    ( s1 > s2 ) && ( s1 - s2 <= max_sequence/2 ) ||
-    ( s2 > s1 ) && ( s2 - s1 >  max_sequence/2 )
-}
+    #[test]
+    fn TestReliabilitySystem_RoundTripTimeFromClock() {
+        let clock = Arc::new(MockClock::new());
+        let mut reliability_system = net::ReliableSystem::with_clock(clock.clone());
 
-struct PacketQueue {
-    queue : VecDeque<PacketData>,
-}
+        reliability_system.PacketSent(100);
+        clock.advance(Duration::from_millis(100));
+        reliability_system.ProcessAck(Sequence16(0), &[0]);
 
-impl fmt::Display for PacketQueue {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self.queue)
+        assert_eq!(reliability_system.get_acked_packets(), 1);
+
+        // The first sample only moves the smoothed rtt a tenth of the way there.
+        assert!((reliability_system.get_round_trip_time() - 0.01).abs() < 0.0001);
     }
-}
 
+    #[test]
+    fn TestReliabilitySystem_PendingAcksExpireOnClock() {
+        let clock = Arc::new(MockClock::new());
+        let mut reliability_system = net::ReliableSystem::with_clock(clock.clone());
 
-impl PacketQueue {
-    pub fn new() -> PacketQueue {
-        PacketQueue {
-            queue : VecDeque::new(),
+        for _ in 0..3 {
+            reliability_system.PacketSent(100);
         }
-    }
 
-    pub fn exists(&self, sequence: u32) -> bool {
-        let mut iterator = self.queue.iter();
+        // A large delta must not age anything; only the clock does.
+        reliability_system.Update(100.0);
+        assert_eq!(reliability_system.get_lost_packets(), 0);
 
-        let mut found = false;
+        clock.advance_seconds(0.5);
+        reliability_system.Tick();
+        assert_eq!(reliability_system.get_lost_packets(), 0);
 
-        loop {
-            match iterator.next() {
-                Some(nextPacketData) => {
-                    if nextPacketData.sequence == sequence {
-                        found = true;
-                        break;
-                    }
-                },
-                None => {
-                    break;
-                },
-            }
-        }
-        found
+        clock.advance_seconds(1.0);
+        reliability_system.Tick();
+        assert_eq!(reliability_system.get_lost_packets(), 3);
     }
 
-    pub fn insert_sorted(&mut self, packet_data: PacketData, max_sequence: u32)
-    {
-        if self.queue.is_empty() {
-
-            if cfg!(test) {
-                println!("INS_EMPTY: {}", packet_data.sequence);
-            }
+    #[test]
+    fn TestReliabilitySystem_ProcessAckUsesAckBits() {
+        let mut reliability_system = net::ReliableSystem::new();
 
-            self.push_back(packet_data);
+        for _ in 0..40 {
+            reliability_system.PacketSent(100);
         }
-        else {
 
-            match self.front() {
-                Some(front) => {
-                    if !sequence_more_recent(&packet_data.sequence, &front.sequence, &max_sequence ) {
+        // 39 itself plus the 32 before it.
+        reliability_system.ProcessAck(Sequence16(39), &[0xFFFFFFFF]);
+        assert_eq!(reliability_system.get_acked_packets(), 33);
 
-                        if cfg!(test) {
-                            println!("INS_FRONT: {}, before {}", packet_data.sequence, front.sequence);
-                        }
+        // Hearing the same ack again must not count anything twice.
+        reliability_system.ProcessAck(Sequence16(39), &[0xFFFFFFFF]);
+        assert_eq!(reliability_system.get_acked_packets(), 33);
 
-                        self.push_front(packet_data.clone());
-                        return;
-                    }
-                },
-                None => {}
-            }
+        reliability_system.ProcessAck(Sequence16(6), &[0x0000003F]);
+        assert_eq!(reliability_system.get_acked_packets(), 40);
+    }
 
-            match self.back() {
-                Some(last) => {
-                    if sequence_more_recent(&packet_data.sequence, &last.sequence, &max_sequence ) {
+    #[test]
+    fn TestReliabilitySystem_ProcessAckAcrossWrap() {
+        let mut reliability_system = net::ReliableSystem::new();
 
-                        if cfg!(test) {
-                            println!("INS_BACK: {}, after {}", packet_data.sequence, last.sequence);
-                        }
+        for _ in 0..0x10004 {
+            reliability_system.PacketSent(100);
+        }
+        assert_eq!(reliability_system.get_local_sequence(), Sequence16(4));
 
-                        self.push_back(packet_data.clone());
-                        return;
-                    }
-                },
-                None => {}
-            }
+        // Only the last 1024 sequences are tracked. Everything before them was pushed out without
+        // ever being acked.
+        assert_eq!(reliability_system.get_lost_packets(), 0x10004 - 1024);
 
-            let insertion_index = self.find_sequence_insertion_point(packet_data.sequence, max_sequence);
+        // Ack 3 along with 2, 1, 0 and 0xFFFF.
+        reliability_system.ProcessAck(Sequence16(3), &[0x0000000F]);
+        assert_eq!(reliability_system.get_acked_packets(), 5);
 
-            if insertion_index != (max_sequence as usize) && self.is_index_valid(insertion_index) {
-                // Check that we are not inserting a packet which is already present
-                match self.get_packet(insertion_index) {
-                    Some(packet_at_index) => {
-                        assert!(packet_at_index.sequence != packet_data.sequence);
-                    },
-                    None => {
-                        panic!("How did we find a packet at this index? {}", insertion_index);
-                    }
-                }
+        reliability_system.Tick();
+    }
 
-                if cfg!(test) {
-                    println!("INS_SORT: {} @ index {}", packet_data.sequence, insertion_index);
-                }
-                self.queue.insert(insertion_index, packet_data.clone());
-            }
-            else {
-                if cfg!(test) {
-                    println!("Not inserted... this should panic :: {}", packet_data.sequence);
-                }
-            }
-        }
+    #[test]
+    fn TestReliabilitySystem_RejectsReplays() {
+        let mut receiver = net::ReliableSystem::new();
+
+        assert!(receiver.PacketReceived(Sequence16(0xFFFE), 100));
+        assert!(!receiver.PacketReceived(Sequence16(0xFFFE), 100));
+
+        // Out of order is fine, across the wrap too, but only the once.
+        assert!(receiver.PacketReceived(Sequence16(8), 100));
+        assert!(receiver.PacketReceived(Sequence16(0xFFFF), 100));
+        assert!(!receiver.PacketReceived(Sequence16(0xFFFF), 100));
+        assert!(!receiver.IsReplay(Sequence16(0)));
+
+        // Once the window has moved on, even a sequence we never saw is too old to trust.
+        assert!(receiver.PacketReceived(Sequence16(8).after(1100), 100));
+        assert!(receiver.IsReplay(Sequence16(0)));
+        assert!(!receiver.PacketReceived(Sequence16(8), 100));
+
+        assert_eq!(receiver.get_received_packets(), 4);
+        assert_eq!(receiver.get_replayed_packets(), 3);
+        assert_eq!(receiver.get_remote_sequence(), Sequence16(8).after(1100));
     }
 
-    pub fn verify_sequencing(&self, max_sequence: u32) {
-        let mut iterator = self.queue.iter();
+    #[test]
+    fn TestReliabilitySystem_ReplayedDatagramIsNotDelivered() {
+        let client_keys = KeyPair::generate();
+        let server_keys = KeyPair::generate();
+        let mut client_session = Session::new(&client_keys, server_keys.public_key(), true).unwrap();
+        let mut server_session = Session::new(&server_keys, client_keys.public_key(), false).unwrap();
+
+        let mut sender = net::ReliableSystem::new();
+        let mut receiver = net::ReliableSystem::new();
+        let mut datagram = [0u8; 256];
+        let mut payload = [0u8; 64];
+
+        let length = net::encode_reliable_packet(0x4C494645, 0, &mut sender, Some(&mut client_session), &[5; 8], &mut datagram).unwrap();
+        sender.PacketSent(8);
+
+        assert_eq!(net::decode_reliable_packet(&mut receiver, Some(&mut server_session), &datagram[..length], &mut payload), Some(8));
+
+        // Captured and sent again, it still opens, but goes no further.
+        assert_eq!(net::decode_reliable_packet(&mut receiver, Some(&mut server_session), &datagram[..length], &mut payload), None);
+        assert_eq!(receiver.get_received_packets(), 1);
+        assert_eq!(receiver.get_replayed_packets(), 1);
+
+        // The same without encryption.
+        let length = net::encode_reliable_packet(0x4C494645, 0, &mut sender, None, &[6; 8], &mut datagram).unwrap();
+        assert_eq!(net::decode_reliable_packet(&mut receiver, None, &datagram[..length], &mut payload), Some(8));
+        assert_eq!(net::decode_reliable_packet(&mut receiver, None, &datagram[..length], &mut payload), None);
+        assert_eq!(receiver.get_replayed_packets(), 2);
+    }
 
-        let mut previous = iterator.clone().last();
-        let mut previousPacket;
+    #[test]
+    fn TestReliabilitySystem_ReportsEachSend() {
+        let clock = Arc::new(MockClock::new());
+        let mut reliability_system = net::ReliableSystem::with_clock(clock.clone());
 
-        match previous {
-            Some(m) => {
-                previousPacket = m;
+        let ids : Vec<MessageId> = (0..4).map(|_| reliability_system.PacketSent(100)).collect();
+        assert_eq!(ids, vec![MessageId(0), MessageId(1), MessageId(2), MessageId(3)]);
+        assert!(reliability_system.get_acks().is_empty());
 
-                loop {
-                    match iterator.next() {
-                        Some(nextPacketData) => {
-                            assert!(nextPacketData.sequence <= max_sequence);
+        // Ack 3 and 1, leaving 0 and 2 to time out.
+        reliability_system.ProcessAck(Sequence16(3), &[0x00000002]);
+        assert_eq!(reliability_system.get_acks(), &[Sequence16(3), Sequence16(1)]);
 
-                            if nextPacketData != previousPacket  {
-                                assert!( sequence_more_recent(&(nextPacketData.sequence), &(previousPacket.sequence), &max_sequence) );
-                                previousPacket = nextPacketData;
-                            }
-                        },
-                        None => {
-                            break;
-                        },
-                    }
-                }
-            },
-            None => {},
-        }
+        clock.advance_seconds(1.5);
+        reliability_system.Tick();
+        assert!(reliability_system.get_acks().is_empty());
 
-    }
+        let deliveries : Vec<Delivery> = reliability_system.drain_deliveries().collect();
+        assert_eq!(deliveries, vec![Delivery::Acked(ids[3]), Delivery::Acked(ids[1]),
+                                    Delivery::Lost(ids[0]), Delivery::Lost(ids[2])]);
 
-    pub fn get_packet(&self, index: usize) -> option::Option<&PacketData> {
-        self.queue.get(index)
+        // Ids keep counting across a reset, so an old report can never be mistaken for a new send.
+        reliability_system.reset();
+        assert_eq!(reliability_system.PacketSent(100), MessageId(4));
+        assert_eq!(reliability_system.drain_deliveries().count(), 0);
     }
 
-    fn push_back(&mut self, data: PacketData) {
-        self.queue.push_back(data);
+    #[test]
+    fn TestReliabilitySystem_GenerateWideAckBitsAcrossWrap() {
+        // 128 sequences running up to the wrap and just past it: 0xFFC0 to 0xFFFF, then 0 to 63.
+        let mut received = SequenceBuffer::new(256, MAX_SEQUENCE16);
+        for i in (0xFFC0..0x10000).chain(0..64) {
+            received.insert(i, net::PacketData { sequence : Sequence16(i as u16), size : 3, time : 3.14, message : None });
+        }
+
+        let wide = net::ReliableSystem::with_ack_window(AckWindow::Bits128, Arc::new(MockClock::new()));
+        assert_eq!(wide.generate_ack_bits(Sequence16(64), &received), [0xFFFFFFFF, 0xFFFFFFFF, 0xFFFFFFFF, 0xFFFFFFFF]);
+        assert_eq!(wide.generate_ack_bits(Sequence16(63), &received), [0xFFFFFFFF, 0xFFFFFFFF, 0xFFFFFFFF, 0x7FFFFFFF]);
+        assert_eq!(wide.generate_ack_bits(Sequence16(0), &received), [0xFFFFFFFF, 0xFFFFFFFF, 0x00000000, 0x00000000]);
+        assert_eq!(wide.bit_index_for_sequence(Sequence16(0xFFC0), Sequence16(64)), 127);
+
+        let middle = net::ReliableSystem::with_ack_window(AckWindow::Bits64, Arc::new(MockClock::new()));
+        assert_eq!(middle.generate_ack_bits(Sequence16(64), &received), [0xFFFFFFFF, 0xFFFFFFFF, 0, 0]);
+        assert_eq!(middle.generate_ack_bits(Sequence16(0xFFF4), &received), [0xFFFFFFFF, 0x000FFFFF, 0, 0]);
     }
 
-    pub fn front(&self) -> Option<PacketData> {
-        match self.queue.front() {
-            Some(pd) => {
-                Some(pd.clone())
-            },
-            None => {
-                None
+    // Sends `count` packets straight from one reliability system to another, with the receiver
+    // only acking every `every` packets. Returns how many the sender saw acked.
+    fn ack_every(ack_window: AckWindow, every: u32, count: u32) -> u32 {
+        let clock = Arc::new(MockClock::new());
+        let mut sender = net::ReliableSystem::with_ack_window(ack_window, clock.clone());
+        let mut receiver = net::ReliableSystem::with_ack_window(ack_window, clock.clone());
+
+        for i in 0..count {
+            let sequence = sender.get_local_sequence();
+            sender.PacketSent(100);
+            receiver.PacketReceived(sequence, 100);
+
+            if (i + 1) % every == 0 {
+                let ack_bits = receiver.GenerateAckBits();
+                sender.ProcessAck(receiver.get_remote_sequence(), &ack_bits[..ack_window.words()]);
             }
         }
+        sender.get_acked_packets()
     }
 
-    pub fn back(&self) -> Option<PacketData> {
-        match self.queue.back() {
-            Some(pd) => {
-                Some(pd.clone())
-            },
-            None => {
-                None
-            }
+    #[test]
+    fn TestReliabilitySystem_WindowCoversGapsBetweenAcks() {
+        // Each run wraps the sequence space at least once. As long as an ack comes at least once
+        // every window plus one packets, every packet is acked.
+        const COUNT : u32 = 0x10000 + 1024;
+
+        for ack_window in [AckWindow::Bits32, AckWindow::Bits64, AckWindow::Bits128].iter() {
+            assert_eq!(ack_every(*ack_window, ack_window.bits(), COUNT), COUNT);
+            let count = (ack_window.bits() + 1) * (0x10000 / ack_window.bits());
+            assert_eq!(ack_every(*ack_window, ack_window.bits() + 1, count), count);
         }
-    }
 
-    fn push_front(&mut self, data: PacketData) {
-        self.queue.push_front(data);
+        // Any longer and the packets in between go unacked: 33 of every 64 are acked here.
+        assert_eq!(ack_every(AckWindow::Bits32, 64, COUNT), (COUNT / 64) * 33);
+        assert_eq!(ack_every(AckWindow::Bits64, 128, COUNT), (COUNT / 128) * 65);
     }
 
+    #[test]
+    fn TestReliableConnection_WideAckWindow() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
 
-    pub fn is_index_valid(&self, index: usize) -> bool {
-        const MIN_QUEUE_SIZE: usize = 0x0;
-        const MAX_QUEUE_SIZE: usize = 0xFFFF;
+        server.SetAckWindow(AckWindow::Bits128);
+        client.SetAckWindow(AckWindow::Bits128);
 
-        index > MIN_QUEUE_SIZE && index < MAX_QUEUE_SIZE
-    }
+        assert!(server.Start() && client.Start());
+        server.Listen();
+        client.SetDestination(server.local_addr());
+        client.Connect();
+        handshake(&mut server, &mut client);
 
-    pub fn find_sequence_insertion_point(&self, sequence_num: u32, max_sequence: u32) -> usize {
-        let mut insertion_index = 0xFF;
-        {
-            let mut iterator = self.queue.iter().enumerate();
+        // 100 packets before the first reply; a 32 bit window would only ack the last 33.
+        let sender = exchange(&mut server, &mut client, 100);
 
-            loop {
-                match iterator.next() {
-                    Some((index, nextPacketData)) => {
-                        if cfg!(test) {
-                            println!("INS_IDX:nextPacketData.sequence={}, sequence_num={}", nextPacketData.sequence, sequence_num);
-                        }
+        assert_eq!(client.GetReliabilitySystem().get_acked_packets(), 100);
+        assert_eq!(server.GetReliabilitySystem(&sender).unwrap().get_ack_window(), AckWindow::Bits128);
+    }
 
-                        if sequence_more_recent(&nextPacketData.sequence, &sequence_num, &max_sequence) {
-                            insertion_index = index;
-                            break;
-                        }
-                    },
-                    None => {
-                        if cfg!(test) {
-                            println!("End of list. Insertion Index: {}", insertion_index);
-                        }
-                        break;
-                    },
-                }
-            }
+    #[test]
+    fn TestReliableServer_ReceivesAFlushedBatch() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+
+        client.SetDestination(server.local_addr());
+        assert!(server.Start() && client.Start());
+        server.Listen();
+        client.Connect();
+        handshake(&mut server, &mut client);
+
+        for i in 0..50 {
+            assert!(client.SendPacket(&[i as u8; 10]).is_some());
         }
-        insertion_index
-    }
 
-    pub fn find_index_for_sequence(&self, sequence_num: u32) -> usize {
-        let mut packet_index = 0xFF;
-        {
-            let mut iterator = self.queue.iter().enumerate();
+        // Nothing goes out until the flush, and then it all goes at once.
+        let mut payload = [0u8; 64];
+        assert!(server.ReceivePacket(&mut payload).is_none());
+        assert_eq!(client.Flush(), 50);
 
-            loop {
-                match iterator.next() {
-                    Some((index, nextPacketData)) => {
-                        if cfg!(test) {
-                            println!("GET_IDX:nextPacketData.sequence={}, sequence_num={}", nextPacketData.sequence, sequence_num);
-                        }
+        let mut received = 0;
+        for _ in 0..100 {
+            while let Some((_, size)) = server.ReceivePacket(&mut payload) {
+                assert_eq!(&payload[..size], &[received as u8; 10]);
+                received += 1;
+            }
 
-                        if nextPacketData.sequence == sequence_num {
-                            packet_index = index;
-                            break;
-                        }
-                    },
-                    None => {
-                        println!("End of list. Sequence Number Not Found: {}", sequence_num);
-                        break;
-                    },
-                }
+            if received == 50 {
+                break;
             }
+            thread::sleep(Duration::from_millis(1));
         }
-        packet_index
-    }
-
-    pub fn clear(&mut self) {
-        self.queue.clear();
-    }
 
-    pub fn size(&self) -> usize {
-        self.queue.len()
+        assert_eq!(received, 50);
+        assert_eq!(server.GetPeerCount(), 1);
     }
 
-}
-
-//================================================================================================
-//
-//                                      Net.rs Unit Tests
-//
-//  All tests should fall into this module. Eventually this will be migrated into a common "tests/"
-//  folder with all of the other tests.
-//
-//  The goal of this is to test each object defined above and their functionality.
-//
-//  Any bugs found within this code and are not covered by an existing test will need their own.
-//
-//================================================================================================
+    #[cfg(unix)]
+    #[test]
+    fn TestReliableServer_ShardsShareAPort() {
+        // The second shard joins whichever port the first one was given.
+        let mut first = net::ReliableServer::with_reuse_port(0x4C494645, 10.0, "0.0.0.0:0".parse().unwrap(), Arc::new(MockClock::new()));
+        let port = first.local_addr().get_port();
+        let mut second = net::ReliableServer::with_reuse_port(0x4C494645, 10.0, format!("0.0.0.0:{}", port).parse().unwrap(), Arc::new(MockClock::new()));
+
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+
+        assert!(first.Start() && second.Start() && client.Start());
+        first.Listen();
+        second.Listen();
+
+        client.SetDestination(net::Address::new(Ipv4Addr::new(127, 0, 0, 1), port));
+        client.Connect();
+
+        // Whichever shard the client's datagrams land on answers the handshake.
+        let mut payload = [0u8; 16];
+        for _ in 0..100 {
+            client.Flush();
+            assert!(first.ReceivePacket(&mut payload).is_none() && second.ReceivePacket(&mut payload).is_none());
+            first.Flush();
+            second.Flush();
+            client.ReceivePacket(&mut payload);
+
+            if client.IsConnected() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(client.IsConnected());
 
+        for _ in 0..10 {
+            assert!(client.SendPacket(&[1, 2, 3]).is_some());
+        }
+        client.Flush();
 
+        // Every packet from the one client lands on the same shard.
+        let mut received = (0, 0);
+        for _ in 0..100 {
+            while first.ReceivePacket(&mut payload).is_some() {
+                received.0 += 1;
+            }
+            while second.ReceivePacket(&mut payload).is_some() {
+                received.1 += 1;
+            }
 
-#[cfg(test)]
-mod test {
+            if received.0 + received.1 == 10 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
 
-    use net;
-    use rand;
+        assert!(received == (10, 0) || received == (0, 10));
+        assert_eq!(first.GetPeerCount() + second.GetPeerCount(), 1);
+    }
 
     #[test]
-    fn TestSequenceMoreRecent() {
-        assert_eq!(true, net::sequence_more_recent(&4, &1, &10));
-        assert_eq!(false, net::sequence_more_recent(&1, &4, &10));
+    fn TestAddress_UnmapsIpv4() {
+        let mapped = net::Address::from_socket_addr("[::ffff:127.0.0.1]:9000".parse().unwrap());
+        assert_eq!(mapped, net::Address::new(Ipv4Addr::new(127, 0, 0, 1), 9000));
+        assert_eq!(mapped.get_socket_addr(), "127.0.0.1:9000".parse::<SocketAddr>().unwrap());
+
+        // Loopback is not a mapped address and has to stay IPv6.
+        let loopback = net::Address::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 9000);
+        assert!(loopback.get_socket_addr().is_ipv6());
+        assert!(!loopback.is_empty());
+        assert!(net::Address::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 0).is_empty());
     }
 
     #[test]
-    fn TestPacketQueueExists() {
-        let mut packet_queue = net::PacketQueue::new();
-        let mut packet_data = net::PacketData {
-                sequence: 100,
-                size: 100,
-                time: 4.17,
-        };
+    fn TestAddress_ComparesIpAndPort() {
+        let first = net::Address::new(Ipv4Addr::new(127, 0, 0, 1), 9000);
+        let second = net::Address::new(Ipv4Addr::new(127, 0, 0, 1), 9001);
 
-        packet_queue.push_back(packet_data.clone());
-        packet_data.sequence += 1;
+        assert!(first != second);
+        assert!(first < second);
+        assert!(net::Address::new(Ipv4Addr::new(10, 0, 0, 1), 9001) < first);
 
-        packet_queue.push_back(packet_data.clone());
-        packet_data.sequence += 1;
+        let mut seen = HashMap::new();
+        seen.insert(first.clone(), 1);
+        seen.insert(second.clone(), 2);
+        seen.insert(first.clone(), 3);
 
-        packet_queue.push_back(packet_data.clone());
-        packet_data.sequence += 1;
-
-        assert_eq!(packet_queue.exists(100), true);
-        assert_eq!(packet_queue.exists(102), true);
-        assert_eq!(packet_queue.exists(99), false);
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[&first], 3);
+    }
 
+    #[test]
+    fn TestAddress_DisplayAndParse() {
+        let v4 : net::Address = "192.168.1.20:8890".parse().unwrap();
+        let v6 : net::Address = "[::1]:8890".parse().unwrap();
+
+        assert_eq!(v4, net::Address::new(Ipv4Addr::new(192, 168, 1, 20), 8890));
+        assert_eq!(v6, net::Address::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 8890));
+        assert_eq!(format!("{}", v4), "192.168.1.20:8890");
+        assert_eq!(format!("{}", v6), "[::1]:8890");
+        assert_eq!(format!("{}", v6).parse::<net::Address>().unwrap(), v6);
+
+        assert!("localhost:8890".parse::<net::Address>().is_err());
+        assert!("127.0.0.1".parse::<net::Address>().is_err());
+        assert!("::1:8890".parse::<net::Address>().is_err());
     }
 
     #[test]
-    fn TestInsertQueueAtHead() {
-        let mut packet_queue = net::PacketQueue::new();
-        let mut packet_data = net::PacketData {
-                sequence: 100,
-                size: 100,
-                time: 4.17,
-        };
-        const MAXIMUM_SEQUENCE : u32 = 0xFFFFFFFF;
+    fn TestAddress_ResolvesLocalhost() {
+        let addresses = net::Address::resolve("localhost:8890").unwrap();
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 1;
+        assert!(!addresses.is_empty());
+        assert!(addresses.iter().all(|address| address.get_address().is_loopback() && address.get_port() == 8890));
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 1;
+        assert_eq!(net::Address::resolve("[::1]:8890").unwrap(), vec![net::Address::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), 8890)]);
+        assert!(net::Address::resolve("localhost").is_err());
+    }
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 1;
+    // Sends `count` packets from the client, waits for the server to take them all, and has the
+    // server answer each one. Returns the address the server saw them coming from. A client that
+    // has not connected yet goes through the handshake first.
+    fn exchange(server: &mut net::ReliableServer, client: &mut net::ReliableConnection, count: usize) -> net::Address {
+        if !client.IsConnected() {
+            handshake(server, client);
+        }
 
-        assert_eq!(packet_queue.exists(100), true);
-        assert_eq!(packet_queue.exists(101), true);
-        assert_eq!(packet_queue.exists(102), true);
-        assert_eq!(packet_queue.exists(99), false);
+        for i in 0..count {
+            assert!(client.SendPacket(&[i as u8; 8]).is_some());
+        }
+        client.Flush();
+
+        let mut payload = [0u8; 64];
+        let mut senders = Vec::new();
+        for _ in 0..100 {
+            while let Some((sender, size)) = server.ReceivePacket(&mut payload) {
+                assert_eq!(&payload[..size], &[senders.len() as u8; 8]);
+                senders.push(sender);
+            }
 
-        println!("{:?}", packet_queue.queue);
+            if senders.len() == count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(senders.len(), count);
 
-        packet_data.sequence = 99;
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
+        for sender in &senders {
+            assert!(server.SendPacket(sender, &[7; 4]).is_some());
+        }
+        server.Flush();
 
-        assert_eq!(packet_queue.exists(99), true);
-        let index = packet_queue.find_index_for_sequence(99);
+        let mut replies = 0;
+        for _ in 0..100 {
+            while client.ReceivePacket(&mut payload) == 4 {
+                assert_eq!(&payload[..4], &[7; 4]);
+                replies += 1;
+            }
 
-        println!("{:?}", packet_queue.queue);
+            if replies == count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(replies, count);
 
-        assert_eq!(index, 0);
+        senders[0].clone()
     }
 
     #[test]
-    fn TestInsertQueueAtTail() {
-        let mut packet_queue = net::PacketQueue::new();
-        let mut packet_data = net::PacketData {
-                sequence: 100,
-                size: 100,
-                time: 4.17,
-        };
-        const MAXIMUM_SEQUENCE : u32 = 0xFFFFFFFF;
+    fn TestReliableServer_OverIpv6Loopback() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "[::1]:0".parse().unwrap(), Arc::new(MockClock::new()));
+        let mut client = net::ReliableConnection::bind(0x4C494645, 10.0, "[::1]:0".parse().unwrap(), Arc::new(MockClock::new()));
+
+        assert!(server.Start() && client.Start());
+        server.Listen();
+        client.SetDestination(server.local_addr());
+        client.Connect();
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 1;
+        let sender = exchange(&mut server, &mut client, 10);
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 1;
+        assert_eq!(sender, net::Address::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1), client.local_addr().get_port()));
+        assert_eq!(server.GetPeerCount(), 1);
+        assert_eq!(server.GetReliabilitySystem(&sender).unwrap().get_remote_sequence(), Sequence16(9));
+    }
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 1;
+    #[test]
+    fn TestReliableServer_DualStackTakesIpv4Clients() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "[::]:0".parse().unwrap(), Arc::new(MockClock::new()));
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
 
-        assert_eq!(packet_queue.exists(100), true);
-        assert_eq!(packet_queue.exists(101), true);
-        assert_eq!(packet_queue.exists(102), true);
-        assert_eq!(packet_queue.exists(103), false);
+        assert!(server.Start() && client.Start());
+        server.Listen();
+        client.SetDestination(net::Address::new(Ipv4Addr::new(127, 0, 0, 1), server.local_addr().get_port()));
+        client.Connect();
 
-        packet_data.sequence = 103;
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
+        // The client arrives v4-mapped but is known to the server by its plain IPv4 address.
+        let sender = exchange(&mut server, &mut client, 10);
 
-        assert_eq!(packet_queue.exists(103), true);
-        let index = packet_queue.find_index_for_sequence(103);
-        assert_eq!(index, 3);
+        assert_eq!(sender, net::Address::new(Ipv4Addr::new(127, 0, 0, 1), client.local_addr().get_port()));
+        assert_eq!(server.GetPeerAddresses().len(), 1);
     }
 
     #[test]
-    fn TestInsertQueueAtMiddle() {
-        let mut packet_queue = net::PacketQueue::new();
-        let mut packet_data = net::PacketData {
-                sequence: 100,
-                size: 100,
-                time: 4.17,
-        };
-        const MAXIMUM_SEQUENCE : u32 = 0xFFFFFFFF;
+    fn TestReliableConnection_EphemeralPorts() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        let mut first = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+        let mut second = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+
+        let server_address = server.local_addr();
+        assert_eq!(server_address.get_address(), Ipv4Addr::new(127, 0, 0, 1));
+        assert!(server_address.get_port() != 0);
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 1;
+        // Two clients on one machine no longer fight over a port.
+        assert!(first.local_addr().get_port() != 0);
+        assert!(first.local_addr().get_port() != second.local_addr().get_port());
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 2;
+        assert!(server.Start() && first.Start() && second.Start());
+        server.Listen();
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
+        for client in [&mut first, &mut second].iter_mut() {
+            client.SetDestination(server_address.clone());
+            client.Connect();
+        }
 
-        assert_eq!(packet_queue.exists(100), true);
-        assert_eq!(packet_queue.exists(101), true);
-        assert_eq!(packet_queue.exists(103), true);
-        assert_eq!(packet_queue.exists(102), false);
+        exchange(&mut server, &mut first, 5);
+        exchange(&mut server, &mut second, 5);
 
-        packet_data.sequence = 102;
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
+        let mut peers = server.GetPeerAddresses();
+        peers.sort();
+        let mut clients = vec![net::Address::new(Ipv4Addr::new(127, 0, 0, 1), first.local_addr().get_port()),
+                               net::Address::new(Ipv4Addr::new(127, 0, 0, 1), second.local_addr().get_port())];
+        clients.sort();
 
-        assert_eq!(packet_queue.exists(102), true);
-        let index = packet_queue.find_index_for_sequence(102);
-        assert_eq!(index, 2);
+        assert_eq!(peers, clients);
     }
 
     #[test]
-    fn TestSequenceNotPresent() {
-        let mut packet_queue = net::PacketQueue::new();
-        let mut packet_data = net::PacketData {
-                sequence: 100,
-                size: 100,
-                time: 4.17,
-        };
-        const MAXIMUM_SEQUENCE : u32 = 0xFFFFFFFF;
+    fn TestReliableServer_RejectsPacketsWithoutTheKey() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+        let mut intruder = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+        let mut impostor = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+
+        server.SetSecretKey(b"deployment secret");
+        client.SetSecretKey(b"deployment secret");
+        impostor.SetSecretKey(b"a guess at the secret");
+
+        assert!(server.Start() && client.Start() && intruder.Start() && impostor.Start());
+        server.Listen();
+
+        for connection in [&mut client, &mut intruder, &mut impostor].iter_mut() {
+            connection.SetDestination(server.local_addr());
+            connection.Connect();
+        }
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 1;
+        exchange(&mut server, &mut client, 5);
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 2;
+        // One sends no MAC at all and the other a MAC made with the wrong key. Neither request gets
+        // past the check, so the server never hears of them.
+        intruder.Flush();
+        impostor.Flush();
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
+        let mut payload = [0u8; 64];
+        for _ in 0..100 {
+            assert!(server.ReceivePacket(&mut payload).is_none());
+            if server.get_rejected_packets() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
 
-        assert_eq!(packet_queue.exists(100), true);
-        assert_eq!(packet_queue.exists(101), true);
-        assert_eq!(packet_queue.exists(103), true);
-        assert_eq!(packet_queue.exists(102), false);
+        assert_eq!(server.get_rejected_packets(), 2);
+        assert_eq!(server.GetStats().rejected_packets, 2);
+        assert_eq!(server.GetPeerAddresses().len(), 1);
+        assert_eq!(client.get_rejected_packets(), 0);
+    }
 
-        packet_data.sequence = 108;
+    // Runs the handshake until the client is connected, or gives up after a while.
+    fn handshake(server: &mut net::ReliableServer, client: &mut net::ReliableConnection) {
+        let mut payload = [0u8; 64];
+        for _ in 0..100 {
+            client.Flush();
+            assert!(server.ReceivePacket(&mut payload).is_none());
+            server.Flush();
+            assert_eq!(client.ReceivePacket(&mut payload), 0);
 
-        assert_eq!(packet_queue.exists(108), false);
-        let index = packet_queue.find_index_for_sequence(108);
-        assert_eq!(index, 0xFF);
+            if client.IsConnected() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(client.IsConnected());
     }
 
     #[test]
-    fn TestVerifySequencingPass() {
-        let mut packet_queue = net::PacketQueue::new();
-        let mut packet_data = net::PacketData {
-                sequence: 100,
-                size: 100,
-                time: 4.17,
-        };
-        const MAXIMUM_SEQUENCE : u32 = 0xFFFFFFFF;
+    fn TestReliableServer_EncryptedExchange() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+        let mut stranger = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+
+        server.EnableEncryption();
+        client.EnableEncryption();
+
+        assert!(server.Start() && client.Start() && stranger.Start());
+        server.Listen();
+
+        for connection in [&mut client, &mut stranger].iter_mut() {
+            connection.SetDestination(server.local_addr());
+            connection.Connect();
+        }
+
+        // Nothing can be sent until the server has answered.
+        assert!(!client.IsConnected());
+        assert!(client.SendPacket(&[1; 8]).is_none());
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 1;
+        handshake(&mut server, &mut client);
+        exchange(&mut server, &mut client, 5);
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
-        packet_data.sequence += 2;
+        // Without a handshake of its own, the stranger can't send, nor does it become a peer.
+        assert!(stranger.SendPacket(&[2; 8]).is_none());
+        stranger.Flush();
+        thread::sleep(Duration::from_millis(10));
 
-        packet_queue.insert_sorted(packet_data.clone(), MAXIMUM_SEQUENCE);
+        let mut payload = [0u8; 64];
+        assert!(server.ReceivePacket(&mut payload).is_none());
+        assert_eq!(server.GetPeerCount(), 1);
 
-        assert_eq!(packet_queue.exists(100), true);
-        assert_eq!(packet_queue.exists(101), true);
-        assert_eq!(packet_queue.exists(103), true);
-        assert_eq!(packet_queue.exists(102), false);
+        // Connecting again makes a new session; the server starts the peer over along with it.
+        client.Connect();
+        assert!(!client.IsConnected());
+        handshake(&mut server, &mut client);
+        exchange(&mut server, &mut client, 3);
 
-        packet_queue.verify_sequencing(0xFFFFFFFF ); // assertions within will fail if not sorted
+        assert_eq!(server.GetPeerCount(), 1);
+        assert_eq!(server.GetStats().rejected_packets, 0);
+        assert_eq!(client.get_rejected_packets(), 0);
+    }
+
+    // Sends an 8-byte payload to the server from a bare socket, carrying whatever client id we say.
+    fn send_with_client_id(socket: &UdpSocket, server: &net::ReliableServer, client_id: u64, sender: &mut net::ReliableSystem) {
+        let mut datagram = [0u8; 256];
+        let length = net::encode_reliable_packet(0x4C494645, client_id, sender, None, &[9; 8], &mut datagram).unwrap();
+        sender.PacketSent(8);
+
+        socket.send_to(&datagram[..length], server.local_addr().get_socket_addr()).unwrap();
+        thread::sleep(Duration::from_millis(10));
     }
 
     #[test]
-    #[should_panic]
-    fn TestVerifySequencingFail() {
-        let mut packet_queue = net::PacketQueue::new();
-        let mut packet_data = net::PacketData {
-                sequence: 100,
-                size: 100,
-                time: 4.17,
-        };
-        const MAXIMUM_SEQUENCE : u32 = 0xFFFFFFFF;
+    fn TestReliableServer_AssignsClientIds() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+        assert!(server.Start() && client.Start());
+        server.Listen();
 
-        packet_queue.push_front(packet_data.clone());
-        packet_data.sequence -= 1;
+        client.SetDestination(server.local_addr());
+        client.Connect();
+        assert_eq!(client.get_client_id(), 0);
 
-        packet_queue.push_front(packet_data.clone());
-        packet_data.sequence += MAXIMUM_SEQUENCE ;
+        let address = exchange(&mut server, &mut client, 3);
+        let client_id = server.GetClientId(&address).unwrap();
+        assert!(client_id != 0);
+        assert_eq!(client.get_client_id(), client_id);
 
-        packet_queue.push_front(packet_data.clone());
+        // A peer has to use the id we gave it, never 0 or anybody else's.
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket_address = net::Address::from_socket_addr(socket.local_addr().unwrap());
+        let mut sender = net::ReliableSystem::new();
+        let mut payload = [0u8; 64];
 
-        assert_eq!(packet_queue.exists(100), true);
-        assert_eq!(packet_queue.exists(99), true);
-        assert_eq!(packet_queue.exists(101), true);
-        assert_eq!(packet_queue.exists(102), false);
+        let assigned = connect_socket(&socket, &mut server);
+        assert!(assigned != 0 && assigned != client_id);
+        assert_eq!(server.GetClientId(&socket_address), Some(assigned));
 
-        println!("{:?}", packet_queue.queue);
+        send_with_client_id(&socket, &server, 0, &mut sender);
+        assert!(server.ReceivePacket(&mut payload).is_none());
 
+        send_with_client_id(&socket, &server, client_id, &mut sender);
+        assert!(server.ReceivePacket(&mut payload).is_none());
 
-        packet_queue.verify_sequencing(MAXIMUM_SEQUENCE); // assertions within will fail if not sorted
+        send_with_client_id(&socket, &server, assigned, &mut sender);
+        assert!(server.ReceivePacket(&mut payload).is_some());
+
+        assert_eq!(server.GetStats().rejected_packets, 2);
     }
 
-    #[test]
-    fn TestPacketQueueStressTest_InsertBack() {
-    	const MAXIMUM_SEQUENCE : u32 = 0xFFFF;
+    // Sends a ConnectionRequest from a bare socket and returns whatever the server answers with.
+    fn request_connection(socket: &UdpSocket, server: &mut net::ReliableServer, cookie: &[u8], public_key: &[u8]) -> Option<Vec<u8>> {
+        let mut datagram = [0u8; 256];
+        let length = net::encode_connection_request(0x4C494645, cookie, None, public_key, &mut datagram).unwrap();
+        socket.send_to(&datagram[..length], server.local_addr().get_socket_addr()).unwrap();
+        thread::sleep(Duration::from_millis(10));
 
-    	let mut packet_queue = net::PacketQueue::new();
+        let mut payload = [0u8; 64];
+        assert!(server.ReceivePacket(&mut payload).is_none());
+        server.Flush();
 
-    	// check insert back
-    	println!("---------------\nInsert Back\n---------------");
-    	for i in  0..100 {
-    		let mut packed_data = net::PacketData {
-                    sequence: i,
-                    size: 3,
-                    time: 3.14
-            };
+        match socket.recv_from(&mut datagram) {
+            Ok((length, _)) => Some(datagram[..length].to_vec()),
+            Err(_) => None,
+        }
+    }
+
+    // Connects a bare socket to an unencrypted server, cookie and all, and returns the client id
+    // the server gave it.
+    fn connect_socket(socket: &UdpSocket, server: &mut net::ReliableServer) -> u64 {
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let key_pair = KeyPair::generate();
+
+        let challenge = request_connection(socket, server, &[0; COOKIE_SIZE], key_pair.public_key()).unwrap();
+        let cookie = net::decode_challenge(&challenge).unwrap().to_vec();
 
-    		packet_queue.insert_sorted( packed_data.clone(), MAXIMUM_SEQUENCE );
-    		packet_queue.verify_sequencing( MAXIMUM_SEQUENCE );
-    	}
+        let response = request_connection(socket, server, &cookie, key_pair.public_key()).unwrap();
+        assert_eq!(net::packet_type_of(&response), Some(PacketType::ConnectionResponse));
+        net::client_id_of(&response).unwrap()
     }
 
     #[test]
-    fn TestPacketQueueStressTest_InsertFront() {
-        const MAXIMUM_SEQUENCE : u32 = 0xFFFF;
+    fn TestReliableServer_HandshakeNeedsACookie() {
+        let clock = Arc::new(MockClock::new());
+        let mut server = net::ReliableServer::bind(0x4C494645, 100.0, "127.0.0.1:0".parse().unwrap(), clock.clone());
+        server.EnableEncryption();
+        assert!(server.Start());
+        server.Listen();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let key_pair = KeyPair::generate();
+
+        // Without a cookie we only get one, and the server keeps nothing.
+        let challenge = request_connection(&socket, &mut server, &[0; COOKIE_SIZE], key_pair.public_key()).unwrap();
+        assert_eq!(net::packet_type_of(&challenge), Some(PacketType::ConnectionChallenge));
+        let cookie = net::decode_challenge(&challenge).unwrap().to_vec();
+        assert_eq!(server.GetPeerCount(), 0);
+
+        // Sent back from another address, it is no good.
+        let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+        other.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let answer = request_connection(&other, &mut server, &cookie, key_pair.public_key()).unwrap();
+        assert_eq!(net::packet_type_of(&answer), Some(PacketType::ConnectionChallenge));
+        assert_eq!(server.GetPeerCount(), 0);
+
+        // Nor once it has gone stale.
+        clock.advance_seconds(COOKIE_LIFETIME as f32 + 1.0);
+        let answer = request_connection(&socket, &mut server, &cookie, key_pair.public_key()).unwrap();
+        assert_eq!(net::packet_type_of(&answer), Some(PacketType::ConnectionChallenge));
+        assert_eq!(server.GetPeerCount(), 0);
+
+        // A fresh one, from where it was sent, gets us a peer.
+        let cookie = net::decode_challenge(&answer).unwrap().to_vec();
+        let answer = request_connection(&socket, &mut server, &cookie, key_pair.public_key()).unwrap();
+        assert_eq!(net::packet_type_of(&answer), Some(PacketType::ConnectionResponse));
+        assert_eq!(server.GetPeerCount(), 1);
+    }
 
-    	let mut packet_queue = net::PacketQueue::new();
+    #[test]
+    fn TestReliableServer_UnencryptedNeedsAHandshake() {
+        let clock = Arc::new(MockClock::new());
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), clock.clone());
+        assert!(server.Start());
+        server.Listen();
+
+        // A payload from an address that has not been through the handshake is dropped, and the
+        // address gets nothing back, keep-alives included.
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut sender = net::ReliableSystem::new();
+        let mut payload = [0u8; 64];
+
+        send_with_client_id(&socket, &server, 0, &mut sender);
+        assert!(server.ReceivePacket(&mut payload).is_none());
+        assert_eq!(server.GetPeerCount(), 0);
+
+        for _ in 0..10 {
+            clock.advance_seconds(0.5);
+            server.Tick();
+        }
+        assert!(socket.recv_from(&mut payload).is_err());
 
-    	// check insert front
-    	println!("---------------\nInsert Front\n---------------");
-    	packet_queue.clear();
-    	for i in 0..100 {
-            let mut packed_data = net::PacketData {
-                    sequence: (100-i),
-                    size: 3,
-                    time: 3.14
-            };
+        // With the cookie round trip done, the same socket is a peer.
+        let client_id = connect_socket(&socket, &mut server);
+        assert_eq!(server.GetPeerCount(), 1);
 
-    		packet_queue.insert_sorted( packed_data.clone(), MAXIMUM_SEQUENCE );
-    		packet_queue.verify_sequencing( MAXIMUM_SEQUENCE );
-    	}
+        send_with_client_id(&socket, &server, client_id, &mut sender);
+        assert!(server.ReceivePacket(&mut payload).is_some());
     }
 
     #[test]
-    fn TestPacketQueueStressTest_InsertRandom() {
-        const MAXIMUM_SEQUENCE : u32 = 0xFFFF;
-
-    	let mut packet_queue = net::PacketQueue::new();
-    	// check insert random
-    	println!("\n---------------\nInsert Random\n---------------");
-    	packet_queue.clear();
-    	for i in 0..100  {
-                let mut packed_data = net::PacketData {
-                        sequence: rand::random::<u32>() % MAXIMUM_SEQUENCE,
-                        size: 3,
-                        time: 3.14
-                };
-
-                // We are not discriminating against duplicates. This is to test
-                // that we can insert with consecutively random sequences.
-    		    packet_queue.insert_sorted( packed_data.clone(), MAXIMUM_SEQUENCE );
-    		    packet_queue.verify_sequencing( MAXIMUM_SEQUENCE );
-    	}
+    fn TestReliableConnection_ListenerWaitsForItsPeerToShowUp() {
+        let clock = Arc::new(MockClock::new());
+        let mut listener = net::ReliableConnection::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), clock.clone());
+        assert!(listener.Start());
+        listener.Listen();
+
+        // Whoever asks first gets an answer, even from a spoofed address, but only one no bigger
+        // than the request.
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut datagram = [0u8; 256];
+        let length = net::encode_connection_request(0x4C494645, &[0; COOKIE_SIZE], None, KeyPair::generate().public_key(), &mut datagram).unwrap();
+        socket.send_to(&datagram[..length], listener.local_addr().get_socket_addr()).unwrap();
+        thread::sleep(Duration::from_millis(10));
+
+        let mut payload = [0u8; 64];
+        assert_eq!(listener.ReceivePacket(&mut payload), 0);
+        listener.Flush();
+
+        let (received, _) = socket.recv_from(&mut datagram).unwrap();
+        assert_eq!(net::packet_type_of(&datagram[..received]), Some(PacketType::ConnectionResponse));
+        let client_id = net::client_id_of(&datagram[..received]).unwrap();
+        assert!(client_id != 0);
+
+        // Until the address shows it got the answer, it gets no keep-alives, and the rest of
+        // what it sent does not pay for a payload.
+        assert!(listener.SendPacket(&[1; 8]).is_none());
+        for _ in 0..10 {
+            clock.advance_seconds(0.5);
+            listener.Tick();
+        }
+        assert!(socket.recv_from(&mut datagram).is_err());
+
+        // A packet carrying the id it was given is that proof.
+        let mut sender = net::ReliableSystem::new();
+        let length = net::encode_reliable_packet(0x4C494645, client_id, &mut sender, None, &[9; 8], &mut datagram).unwrap();
+        socket.send_to(&datagram[..length], listener.local_addr().get_socket_addr()).unwrap();
+        thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(listener.ReceivePacket(&mut payload), 8);
+        assert!(listener.SendPacket(&[1; 8]).is_some());
+        listener.Flush();
+        assert!(socket.recv_from(&mut datagram).is_ok());
     }
 
     #[test]
-    fn TestPacketQueueStressTest_Wrap() {
-        const MAXIMUM_SEQUENCE : u32 = 0xFFFF;
+    fn TestReliableServer_ReplaysDoNotHoldOffTheTimeout() {
+        let clock = Arc::new(MockClock::new());
+        let mut server = net::ReliableServer::bind(0x4C494645, 1.0, "127.0.0.1:0".parse().unwrap(), clock.clone());
+        assert!(server.Start());
+        server.Listen();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_id = connect_socket(&socket, &mut server);
+        let mut sender = net::ReliableSystem::new();
+        let mut datagram = [0u8; 256];
+        let length = net::encode_reliable_packet(0x4C494645, client_id, &mut sender, None, &[9; 8], &mut datagram).unwrap();
+        let mut payload = [0u8; 64];
+
+        socket.send_to(&datagram[..length], server.local_addr().get_socket_addr()).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert!(server.ReceivePacket(&mut payload).is_some());
+
+        // Only the same packet over and over again, which is no sign the peer is still there.
+        for _ in 0..3 {
+            clock.advance_seconds(0.4);
+            socket.send_to(&datagram[..length], server.local_addr().get_socket_addr()).unwrap();
+            thread::sleep(Duration::from_millis(10));
+            assert!(server.ReceivePacket(&mut payload).is_none());
+            server.Tick();
+        }
 
-    	let mut packet_queue = net::PacketQueue::new();
+        assert_eq!(server.GetPeerCount(), 0);
+    }
 
-        // check wrap around
-    	println!("---------------\nWrap Around\n---------------");
+    #[test]
+    fn TestReliableServer_NeverAnswersWithMoreThanItGot() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 100.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        server.EnableEncryption();
+        assert!(server.Start());
+        server.Listen();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let key_pair = KeyPair::generate();
+
+        // Requests come padded, and the challenge is smaller than the request.
+        let mut request = [0u8; 512];
+        let length = net::encode_connection_request(0x4C494645, &[0; COOKIE_SIZE], None, key_pair.public_key(), &mut request).unwrap();
+        assert_eq!(length, net::MIN_REQUEST_SIZE);
+
+        let challenge = request_connection(&socket, &mut server, &[0; COOKIE_SIZE], key_pair.public_key()).unwrap();
+        assert!(challenge.len() <= length);
+
+        // One that was not padded is not answered at all.
+        let mut payload = [0u8; 64];
+        socket.send_to(&request[..100], server.local_addr().get_socket_addr()).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert!(server.ReceivePacket(&mut payload).is_none());
+        assert_eq!(server.Flush(), 0);
+        assert_eq!(server.GetStats().rejected_packets, 1);
+
+        // Nor is anything bigger than the request it answers.
+        let address = net::Address::from_socket_addr(socket.local_addr().unwrap());
+        let connection = &mut server.connection;
+        assert!(!connection.QueueReplyTo(&address, net::MIN_REQUEST_SIZE, |_| Some(net::MIN_REQUEST_SIZE + 1)));
+        assert!(connection.QueueReplyTo(&address, net::MIN_REQUEST_SIZE, |_| Some(net::MIN_REQUEST_SIZE)));
+        assert!(!connection.QueueReplyTo(&address, net::MIN_REQUEST_SIZE - 1, |_| Some(10)));
+        assert_eq!(connection.Flush(), 1);
+    }
 
-    	for i in 200..255 {
-            let mut packed_data = net::PacketData {
-                    sequence: i,
-                    size: 3,
-                    time: 3.14
-            };
+    #[test]
+    fn TestReliableServer_RateLimitsAndBans() {
+        let clock = Arc::new(MockClock::new());
+        let mut server = net::ReliableServer::bind(0x4C494645, 100.0, "127.0.0.1:0".parse().unwrap(), clock.clone());
+        assert!(!server.SetRateLimit(RateLimit { packets_per_second : 0.0, burst : 4.0, max_malformed : 3, ban_seconds : 5.0 }));
+        assert!(server.SetRateLimit(RateLimit { packets_per_second : 10.0, burst : 4.0, max_malformed : 3, ban_seconds : 5.0 }));
+        assert!(server.Start());
+        server.Listen();
+
+        let flooder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sender = net::ReliableSystem::new();
+        let mut payload = [0u8; 64];
+
+        // The handshake takes two from the bucket, which a second fills again.
+        let client_id = connect_socket(&flooder, &mut server);
+        clock.advance_seconds(1.0);
+
+        // The first four make it through; the fifth is over the limit and earns a ban.
+        for _ in 0..5 {
+            send_with_client_id(&flooder, &server, client_id, &mut sender);
+        }
+        let mut received = 0;
+        while server.ReceivePacket(&mut payload).is_some() {
+            received += 1;
+        }
+        assert_eq!(received, 4);
 
+        // Banned, nothing gets through even at a polite pace, until the ban runs out.
+        clock.advance_seconds(1.0);
+        send_with_client_id(&flooder, &server, client_id, &mut sender);
+        assert!(server.ReceivePacket(&mut payload).is_none());
 
-    		packet_queue.insert_sorted( packed_data.clone(), MAXIMUM_SEQUENCE );
-    		packet_queue.verify_sequencing( MAXIMUM_SEQUENCE );
-    	}
+        clock.advance_seconds(5.0);
+        send_with_client_id(&flooder, &server, client_id, &mut sender);
+        assert!(server.ReceivePacket(&mut payload).is_some());
 
-    	for i in 0..50 {
-            let mut packed_data = net::PacketData {
-                    sequence: i,
-                    size: 3,
-                    time: 3.14
-            };
+        // Garbage gets a source banned too.
+        let garbage = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for _ in 0..3 {
+            garbage.send_to(&[0xAB; 40], server.local_addr().get_socket_addr()).unwrap();
+        }
+        thread::sleep(Duration::from_millis(10));
+        assert!(server.ReceivePacket(&mut payload).is_none());
 
-    		packet_queue.insert_sorted( packed_data.clone(), MAXIMUM_SEQUENCE );
-    		packet_queue.verify_sequencing( MAXIMUM_SEQUENCE );
-    	}
+        let stats = server.GetStats();
+        assert_eq!(stats.bans, 2);
+        assert_eq!(stats.dropped_packets, 2);
     }
 
     #[test]
-    fn TestReliabilitySystem_CheckBitIndexForSequence() {
-        println!("---------------Check Bit Index for Sequence---------------");
-
-        const MAXIMUM_SEQUENCE : u32 = 0xFF;
+    fn TestReliableServer_AccessList() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 100.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        server.SetAccessList(AccessList::parse("allow 127.0.0.0/8\ndeny 127.0.0.2").unwrap());
+        assert!(server.Start());
+        server.Listen();
+
+        let inside = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let outside = UdpSocket::bind("127.0.0.2:0").unwrap();
+        let mut sender = net::ReliableSystem::new();
+        let mut payload = [0u8; 64];
+
+        send_with_client_id(&outside, &server, 0, &mut sender);
+        assert!(server.ReceivePacket(&mut payload).is_none());
+        assert_eq!(server.GetStats().peers, 0);
+
+        let inside_id = connect_socket(&inside, &mut server);
+        send_with_client_id(&inside, &server, inside_id, &mut sender);
+        assert!(server.ReceivePacket(&mut payload).is_some());
+
+        // A fresh list replaces the old one from the next datagram on.
+        server.SetAccessList(AccessList::parse("deny 127.0.0.1/32").unwrap());
+        send_with_client_id(&inside, &server, inside_id, &mut sender);
+        assert!(server.ReceivePacket(&mut payload).is_none());
+
+        let outside_id = connect_socket(&outside, &mut server);
+        send_with_client_id(&outside, &server, outside_id, &mut sender);
+        assert!(server.ReceivePacket(&mut payload).is_some());
+
+        assert_eq!(server.GetStats().denied_packets, 2);
+    }
 
-        let reliability_system = net::ReliableSystem::new(MAXIMUM_SEQUENCE);
+    #[test]
+    fn TestReliableServer_ConnectTokens() {
+        let private_key = [7u8; CONNECT_TOKEN_KEY_SIZE];
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        server.SetConnectTokenKey(&private_key);
+        assert!(server.Start());
+        server.Listen();
+
+        let elsewhere = net::Address::new(Ipv4Addr::new(127, 0, 0, 1), server.local_addr().get_port().wrapping_add(1));
+
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+        assert!(client.SetConnectToken(generate_connect_token(&private_key, 0x4C494645, 42, &[server.local_addr()], 30).unwrap()));
+        assert!(client.Start());
+        client.Connect();
+
+        handshake(&mut server, &mut client);
+        let address = exchange(&mut server, &mut client, 3);
+        assert_eq!(server.GetTokenClientId(&address), Some(42));
+
+        // A token with nowhere to go is turned down.
+        let mut nowhere = generate_connect_token(&private_key, 0x4C494645, 45, &[server.local_addr()], 30).unwrap();
+        nowhere.server_addresses.clear();
+        assert!(!net::ReliableConnection::new(0x4C494645, 10.0, 0).SetConnectToken(nowhere));
+
+        // No token, a token we did not mint, and a token for another server: none of them gets a peer.
+        let mut stranger = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+        stranger.EnableEncryption();
+
+        let mut forger = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+        assert!(forger.SetConnectToken(generate_connect_token(&[8u8; CONNECT_TOKEN_KEY_SIZE], 0x4C494645, 43, &[server.local_addr()], 30).unwrap()));
+
+        let mut misdirected = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+        assert!(misdirected.SetConnectToken(generate_connect_token(&private_key, 0x4C494645, 44, &[elsewhere], 30).unwrap()));
+
+        for connection in [&mut stranger, &mut forger, &mut misdirected].iter_mut() {
+            connection.SetDestination(server.local_addr());
+            assert!(connection.Start());
+            connection.Connect();
+        }
 
+        // Each one gets a cookie and tries again with it, only to be turned away.
+        let mut payload = [0u8; 64];
+        for _ in 0..3 {
+            for connection in [&mut stranger, &mut forger, &mut misdirected].iter_mut() {
+                connection.Flush();
+            }
+            thread::sleep(Duration::from_millis(10));
 
-        assert_eq!( reliability_system.bit_index_for_sequence( 99, 100, MAXIMUM_SEQUENCE ), 0 );
-        assert_eq!( reliability_system.bit_index_for_sequence( 90, 100, MAXIMUM_SEQUENCE ), 9 );
-        assert_eq!( reliability_system.bit_index_for_sequence( 0, 1, MAXIMUM_SEQUENCE ), 0 );
-        assert_eq!( reliability_system.bit_index_for_sequence( 255, 0, MAXIMUM_SEQUENCE ), 0 );
-        assert_eq!( reliability_system.bit_index_for_sequence( 255, 1, MAXIMUM_SEQUENCE ), 1 );
-        assert_eq!( reliability_system.bit_index_for_sequence( 254, 1, MAXIMUM_SEQUENCE ), 2 );
-        assert_eq!( reliability_system.bit_index_for_sequence( 254, 2, MAXIMUM_SEQUENCE ), 3 );
+            assert!(server.ReceivePacket(&mut payload).is_none());
+            server.Flush();
+            thread::sleep(Duration::from_millis(10));
 
+            for connection in [&mut stranger, &mut forger, &mut misdirected].iter_mut() {
+                assert_eq!(connection.ReceivePacket(&mut payload), 0);
+            }
+        }
+        assert_eq!(server.GetPeerCount(), 1);
+        assert_eq!(server.GetStats().rejected_packets, 3);
+        assert!(!misdirected.IsConnected());
     }
 
-    #[test]
-    fn TestReliabilitySystem_CheckGenerateAckBit() {
-        println!("---------------Check Generate Ack Bit---------------");
+    // Polls until `count` events have turned up, or gives up after a while.
+    fn poll_client(client: &mut net::ReliableConnection, count: usize) -> Vec<ConnectionEvent> {
+        let mut events = Vec::new();
+        for _ in 0..100 {
+            events.extend(client.poll_events());
+            if events.len() >= count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        events
+    }
 
-        const MAXIMUM_SEQUENCE : u32 = 0xFF;
+    fn poll_server(server: &mut net::ReliableServer, count: usize) -> Vec<(net::Address, ConnectionEvent)> {
+        let mut events = Vec::new();
+        for _ in 0..100 {
+            events.extend(server.poll_events());
+            if events.len() >= count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        events
+    }
 
-        let mut packet_queue = net::PacketQueue::new();
-        let reliability_system = net::ReliableSystem::new(MAXIMUM_SEQUENCE);
+    #[test]
+    fn TestReliableConnection_PollEvents() {
+        let server_clock = Arc::new(MockClock::new());
+        let client_clock = Arc::new(MockClock::new());
+
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), server_clock.clone());
+        let mut client = net::ReliableConnection::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), client_clock.clone());
+        let client_address = client.local_addr();
+
+        assert!(server.Start() && client.Start());
+        server.Listen();
+        client.SetDestination(server.local_addr());
+        client.Connect();
+        handshake(&mut server, &mut client);
+
+        let sent : Vec<MessageId> = (0..3).map(|i| client.SendPacket(&[i; 4]).unwrap()).collect();
+        client.Flush();
+
+        let message = |i: u8| ConnectionEvent::Message { channel : net::DEFAULT_CHANNEL, data : vec![i; 4] };
+
+        assert_eq!(poll_server(&mut server, 4), vec![(client_address.clone(), ConnectionEvent::Connected),
+                                                     (client_address.clone(), message(0)),
+                                                     (client_address.clone(), message(1)),
+                                                     (client_address.clone(), message(2))]);
+
+        // The reply carries acks for all three.
+        let reply = server.SendPacket(&client_address, &[9; 4]).unwrap();
+        server.Flush();
+
+        assert_eq!(poll_client(&mut client, 5), vec![ConnectionEvent::Connected,
+                                                     ConnectionEvent::MessageAcked { id : sent[2] },
+                                                     ConnectionEvent::MessageAcked { id : sent[1] },
+                                                     ConnectionEvent::MessageAcked { id : sent[0] },
+                                                     message(9)]);
+
+        // The client never answers the reply, and then goes quiet altogether.
+        server_clock.advance_seconds(11.0);
+        server.Tick();
+        assert_eq!(server.poll_events(), vec![(client_address.clone(), ConnectionEvent::MessageLost { id : reply }),
+                                              (client_address.clone(), ConnectionEvent::TimedOut)]);
+        assert_eq!(server.GetPeerCount(), 0);
+
+        // Nobody is polling the server any more, so this is never acked.
+        let unanswered = client.SendPacket(&[3; 4]).unwrap();
+        client_clock.advance_seconds(1.5);
+        client.Tick();
+        assert_eq!(client.poll_events(), vec![ConnectionEvent::MessageLost { id : unanswered }]);
+
+        client_clock.advance_seconds(11.0);
+        client.Tick();
+        assert_eq!(client.poll_events(), vec![ConnectionEvent::TimedOut]);
+    }
 
-        for i in 0..32 {
-            let mut packed_data = net::PacketData {
-                    sequence: i,
-                    size: 3,
-                    time: 3.14
-            };
+    #[test]
+    fn TestReliableServer_KeepAliveAcksWhatItReceives() {
+        let server_clock = Arc::new(MockClock::new());
+
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), server_clock.clone());
+        let mut client = net::ReliableConnection::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+
+        assert!(server.Start() && client.Start());
+        server.Listen();
+        client.SetDestination(server.local_addr());
+        client.Connect();
+        handshake(&mut server, &mut client);
+        assert_eq!(client.poll_events(), vec![ConnectionEvent::Connected]);
+
+        // Empty payloads are what keep-alives look like, so the application cannot send one.
+        assert_eq!(client.SendPacket(&[]), None);
+
+        let sent : Vec<MessageId> = (0..3).map(|i| client.SendPacket(&[i; 4]).unwrap()).collect();
+        client.Flush();
+        assert_eq!(poll_server(&mut server, 4).len(), 4);
+
+        // The server only ever receives. Before the interval is up it stays quiet...
+        server.Tick();
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(client.poll_events(), vec![]);
+
+        // ...and then acks everything with a keep-alive, which is not a message in its own right.
+        server_clock.advance_seconds(net::DEFAULT_KEEP_ALIVE_INTERVAL);
+        server.Tick();
+
+        assert_eq!(poll_client(&mut client, 3), vec![ConnectionEvent::MessageAcked { id : sent[2] },
+                                                     ConnectionEvent::MessageAcked { id : sent[1] },
+                                                     ConnectionEvent::MessageAcked { id : sent[0] }]);
+    }
 
-            packet_queue.insert_sorted( packed_data.clone(), MAXIMUM_SEQUENCE );
-            packet_queue.verify_sequencing( MAXIMUM_SEQUENCE );
+    // Ticks both ends for `seconds` of mock time with nobody sending anything, and returns every
+    // event either of them saw.
+    fn idle(server: &mut net::ReliableServer, server_clock: &MockClock, client: &mut net::ReliableConnection, client_clock: &MockClock, seconds: u32)
+        -> (Vec<(net::Address, ConnectionEvent)>, Vec<ConnectionEvent>) {
+        let mut events = (Vec::new(), Vec::new());
+
+        for _ in 0..seconds * 10 {
+            server_clock.advance_seconds(0.1);
+            client_clock.advance_seconds(0.1);
+            server.Tick();
+            client.Tick();
+            thread::sleep(Duration::from_millis(1));
+
+            events.0.extend(server.poll_events());
+            events.1.extend(client.poll_events());
         }
+        events
+    }
 
-        assert_eq!( packet_queue.size(), 32 );
-        assert_eq!( reliability_system.generate_ack_bits( 32, &packet_queue, MAXIMUM_SEQUENCE ), 0xFFFFFFFF );
-        assert_eq!( reliability_system.generate_ack_bits( 31, &packet_queue, MAXIMUM_SEQUENCE ), 0x7FFFFFFF );
-        assert_eq!( reliability_system.generate_ack_bits( 33, &packet_queue, MAXIMUM_SEQUENCE ), 0xFFFFFFFE );
-        assert_eq!( reliability_system.generate_ack_bits( 16, &packet_queue, MAXIMUM_SEQUENCE ), 0x0000FFFF );
-        assert_eq!( reliability_system.generate_ack_bits( 48, &packet_queue, MAXIMUM_SEQUENCE ), 0xFFFF0000 );
+    #[test]
+    fn TestReliableConnection_KeepAliveHoldsIdleConnection() {
+        let server_clock = Arc::new(MockClock::new());
+        let client_clock = Arc::new(MockClock::new());
+
+        let mut server = net::ReliableServer::bind(0x4C494645, 1.0, "127.0.0.1:0".parse().unwrap(), server_clock.clone());
+        let mut client = net::ReliableConnection::bind(0x4C494645, 1.0, "127.0.0.1:0".parse().unwrap(), client_clock.clone());
+
+        assert!(server.Start() && client.Start());
+        server.Listen();
+        client.SetDestination(server.local_addr());
+        client.Connect();
+        client.SetKeepAliveInterval(0.25);
+        server.SetKeepAliveInterval(0.25);
+
+        // Connecting needs no application traffic either.
+        let (server_events, client_events) = idle(&mut server, &server_clock, &mut client, &client_clock, 5);
+        assert_eq!(server_events, vec![(client.local_addr(), ConnectionEvent::Connected)]);
+        assert_eq!(client_events, vec![ConnectionEvent::Connected]);
+
+        // With keep-alives turned off on both ends, the connection dies of silence.
+        client.SetKeepAliveInterval(0.0);
+        server.SetKeepAliveInterval(0.0);
+
+        let (server_events, client_events) = idle(&mut server, &server_clock, &mut client, &client_clock, 2);
+        assert_eq!(server_events, vec![(client.local_addr(), ConnectionEvent::TimedOut)]);
+        assert_eq!(client_events, vec![ConnectionEvent::TimedOut]);
+    }
 
+    #[test]
+    fn TestReliableConnection_KeepAliveIntervalThatIsNotANumberTurnsThemOff() {
+        let server_clock = Arc::new(MockClock::new());
+        let client_clock = Arc::new(MockClock::new());
+
+        let mut server = net::ReliableServer::bind(0x4C494645, 1.0, "127.0.0.1:0".parse().unwrap(), server_clock.clone());
+        let mut client = net::ReliableConnection::bind(0x4C494645, 1.0, "127.0.0.1:0".parse().unwrap(), client_clock.clone());
+
+        assert!(server.Start() && client.Start());
+        server.Listen();
+        client.SetDestination(server.local_addr());
+        client.Connect();
+        client.SetKeepAliveInterval(0.25);
+        server.SetKeepAliveInterval(0.25);
+        idle(&mut server, &server_clock, &mut client, &client_clock, 5);
+
+        // Neither ticks its way into a panic; both just stop sending keep-alives.
+        client.SetKeepAliveInterval(f32::NAN);
+        server.SetKeepAliveInterval(f32::INFINITY);
+
+        let (server_events, client_events) = idle(&mut server, &server_clock, &mut client, &client_clock, 2);
+        assert_eq!(server_events, vec![(client.local_addr(), ConnectionEvent::TimedOut)]);
+        assert_eq!(client_events, vec![ConnectionEvent::TimedOut]);
     }
 
     #[test]
-    fn TestReliabilitySystem_CheckGenerateAckBitWithWrap() {
-        println!("---------------Check Generate Ack Bit---------------");
+    fn TestReliableServer_StopDisconnectsPeers() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
 
-        const MAXIMUM_SEQUENCE : u32 = 0xFF;
+        assert!(server.Start() && client.Start());
+        server.Listen();
+        client.SetDestination(server.local_addr());
+        client.Connect();
+        exchange(&mut server, &mut client, 1);
 
-        let mut packet_queue = net::PacketQueue::new();
-        let reliability_system = net::ReliableSystem::new(MAXIMUM_SEQUENCE);
+        let client_address = server.GetPeerAddresses()[0].clone();
+        server.poll_events();
+        server.Stop();
 
-        for i in 224..256 {
-            let mut packed_data = net::PacketData {
-                    sequence: i,
-                    size: 3,
-                    time: 3.14
-            };
+        assert_eq!(server.poll_events(), vec![(client_address, ConnectionEvent::Disconnected { reason : DisconnectReason::Stopped })]);
+    }
+
+    #[test]
+    fn TestReliableServer_Kick() {
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+        server.EnableEncryption();
+        client.EnableEncryption();
+
+        assert!(server.Start() && client.Start());
+        server.Listen();
+        client.SetDestination(server.local_addr());
+        client.Connect();
+        handshake(&mut server, &mut client);
+
+        let address = exchange(&mut server, &mut client, 1);
+        server.poll_events();
+        client.poll_events();
+
+        assert!(!server.Kick(&net::Address::new(Ipv4Addr::new(127, 0, 0, 1), 1), 7));
+        assert!(server.Kick(&address, 7));
+        assert_eq!(server.GetPeerCount(), 0);
+        assert!(server.GetReliabilitySystem(&address).is_none());
+        assert_eq!(server.poll_events(), vec![(address, ConnectionEvent::Disconnected { reason : DisconnectReason::Kicked { code : 7 } })]);
+
+        // Only the first of the copies counts; the rest find the client already gone.
+        server.Flush();
+        let kicked = ConnectionEvent::Disconnected { reason : DisconnectReason::Kicked { code : 7 } };
+        let events = poll_client(&mut client, 1);
+        assert_eq!(events.iter().filter(|event| **event == kicked).count(), 1);
+        assert!(!client.IsConnected());
+        assert_eq!(client.get_client_id(), 0);
+    }
+
+    #[test]
+    fn TestReliableServer_BanAddress() {
+        let clock = Arc::new(MockClock::new());
+        let mut server = net::ReliableServer::bind(0x4C494645, 100.0, "127.0.0.1:0".parse().unwrap(), clock.clone());
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+
+        assert!(server.Start() && client.Start());
+        server.Listen();
+        client.SetDestination(server.local_addr());
+        client.Connect();
+
+        let address = exchange(&mut server, &mut client, 1);
+        let target = net::BanTarget::Address(address.get_address());
+
+        // Spans that are no number of seconds, or more than anyone could mean, ban nobody.
+        for &seconds in &[0.0, -1.0, f64::NAN, f64::INFINITY, 1e30] {
+            assert_eq!(server.Ban(target, seconds), None);
+        }
+        assert!(!server.IsBanned(&target));
+        assert_eq!(server.GetPeerCount(), 1);
+
+        assert_eq!(server.Ban(target, 30.0), Some(1));
+        assert!(server.IsBanned(&target));
+        assert_eq!(server.GetPeerCount(), 0);
+
+        server.Flush();
+        let kicked = ConnectionEvent::Disconnected { reason : DisconnectReason::Kicked { code : net::BANNED_CODE } };
+        assert!(poll_client(&mut client, 1).contains(&kicked));
+
+        // Coming back from another port gets nowhere either, not even a challenge.
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let key_pair = KeyPair::generate();
+
+        assert!(request_connection(&socket, &mut server, &[0; COOKIE_SIZE], key_pair.public_key()).is_none());
+        assert_eq!(server.GetPeerCount(), 0);
+        assert_eq!(server.GetStats().refused_connections, 1);
+
+        // Until the ban runs out.
+        clock.advance_seconds(31.0);
+        server.Tick();
+        assert!(!server.IsBanned(&target));
+        assert!(!server.Unban(&target));
+
+        connect_socket(&socket, &mut server);
+        assert_eq!(server.GetPeerCount(), 1);
+    }
 
-            packet_queue.insert_sorted( packed_data.clone(), MAXIMUM_SEQUENCE );
-            packet_queue.verify_sequencing( MAXIMUM_SEQUENCE );
+    #[test]
+    fn TestReliableServer_BanTokenClientId() {
+        let private_key = [7u8; CONNECT_TOKEN_KEY_SIZE];
+        let mut server = net::ReliableServer::bind(0x4C494645, 10.0, "127.0.0.1:0".parse().unwrap(), Arc::new(MockClock::new()));
+        server.SetConnectTokenKey(&private_key);
+        assert!(server.Start());
+        server.Listen();
+
+        // Nobody connected with the id yet, so there is nobody to kick.
+        assert_eq!(server.Ban(net::BanTarget::ClientId(42), 30.0), Some(0));
+
+        let mut client = net::ReliableConnection::new(0x4C494645, 10.0, 0);
+        assert!(client.SetConnectToken(generate_connect_token(&private_key, 0x4C494645, 42, &[server.local_addr()], 30).unwrap()));
+        assert!(client.Start());
+        client.Connect();
+
+        // The client gets a cookie, as anyone would, and is turned away once its token is read.
+        let mut payload = [0u8; 64];
+        for _ in 0..3 {
+            client.Flush();
+            thread::sleep(Duration::from_millis(10));
+
+            assert!(server.ReceivePacket(&mut payload).is_none());
+            server.Flush();
+            thread::sleep(Duration::from_millis(10));
+
+            assert_eq!(client.ReceivePacket(&mut payload), 0);
         }
+        assert!(!client.IsConnected());
+        assert_eq!(server.GetPeerCount(), 0);
+        assert_eq!(server.GetStats().refused_connections, 1);
 
-        assert_eq!( packet_queue.size(), 32 );
-        assert_eq!( reliability_system.generate_ack_bits( 0, &packet_queue, MAXIMUM_SEQUENCE ), 0xFFFFFFFF );
-        assert_eq!( reliability_system.generate_ack_bits( 255, &packet_queue, MAXIMUM_SEQUENCE ), 0x7FFFFFFF );
-        assert_eq!( reliability_system.generate_ack_bits( 1, &packet_queue, MAXIMUM_SEQUENCE ), 0xFFFFFFFE );
-        assert_eq!( reliability_system.generate_ack_bits( 240, &packet_queue, MAXIMUM_SEQUENCE ), 0x0000FFFF );
-        assert_eq!( reliability_system.generate_ack_bits( 16, &packet_queue, MAXIMUM_SEQUENCE ), 0xFFFF0000 );
+        assert!(server.Unban(&net::BanTarget::ClientId(42)));
+        assert!(!server.IsBanned(&net::BanTarget::ClientId(42)));
+    }
+
+    #[test]
+    fn TestServerStats_Merge() {
+        let mut total = net::ServerStats::default();
+        let shard = net::ServerStats {
+            peers : 2,
+            sent_packets : 100,
+            acked_packets : 90,
+            lost_packets : 10,
+            rejected_packets : 3,
+            replayed_packets : 4,
+            dropped_packets : 5,
+            bans : 1,
+            denied_packets : 7,
+            refused_connections : 3,
+            sent_bandwidth : 1.5,
+            acked_bandwidth : 1.0,
+        };
 
+        total.merge(&shard);
+        total.merge(&shard);
+
+        assert_eq!(total.peers, 4);
+        assert_eq!(total.sent_packets, 200);
+        assert_eq!(total.lost_packets, 20);
+        assert_eq!(total.rejected_packets, 6);
+        assert_eq!(total.replayed_packets, 8);
+        assert_eq!(total.dropped_packets, 10);
+        assert_eq!(total.bans, 2);
+        assert_eq!(total.denied_packets, 14);
+        assert_eq!(total.refused_connections, 6);
+        assert_eq!(total.acked_bandwidth, 2.0);
+        assert_eq!(format!("{}", total), "peers 4, sent 200, acked 180, lost 20 (10), rejected 6, replayed 8, dropped 10, bans 2, denied 14, refused 6, sent bandwidth = 3kbps, acked bandwidth = 2kbps");
     }
 }
@@ -139,8 +139,7 @@ impl NetworkBufferManager {
             return Result::Err(NetworkBufferManagerProbe::Full)
         }
         else {
-            let ack_indx = packet.get_sequence_num() % 32;
-            let ack_num = ack_indx as usize;
+            let ack_num = packet.get_sequence_num().index(MAX_PACKET_BUFFER_SIZE);
 
             let rx = self.rx_acks[ack_num];
             let tx = self.tx_packets[ack_num];
@@ -257,6 +256,7 @@ pub fn get_network_buffer_manager() -> &'static UDP_BUFFER {
 #[cfg(test)]
 mod test {
     use packet::Packet;
+    use sequence::Sequence16;
     use netbuffers::{NetworkBufferManager, MAX_PACKET_BUFFER_SIZE, NetworkBufferManagerProbe};
     use utils::*;
 
@@ -275,12 +275,12 @@ mod test {
     fn test_network_buffer_insertion() {
         let mut udp_buffer: NetworkBufferManager = NetworkBufferManager::new();
         let mut temp_packet: Packet = Packet::new();
-        let user_name = String::from("network buffer tester");
-        let seq_num :u32 = 1000;
-        let bfr_index :usize = (seq_num as usize) % MAX_PACKET_BUFFER_SIZE;
+        let client_id = 0x0102030405060708;
+        let seq_num = Sequence16(1000);
+        let bfr_index :usize = seq_num.index(MAX_PACKET_BUFFER_SIZE);
 
         temp_packet.set_sequence_number(seq_num);
-        temp_packet.set_client_id(user_name.clone());
+        temp_packet.set_client_id(client_id);
         temp_packet.set_ackbit(3); // assume we have already received ack for pkt 3
 
         match udp_buffer.insert(temp_packet) {
@@ -296,7 +296,7 @@ mod test {
                     let ack_bits = inserted_packet.get_ackbits();
 
                     assert_eq!(is_bit_set(ack_bits, 8), false); // We did not get the ack for this yet
-                    assert_eq!(inserted_packet.get_sequence_num(), 1000 as u32);
+                    assert_eq!(inserted_packet.get_sequence_num(), Sequence16(1000));
                     assert_eq!(is_bit_set(ack_bits, 3), true);
                     assert_eq!(is_bit_set(ack_bits, 0), false);
                 }
@@ -312,18 +312,18 @@ mod test {
     #[test]
     fn test_network_buffer_fill() {
         let mut udp_buffer: NetworkBufferManager = NetworkBufferManager::new();
-        let mut seq_num: u32 = 1000;
+        let mut seq_num = Sequence16(1000);
 
         for x in 0..33 {
             let mut temp_packet: Packet = Packet::new();
-            let user_name = String::from("network buffer tester");
+            let client_id = 0x0102030405060708;
 
             temp_packet.set_sequence_number(seq_num);
             //temp_packet.set_ack(ack_bit as u32);
             //temp_packet.set_ackbit(ack_bit);
-            temp_packet.set_client_id(user_name.clone());
+            temp_packet.set_client_id(client_id);
 
-            let index: usize = (seq_num as usize) % 32;
+            let index: usize = seq_num.index(32);
 
             match udp_buffer.insert(temp_packet) {
 
@@ -336,7 +336,7 @@ mod test {
                         //let ack_bits = inserted_packet.get_ackbits();
 
                         //assert_eq!(is_bit_set(ack_bits, ack_bit), true);
-                        assert_eq!(inserted_packet.get_sequence_num(), seq_num as u32);
+                        assert_eq!(inserted_packet.get_sequence_num(), seq_num);
                     }
                     else if n == NetworkBufferManagerProbe::Exists {
                         println!("Packet already present in buffer.");
@@ -350,11 +350,11 @@ mod test {
                     let last_inserted_packet : &Packet = &udp_buffer.sent_packet_buffer[index-1];
 
                     assert_eq!(x, 32);
-                    assert_eq!(last_inserted_packet.get_sequence_num(), 1031);
+                    assert_eq!(last_inserted_packet.get_sequence_num(), Sequence16(1031));
                     println!("Network Buffer is full! This should never occur.");
                 },
             }
-            seq_num += 1;
+            seq_num = seq_num.next();
         }
     }
 
@@ -378,9 +378,9 @@ mod test {
         let mut udp_buffer: NetworkBufferManager = NetworkBufferManager::new();
 
         for x in 0..2 {
-            let index = x as u32;
+            let index = Sequence16(x as u16);
             let mut temp_packet: Packet = Packet::new();
-            let ack : u32 = index  % (MAX_PACKET_BUFFER_SIZE as u32);
+            let ack = Sequence16(index.index(MAX_PACKET_BUFFER_SIZE) as u16);
 
             temp_packet.set_sequence_number(index);
             temp_packet.set_ack(ack);
@@ -411,9 +411,9 @@ mod test {
         let buffer_index_to_be_high_priority = vec![0, 10, 20, 21, 31];
 
         for x in buffer_index_to_be_high_priority.clone() {
-            let index = x as u32;
+            let index = Sequence16(x as u16);
             let mut temp_packet: Packet = Packet::new();
-            let ack : u32 = index  % (MAX_PACKET_BUFFER_SIZE as u32);
+            let ack = Sequence16(index.index(MAX_PACKET_BUFFER_SIZE) as u16);
 
             temp_packet.set_sequence_number(index);
             temp_packet.set_ack(ack);
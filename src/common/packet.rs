@@ -5,6 +5,7 @@ use std::*;
 use utils::*;
 use crc::{crc32};
 use debug::{is_debug_print_enabled};
+use sequence::Sequence16;
 
 /*
 enum Actor {
@@ -18,10 +19,11 @@ enum Actor {
 pub struct UDPHeader {
     pub signature: u32,
     pub crc32: u32,
-    pub client_id: u64,         // hash of username?
-    pub sequence_number: u32,
-    pub ack_num: u32,
+    pub client_id: u64,         // Assigned by the server when we connect; 0 until then
+    pub sequence_number: Sequence16,
+    pub ack_num: Sequence16,
     pub ack_bits: u32,
+    pub packet_type: u8,        // a PacketType
 }
 
 #[repr(packed)]
@@ -40,6 +42,30 @@ pub struct Packet {
 
 pub const MAX_PACKET_SIZE: usize = 1472;
 
+// What a datagram is for. Everything the reliability system sends is a Payload, keep-alives
+// included; the rest set up a connection before any payload can flow.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum PacketType {
+    Payload = 0,
+    ConnectionRequest = 1,   // a client's public key, asking for an encrypted session
+    ConnectionResponse = 2,  // the server's public key in answer
+    ConnectionChallenge = 3, // a cookie the client has to send back before we answer for real
+    Disconnect = 4,          // the server is done with the client; sequenced and sealed like a payload
+}
+
+impl PacketType {
+    pub fn from_byte(byte: u8) -> Option<PacketType> {
+        match byte {
+            0 => Some(PacketType::Payload),
+            1 => Some(PacketType::ConnectionRequest),
+            2 => Some(PacketType::ConnectionResponse),
+            3 => Some(PacketType::ConnectionChallenge),
+            4 => Some(PacketType::Disconnect),
+            _ => None,
+        }
+    }
+}
+
 
 impl fmt::Debug for UDPHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -66,13 +92,15 @@ impl fmt::Display for UDPHeader {
   CRC32:\t{:?}
   Client_ID:\t{:?}
   Ack_Num:\t{:?}
-  Ack_Bits:\t{:?}",
+  Ack_Bits:\t{:?}
+  Packet_Type:\t{:?}",
          self.signature,
          self.sequence_number,
          self.crc32,
          self.client_id,
          self.ack_num,
-         self.ack_bits)
+         self.ack_bits,
+         self.packet_type)
     }
 }
 
@@ -128,9 +156,10 @@ impl Packet {
                    signature: 0x4C494645,
                    crc32: 0,
                    client_id: 0,
-                   sequence_number: 0,
-                   ack_num: 0,
-                   ack_bits: 0
+                   sequence_number: Sequence16(0),
+                   ack_num: Sequence16(0),
+                   ack_bits: 0,
+                   packet_type: PacketType::Payload as u8,
                },
                data: UDPData {
                    raw_data: vec![0;MAX_PACKET_SIZE - get_packet_header_size()],
@@ -150,22 +179,19 @@ impl Packet {
         mem::replace::<(Vec<u8>)>(&mut self.data.raw_data, data);
     }
 
-    pub fn get_sequence_num(&self) -> u32 {
+    pub fn get_sequence_num(&self) -> Sequence16 {
         self.header.sequence_number
     }
 
     pub fn inc_sequence_num(&mut self) {
-        let mut next_seq_num = self.get_sequence_num();
-        next_seq_num += 1;
-        next_seq_num %= u32::MAX.count_ones();
-        self.header.sequence_number = next_seq_num;
+        self.header.sequence_number = self.get_sequence_num().next();
     }
 
-    pub fn set_ack(&mut self, ack: u32) {
+    pub fn set_ack(&mut self, ack: Sequence16) {
         self.header.ack_num = ack;
     }
 
-    pub fn get_ack(&self) -> u32 {
+    pub fn get_ack(&self) -> Sequence16 {
         self.header.ack_num
     }
 
@@ -173,6 +199,10 @@ impl Packet {
         bit_set(&mut self.header.ack_bits, bit)
     }
 
+    pub fn set_ackbits(&mut self, ack_bits: u32) {
+        self.header.ack_bits = ack_bits;
+    }
+
     pub fn get_ackbits(&self) -> u32 {
         self.header.ack_bits
     }
@@ -181,15 +211,15 @@ impl Packet {
         (self.header.ack_bits>>bit) & 1
     }
 
-    pub fn set_client_id(&mut self, client_name: String) {
-        self.header.client_id = hash(&client_name);
+    pub fn set_client_id(&mut self, client_id: u64) {
+        self.header.client_id = client_id;
     }
 
     pub fn get_client_id(&self) -> u64 {
         self.header.client_id
     }
 
-    pub fn set_sequence_number(&mut self, seq_num: u32) {
+    pub fn set_sequence_number(&mut self, seq_num: Sequence16) {
         self.header.sequence_number = seq_num
     }
 
@@ -212,41 +242,176 @@ pub fn get_packet_header_size() -> usize {
     mem::size_of::<UDPHeader>()
 }
 
+// The header as it goes out on the wire: every field big-endian, one after the other. Encoding by
+// hand means a datagram can be built straight into, and read straight out of, a reusable buffer.
+pub const HEADER_SIZE: usize = 25;
+
+impl UDPHeader {
+    pub fn new(signature: u32) -> UDPHeader {
+        UDPHeader {
+            signature: signature,
+            crc32: 0,
+            client_id: 0,
+            sequence_number: Sequence16(0),
+            ack_num: Sequence16(0),
+            ack_bits: 0,
+            packet_type: PacketType::Payload as u8,
+        }
+    }
+
+    // Writes the header to the front of `buffer`. None if the buffer is too small for it.
+    pub fn write_to(&self, buffer: &mut [u8]) -> Option<usize> {
+        if buffer.len() < HEADER_SIZE {
+            return None;
+        }
+
+        write_u32(buffer, 0, self.signature);
+        write_u32(buffer, 4, self.crc32);
+        write_u64(buffer, 8, self.client_id);
+        write_u16(buffer, 16, self.sequence_number.0);
+        write_u16(buffer, 18, self.ack_num.0);
+        write_u32(buffer, 20, self.ack_bits);
+        buffer[24] = self.packet_type;
+
+        Some(HEADER_SIZE)
+    }
+
+    pub fn read_from(buffer: &[u8]) -> Option<UDPHeader> {
+        if buffer.len() < HEADER_SIZE {
+            return None;
+        }
+
+        Some(UDPHeader {
+            signature: read_u32(buffer, 0),
+            crc32: read_u32(buffer, 4),
+            client_id: read_u64(buffer, 8),
+            sequence_number: Sequence16(read_u16(buffer, 16)),
+            ack_num: Sequence16(read_u16(buffer, 18)),
+            ack_bits: read_u32(buffer, 20),
+            packet_type: buffer[24],
+        })
+    }
+}
+
+// Lays a datagram out in `buffer`, header first and payload after, and returns its length.
+// None if the two together do not fit.
+pub fn write_datagram(header: &UDPHeader, payload: &[u8], buffer: &mut [u8]) -> Option<usize> {
+    write_datagram_with_acks(header, &[], payload, buffer)
+}
+
+// Splits a received datagram into its header and its payload. The payload borrows from the
+// datagram rather than being copied out.
+pub fn read_datagram(datagram: &[u8]) -> Option<(UDPHeader, &[u8])> {
+    read_datagram_with_acks(datagram, &mut [])
+}
+
+// Ack bits beyond the 32 that fit in the header go between the header and the payload, as
+// big-endian words. Both ends have to agree on how many words there are.
+pub fn write_datagram_with_acks(header: &UDPHeader, extra_ack_bits: &[u32], payload: &[u8], buffer: &mut [u8]) -> Option<usize> {
+    let payload_start = HEADER_SIZE + 4 * extra_ack_bits.len();
+    let length = payload_start + payload.len();
+
+    if length > buffer.len() {
+        return None;
+    }
+
+    let _ = header.write_to(buffer);
+
+    for (index, word) in extra_ack_bits.iter().enumerate() {
+        write_u32(buffer, HEADER_SIZE + 4 * index, *word);
+    }
+    buffer[payload_start..length].copy_from_slice(payload);
+
+    Some(length)
+}
+
+// Fills `extra_ack_bits` from the words after the header. None if the datagram is too short to
+// hold them.
+pub fn read_datagram_with_acks<'a>(datagram: &'a [u8], extra_ack_bits: &mut [u32]) -> Option<(UDPHeader, &'a [u8])> {
+    let payload_start = HEADER_SIZE + 4 * extra_ack_bits.len();
+
+    if datagram.len() < payload_start {
+        return None;
+    }
+
+    match UDPHeader::read_from(datagram) {
+        Some(header) => {
+            for (index, word) in extra_ack_bits.iter_mut().enumerate() {
+                *word = read_u32(datagram, HEADER_SIZE + 4 * index);
+            }
+
+            Some((header, &datagram[payload_start..]))
+        },
+        None => {
+            None
+        }
+    }
+}
+
+// Big-endian helpers, shared with anything else that lays out bytes for the wire.
+pub fn write_u16(buffer: &mut [u8], offset: usize, value: u16) {
+    buffer[offset]     = (value >> 8) as u8;
+    buffer[offset + 1] = value as u8;
+}
+
+pub fn write_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset]     = (value >> 24) as u8;
+    buffer[offset + 1] = (value >> 16) as u8;
+    buffer[offset + 2] = (value >> 8) as u8;
+    buffer[offset + 3] = value as u8;
+}
+
+pub fn write_u64(buffer: &mut [u8], offset: usize, value: u64) {
+    write_u32(buffer, offset, (value >> 32) as u32);
+    write_u32(buffer, offset + 4, value as u32);
+}
+
+pub fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    ((buffer[offset] as u16) << 8) | (buffer[offset + 1] as u16)
+}
+
+pub fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    ((buffer[offset] as u32) << 24) |
+    ((buffer[offset + 1] as u32) << 16) |
+    ((buffer[offset + 2] as u32) << 8) |
+    (buffer[offset + 3] as u32)
+}
+
+pub fn read_u64(buffer: &[u8], offset: usize) -> u64 {
+    ((read_u32(buffer, offset) as u64) << 32) | (read_u32(buffer, offset + 4) as u64)
+}
+
 #[cfg(test)]
 mod test {
 
-    use utils::hash;
-    use packet::Packet;
+    use sequence::Sequence16;
+    use packet::{Packet, UDPHeader, PacketType, HEADER_SIZE, MAX_PACKET_SIZE, write_datagram, read_datagram,
+                 write_datagram_with_acks, read_datagram_with_acks};
 
     #[test]
     // Send and listen to the same socket (listen_addr), from another socket (send_addr)
     fn test_build_packet() {
-        let username = String::from("LifeUser1");
-        let hashed_username: u64 = hash(&username.clone());
-
-        println!("Hash: {}", hashed_username);
-
         let mut synchronize_pkt = Packet::new();
 
 
-        assert_eq!(0, synchronize_pkt.get_sequence_num());
+        assert_eq!(Sequence16(0), synchronize_pkt.get_sequence_num());
         synchronize_pkt.inc_sequence_num();
         synchronize_pkt.inc_sequence_num();
-        assert_eq!(2, synchronize_pkt.get_sequence_num());
+        assert_eq!(Sequence16(2), synchronize_pkt.get_sequence_num());
 
         // Testing wrapped case
-        for _ in 0..32 {
+        for _ in 0..0x10000 {
             synchronize_pkt.inc_sequence_num();
         }
-        assert_eq!(2, synchronize_pkt.get_sequence_num());
+        assert_eq!(Sequence16(2), synchronize_pkt.get_sequence_num());
 
-        synchronize_pkt.set_client_id(username);
-        assert_eq!(synchronize_pkt.get_client_id(), hashed_username);
+        synchronize_pkt.set_client_id(0x7F727D0AF49D4191);
+        assert_eq!(synchronize_pkt.get_client_id(), 0x7F727D0AF49D4191);
 
-        synchronize_pkt.set_ack(5);
+        synchronize_pkt.set_ack(Sequence16(5));
         synchronize_pkt.set_ackbit(31);
 
-        assert_eq!(5, synchronize_pkt.get_ack());
+        assert_eq!(Sequence16(5), synchronize_pkt.get_ack());
         assert_eq!(1, synchronize_pkt.is_ackbit_set(31));
         assert_eq!(0, synchronize_pkt.is_ackbit_set(5));
 
@@ -265,21 +430,81 @@ mod test {
 
         println!("{}", checksum);
 
-        assert_eq!(checksum, 0x8EC868F8);
+        assert_eq!(checksum, 0x7C0D50D9);
     }
 
     #[test]
     fn test_packet_crc32() {
-        let username = String::from("LifeUser1");
         let mut packet = Packet::new();
         let packet_data = vec![100, 3, 122, 255];
 
         packet.set_data(packet_data.clone());
-        packet.set_client_id(username);
+        packet.set_client_id(0x7F727D0AF49D4191);
         packet.calculate_checksum();
 
         let checksum = packet.get_checksum();
 
         assert_eq!(checksum, 0x6F947FE0);
     }
+
+    #[test]
+    fn test_datagram_round_trip() {
+        let mut header = UDPHeader::new(0x4C494645);
+        header.client_id = 0x0102030405060708;
+        header.sequence_number = Sequence16(0xFFFF);
+        header.ack_num = Sequence16(7);
+        header.ack_bits = 0x80000001;
+        header.packet_type = PacketType::ConnectionResponse as u8;
+
+        let payload = [100, 3, 122, 255];
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+
+        let length = write_datagram(&header, &payload, &mut buffer).unwrap();
+        assert_eq!(length, HEADER_SIZE + payload.len());
+        assert_eq!(&buffer[0..4], &[0x4C, 0x49, 0x46, 0x45]);
+        assert_eq!(&buffer[16..20], &[0xFF, 0xFF, 0x00, 0x07]);
+        assert_eq!(buffer[24], 2);
+
+        let (decoded, decoded_payload) = read_datagram(&buffer[..length]).unwrap();
+        assert!(decoded == header);
+        assert_eq!(decoded_payload, &payload[..]);
+        assert_eq!(PacketType::from_byte(decoded.packet_type), Some(PacketType::ConnectionResponse));
+        assert_eq!(PacketType::from_byte(3), Some(PacketType::ConnectionChallenge));
+        assert_eq!(PacketType::from_byte(4), Some(PacketType::Disconnect));
+        assert_eq!(PacketType::from_byte(5), None);
+    }
+
+    #[test]
+    fn test_datagram_bounds() {
+        let header = UDPHeader::new(0x4C494645);
+        let mut buffer = [0u8; HEADER_SIZE + 2];
+
+        assert_eq!(write_datagram(&header, &[1, 2, 3], &mut buffer), None);
+        assert_eq!(write_datagram(&header, &[1, 2], &mut buffer), Some(HEADER_SIZE + 2));
+
+        assert!(read_datagram(&buffer[..HEADER_SIZE - 1]).is_none());
+        assert_eq!(read_datagram(&buffer[..HEADER_SIZE]).unwrap().1.len(), 0);
+    }
+
+    #[test]
+    fn test_datagram_extra_ack_bits() {
+        let mut header = UDPHeader::new(0x4C494645);
+        header.ack_bits = 0xFFFFFFFF;
+
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let length = write_datagram_with_acks(&header, &[0x01020304, 0x80000000, 7], &[9, 9], &mut buffer).unwrap();
+        assert_eq!(length, HEADER_SIZE + 12 + 2);
+        assert_eq!(&buffer[HEADER_SIZE..HEADER_SIZE + 4], &[1, 2, 3, 4]);
+
+        let mut extra = [0u32; 3];
+        {
+            let (decoded, payload) = read_datagram_with_acks(&buffer[..length], &mut extra).unwrap();
+            assert!(decoded == header);
+            assert_eq!(payload, &[9, 9]);
+        }
+        assert_eq!(extra, [0x01020304, 0x80000000, 7]);
+
+        // Too short for the words it is meant to carry.
+        assert!(read_datagram_with_acks(&buffer[..HEADER_SIZE + 8], &mut extra).is_none());
+    }
 }
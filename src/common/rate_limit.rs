@@ -0,0 +1,417 @@
+/*
+ * Per-source rate limiting, with temporary bans for sources that misbehave.
+ *
+ * Every source address gets a token bucket. It holds up to `burst` tokens and refills at
+ * `packets_per_second`; each datagram from the source takes one. A datagram that finds the bucket
+ * empty is dropped and gets its source banned for `ban_seconds`, as does the `max_malformed`th
+ * datagram from a source that was not ours or failed its checks. Everything from a banned source is
+ * dropped without a look at what is in it.
+ *
+ * A source is an IP address, whatever port it sends from, so hopping from port to port gets around
+ * neither the limit nor a ban.
+ *
+ * Checking happens before a datagram is decoded at all, so a flood costs a hash lookup per packet
+ * and nothing more. Sources that have been quiet for a while are forgotten on prune. Rather than
+ * grow without bound, once we track MAX_TRACKED_SOURCES we forget the quarter of them that have
+ * been quiet longest to make room. Banned sources are never forgotten early, so if every source we
+ * track is banned, new ones are turned away until a ban runs out.
+ */
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use clock;
+use net::Address;
+
+// Enough for every client a server could plausibly have, and then some.
+pub const MAX_TRACKED_SOURCES: usize = 65536;
+
+// How many sources to forget at once when we run out of room, so the sort that picks them is paid
+// for by many newcomers rather than each one.
+const EVICTED_AT_ONCE: usize = MAX_TRACKED_SOURCES / 4;
+
+// The longest ban worth asking for: a year. Anything longer is more likely a typo.
+pub const MAX_BAN_SECONDS: f64 = 365.0 * 24.0 * 60.0 * 60.0;
+
+// Quiet for this long, and not banned, and a source is forgotten.
+const IDLE_SECONDS: f64 = 60.0;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub packets_per_second : f64,
+    pub burst : f64,  // How many packets a source can send at once after a quiet spell; at least 1
+    pub max_malformed : u32,  // Bad datagrams a source may send before it is banned
+    pub ban_seconds : f64,  // More than 0, up to MAX_BAN_SECONDS
+}
+
+impl RateLimit {
+    // A generous default: far more than a client sending every tick, far less than a flood.
+    pub fn new() -> RateLimit {
+        RateLimit {
+            packets_per_second : 1000.0,
+            burst : 1000.0,
+            max_malformed : 16,
+            ban_seconds : 30.0,
+        }
+    }
+
+    // A rate that is not a positive number would never refill, and ban every source after its
+    // first burst. A ban has to last a while, but not past MAX_BAN_SECONDS.
+    pub fn is_valid(&self) -> bool {
+        self.packets_per_second > 0.0 && self.packets_per_second.is_finite() && self.burst.is_finite() &&
+            self.ban_seconds > 0.0 && self.ban_seconds <= MAX_BAN_SECONDS
+    }
+}
+
+struct Source {
+    tokens : f64,
+    last_seen : Duration,
+    malformed : u32,
+    banned_until : Option<Duration>,
+}
+
+impl Source {
+    fn is_banned(&self, now: Duration) -> bool {
+        self.banned_until.map_or(false, |until| now < until)
+    }
+}
+
+pub struct RateLimiter {
+    limit : RateLimit,
+    sources : HashMap<IpAddr, Source>,
+    dropped_packets : u64,  // Over the limit, or from a banned source
+    bans : u64,  // How many times a source has been banned
+}
+
+impl RateLimiter {
+    // None unless the limit is_valid. A burst of less than one would turn away a source's very
+    // first datagram, so it is raised to one.
+    pub fn new(limit: RateLimit) -> Option<RateLimiter> {
+        if !limit.is_valid() {
+            return None;
+        }
+
+        Some(RateLimiter {
+            limit : RateLimit { burst : limit.burst.max(1.0), .. limit },
+            sources : HashMap::new(),
+            dropped_packets : 0,
+            bans : 0,
+        })
+    }
+
+    pub fn get_limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    // Whether a datagram just in from `address` may go any further. Takes a token if it may.
+    pub fn allow(&mut self, address: &Address, now: Duration) -> bool {
+        let ip = address.get_address();
+
+        if !self.sources.contains_key(&ip) && self.sources.len() >= MAX_TRACKED_SOURCES && !self.evict(now) {
+            self.dropped_packets += 1;
+            return false;
+        }
+
+        let limit = self.limit;
+        let source = self.sources.entry(ip).or_insert_with(|| Source {
+            tokens : limit.burst,
+            last_seen : now,
+            malformed : 0,
+            banned_until : None,
+        });
+
+        if let Some(until) = source.banned_until {
+            if now < until {
+                self.dropped_packets += 1;
+                return false;
+            }
+
+            source.banned_until = None;
+            source.malformed = 0;
+            source.tokens = limit.burst;
+        }
+
+        let refill = clock::seconds_between(source.last_seen, now) * limit.packets_per_second;
+        source.tokens = (source.tokens + refill).min(limit.burst);
+        source.last_seen = now;
+
+        if source.tokens >= 1.0 {
+            source.tokens -= 1.0;
+            return true;
+        }
+
+        source.banned_until = Some(clock::deadline_after(now, limit.ban_seconds));
+        self.dropped_packets += 1;
+        self.bans += 1;
+        false
+    }
+
+    // Notes a datagram from `address` that was not ours or failed a check, and bans the source
+    // once there have been too many.
+    pub fn malformed(&mut self, address: &Address, now: Duration) {
+        let limit = self.limit;
+
+        if let Some(source) = self.sources.get_mut(&address.get_address()) {
+            if source.banned_until.is_some() {
+                return;
+            }
+
+            source.malformed += 1;
+
+            if source.malformed >= limit.max_malformed {
+                source.banned_until = Some(clock::deadline_after(now, limit.ban_seconds));
+                self.bans += 1;
+            }
+        }
+    }
+
+    pub fn is_banned(&self, address: &Address, now: Duration) -> bool {
+        match self.sources.get(&address.get_address()) {
+            Some(source) => source.is_banned(now),
+            None => false,
+        }
+    }
+
+    // Forgets sources that have gone quiet and are not banned. Their buckets would be full again by
+    // now anyway.
+    pub fn prune(&mut self, now: Duration) {
+        let forgotten : Vec<IpAddr> = self.sources.iter()
+            .filter(|&(_, source)| !source.is_banned(now) && clock::seconds_between(source.last_seen, now) > IDLE_SECONDS)
+            .map(|(ip, _)| *ip)
+            .collect();
+
+        for ip in forgotten {
+            self.sources.remove(&ip);
+        }
+    }
+
+    // Makes room by forgetting the sources, banned ones aside, that have been quiet longest. They
+    // lose nothing but a bucket that has been refilling anyway. False if every source is banned.
+    fn evict(&mut self, now: Duration) -> bool {
+        let mut quiet : Vec<(Duration, IpAddr)> = self.sources.iter()
+            .filter(|&(_, source)| !source.is_banned(now))
+            .map(|(ip, source)| (source.last_seen, *ip))
+            .collect();
+        quiet.sort_by_key(|&(last_seen, _)| last_seen);
+
+        for &(_, ip) in quiet.iter().take(EVICTED_AT_ONCE) {
+            self.sources.remove(&ip);
+        }
+        !quiet.is_empty()
+    }
+
+    pub fn get_dropped_packets(&self) -> u64 {
+        self.dropped_packets
+    }
+
+    pub fn get_bans(&self) -> u64 {
+        self.bans
+    }
+
+    pub fn get_tracked_sources(&self) -> usize {
+        self.sources.len()
+    }
+}
+
+// ---------------------------------
+// |       RateLimiter Tests       |
+// ---------------------------------
+
+#[cfg(test)]
+mod test {
+
+    use std::f64;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+    use net::Address;
+    use rate_limit::{RateLimit, RateLimiter, MAX_BAN_SECONDS, MAX_TRACKED_SOURCES};
+
+    fn limit() -> RateLimit {
+        RateLimit {
+            packets_per_second : 10.0,
+            burst : 5.0,
+            max_malformed : 3,
+            ban_seconds : 2.0,
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_allows_a_burst_then_bans() {
+        let mut limiter = RateLimiter::new(limit()).unwrap();
+        let address = Address::new(Ipv4Addr::new(10, 0, 0, 1), 4000);
+        let other = Address::new(Ipv4Addr::new(10, 0, 0, 2), 4000);
+        let now = Duration::from_secs(100);
+
+        for _ in 0..5 {
+            assert!(limiter.allow(&address, now));
+        }
+        assert!(!limiter.allow(&address, now));
+        assert!(limiter.is_banned(&address, now));
+        assert_eq!(limiter.get_bans(), 1);
+
+        // The ban holds even once the bucket would have refilled, and touches nobody else.
+        assert!(!limiter.allow(&address, now + Duration::from_secs(1)));
+        assert!(limiter.allow(&other, now));
+        assert_eq!(limiter.get_dropped_packets(), 2);
+
+        // Afterwards the source starts over with a full bucket.
+        let later = now + Duration::from_millis(2001);
+        assert!(!limiter.is_banned(&address, later));
+        for _ in 0..5 {
+            assert!(limiter.allow(&address, later));
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_bans_the_address_not_the_port() {
+        let mut limiter = RateLimiter::new(limit()).unwrap();
+        let now = Duration::from_secs(100);
+
+        for port in 4000..4006 {
+            limiter.allow(&Address::new(Ipv4Addr::new(10, 0, 0, 1), port), now);
+        }
+        assert!(limiter.is_banned(&Address::new(Ipv4Addr::new(10, 0, 0, 1), 5000), now));
+        assert!(!limiter.allow(&Address::new(Ipv4Addr::new(10, 0, 0, 1), 5000), now));
+        assert_eq!(limiter.get_tracked_sources(), 1);
+    }
+
+    #[test]
+    fn test_rate_limit_lets_at_least_one_through() {
+        let mut limiter = RateLimiter::new(RateLimit { packets_per_second : 0.5, burst : 0.5, .. limit() }).unwrap();
+        let address = Address::new(Ipv4Addr::new(10, 0, 0, 1), 4000);
+        let now = Duration::from_secs(100);
+
+        assert_eq!(limiter.get_limit().burst, 1.0);
+        assert!(limiter.allow(&address, now));
+        assert!(!limiter.allow(&address, now));
+
+        // One every two seconds, once the ban is over.
+        assert!(limiter.allow(&address, now + Duration::from_secs(3)));
+        assert!(!limiter.allow(&address, now + Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn test_rate_limit_refills_over_time() {
+        let mut limiter = RateLimiter::new(limit()).unwrap();
+        let address = Address::new(Ipv4Addr::new(10, 0, 0, 1), 4000);
+        let mut now = Duration::from_secs(100);
+
+        // Ten a second, every second, for a while: right at the limit, never over it.
+        for _ in 0..50 {
+            assert!(limiter.allow(&address, now));
+            now = now + Duration::from_millis(100);
+        }
+        assert_eq!(limiter.get_dropped_packets(), 0);
+    }
+
+    #[test]
+    fn test_rate_limit_bans_repeated_malformed_packets() {
+        let mut limiter = RateLimiter::new(limit()).unwrap();
+        let address = Address::new(Ipv4Addr::new(10, 0, 0, 1), 4000);
+        let now = Duration::from_secs(100);
+
+        for _ in 0..3 {
+            assert!(limiter.allow(&address, now));
+            limiter.malformed(&address, now);
+        }
+        assert!(limiter.is_banned(&address, now));
+        assert!(!limiter.allow(&address, now));
+        assert_eq!(limiter.get_bans(), 1);
+    }
+
+    #[test]
+    fn test_rate_limit_refuses_limits_it_cannot_keep() {
+        assert!(RateLimiter::new(limit()).is_some());
+        assert!(RateLimiter::new(RateLimit { ban_seconds : MAX_BAN_SECONDS, .. limit() }).is_some());
+
+        for &rate in [0.0, -1.0, f64::NAN, f64::INFINITY].iter() {
+            assert!(!RateLimit { packets_per_second : rate, .. limit() }.is_valid());
+            assert!(RateLimiter::new(RateLimit { packets_per_second : rate, .. limit() }).is_none());
+        }
+        for &burst in [f64::NAN, f64::INFINITY].iter() {
+            assert!(RateLimiter::new(RateLimit { burst : burst, .. limit() }).is_none());
+        }
+        for &seconds in [0.0, -1.0, f64::NAN, f64::INFINITY, MAX_BAN_SECONDS + 1.0].iter() {
+            assert!(RateLimiter::new(RateLimit { ban_seconds : seconds, .. limit() }).is_none());
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_bans_for_a_year_without_overflowing() {
+        let mut limiter = RateLimiter::new(RateLimit { ban_seconds : MAX_BAN_SECONDS, .. limit() }).unwrap();
+        let flooder = Address::new(Ipv4Addr::new(10, 0, 0, 1), 4000);
+        let garbage = Address::new(Ipv4Addr::new(10, 0, 0, 2), 4000);
+        let now = Duration::from_secs(100);
+
+        for _ in 0..6 {
+            limiter.allow(&flooder, now);
+        }
+        for _ in 0..3 {
+            limiter.allow(&garbage, now);
+            limiter.malformed(&garbage, now);
+        }
+
+        let much_later = now + Duration::from_secs(MAX_BAN_SECONDS as u64 - 1);
+        assert!(limiter.is_banned(&flooder, much_later));
+        assert!(limiter.is_banned(&garbage, much_later));
+    }
+
+    #[test]
+    fn test_rate_limit_makes_room_at_capacity() {
+        let mut limiter = RateLimiter::new(limit()).unwrap();
+        let banned = Address::new(Ipv4Addr::new(10, 0, 0, 1), 4000);
+        let now = Duration::from_secs(100);
+
+        for _ in 0..6 {
+            limiter.allow(&banned, now);
+        }
+        for i in 1..MAX_TRACKED_SOURCES as u32 {
+            assert!(limiter.allow(&Address::new(Ipv4Addr::from(0x0B000000 + i), 4000), now));
+        }
+        assert_eq!(limiter.get_tracked_sources(), MAX_TRACKED_SOURCES);
+
+        // A newcomer is still held to the limit, and the ban still stands.
+        let newcomer = Address::new(Ipv4Addr::new(12, 0, 0, 1), 4000);
+        for _ in 0..5 {
+            assert!(limiter.allow(&newcomer, now));
+        }
+        assert!(!limiter.allow(&newcomer, now));
+        assert!(limiter.is_banned(&banned, now));
+        assert!(limiter.get_tracked_sources() < MAX_TRACKED_SOURCES);
+    }
+
+    #[test]
+    fn test_rate_limit_turns_newcomers_away_when_every_source_is_banned() {
+        let mut limiter = RateLimiter::new(RateLimit { burst : 1.0, .. limit() }).unwrap();
+        let now = Duration::from_secs(100);
+
+        for i in 0..MAX_TRACKED_SOURCES as u32 {
+            let address = Address::new(Ipv4Addr::from(0x0B000000 + i), 4000);
+            limiter.allow(&address, now);
+            limiter.allow(&address, now);
+        }
+        assert_eq!(limiter.get_bans(), MAX_TRACKED_SOURCES as u64);
+
+        let newcomer = Address::new(Ipv4Addr::new(12, 0, 0, 1), 4000);
+        assert!(!limiter.allow(&newcomer, now));
+        assert!(limiter.allow(&newcomer, now + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_rate_limit_forgets_quiet_sources() {
+        let mut limiter = RateLimiter::new(limit()).unwrap();
+        let quiet = Address::new(Ipv4Addr::new(10, 0, 0, 1), 4000);
+        let banned = Address::new(Ipv4Addr::new(10, 0, 0, 2), 4000);
+        let now = Duration::from_secs(100);
+
+        assert!(limiter.allow(&quiet, now));
+        for _ in 0..6 {
+            limiter.allow(&banned, now + Duration::from_secs(60));
+        }
+
+        limiter.prune(now + Duration::from_secs(61));
+        assert_eq!(limiter.get_tracked_sources(), 1);
+        assert!(limiter.is_banned(&banned, now + Duration::from_secs(61)));
+    }
+}
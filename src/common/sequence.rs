@@ -0,0 +1,183 @@
+/*
+ * Sequence16, the sequence numbers packets carry on the wire.
+ *
+ * Sixteen bits wrap after 65536 packets, which is under twenty minutes at 60 packets a second,
+ * so every comparison has to allow for it. A sequence counts as more recent than another when it
+ * is less than half the sequence space ahead of it, following sequence_more_recent with a
+ * max_sequence of 0xFFFF. That way the two can never disagree about which of a pair is newer,
+ * and a SequenceBuffer keyed by Sequence16 values orders them the same way we do.
+ */
+
+use std::fmt;
+use sequence_buffer::sequence_more_recent;
+
+// The highest Sequence16, as the max_sequence of the sequence_buffer functions.
+pub const MAX_SEQUENCE16: u32 = 0xFFFF;
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Eq, Hash, Debug, Clone, Copy, Default)]
+pub struct Sequence16(pub u16);
+
+impl Sequence16 {
+    pub fn next(&self) -> Sequence16 {
+        Sequence16(self.0.wrapping_add(1))
+    }
+
+    // The sequence `amount` steps after this one.
+    pub fn after(&self, amount: u16) -> Sequence16 {
+        Sequence16(self.0.wrapping_add(amount))
+    }
+
+    // The sequence `amount` steps before this one.
+    pub fn before(&self, amount: u16) -> Sequence16 {
+        Sequence16(self.0.wrapping_sub(amount))
+    }
+
+    pub fn more_recent_than(&self, other: Sequence16) -> bool {
+        sequence_more_recent(&(self.0 as u32), &(other.0 as u32), &MAX_SEQUENCE16)
+    }
+
+    // How many steps this sequence is ahead of `older`, counting forwards through the wrap.
+    pub fn distance_from(&self, older: Sequence16) -> u16 {
+        self.0.wrapping_sub(older.0)
+    }
+
+    // How far ahead of `other` this sequence is: positive if it is more recent, negative if it is
+    // older, and never more than half the sequence space either way.
+    pub fn difference(&self, other: Sequence16) -> i32 {
+        if self.more_recent_than(other) {
+            self.distance_from(other) as i32
+        }
+        else {
+            -(other.distance_from(*self) as i32)
+        }
+    }
+
+    // The slot this sequence takes in a ring of `size` entries.
+    pub fn index(&self, size: usize) -> usize {
+        self.0 as usize % size
+    }
+}
+
+impl From<u16> for Sequence16 {
+    fn from(sequence: u16) -> Sequence16 {
+        Sequence16(sequence)
+    }
+}
+
+impl From<Sequence16> for u32 {
+    fn from(sequence: Sequence16) -> u32 {
+        sequence.0 as u32
+    }
+}
+
+impl fmt::Display for Sequence16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// ---------------------------------
+// |       Sequence16 Tests        |
+// ---------------------------------
+
+#[cfg(test)]
+mod test {
+
+    use rand;
+    use sequence::{Sequence16, MAX_SEQUENCE16};
+    use sequence_buffer::{SequenceBuffer, sequence_more_recent};
+
+    // Every sequence paired with others spread across the whole space, including both sides of
+    // the halfway point where which of the two is newer flips over.
+    fn for_pairs<F>(mut check: F) where F: FnMut(Sequence16, Sequence16, u16) {
+        let steps : Vec<u16> = vec![0, 1, 2, 31, 32, 33, 1000, 0x7FFE, 0x7FFF, 0x8000, 0x8001, 0xFFFE, 0xFFFF];
+
+        for sequence in 0..0x10000u32 {
+            let a = Sequence16(sequence as u16);
+
+            for step in steps.iter() {
+                check(a, a.after(*step), *step);
+            }
+
+            let step = rand::random::<u16>();
+            check(a, a.after(step), step);
+        }
+    }
+
+    #[test]
+    fn test_sequence16_wraps() {
+        assert_eq!(Sequence16(0xFFFF).next(), Sequence16(0));
+        assert_eq!(Sequence16(0).before(1), Sequence16(0xFFFF));
+        assert_eq!(Sequence16(0xFFFE).after(4), Sequence16(2));
+        assert_eq!(Sequence16(2).distance_from(Sequence16(0xFFFE)), 4);
+        assert_eq!(Sequence16(2).difference(Sequence16(0xFFFE)), 4);
+        assert_eq!(Sequence16(0xFFFE).difference(Sequence16(2)), -4);
+
+        assert!(Sequence16(0).more_recent_than(Sequence16(0xFFFF)));
+        assert!(!Sequence16(0xFFFF).more_recent_than(Sequence16(0)));
+        assert!(Sequence16(0x7FFF).more_recent_than(Sequence16(0)));
+        assert!(!Sequence16(0x8001).more_recent_than(Sequence16(0)));
+    }
+
+    #[test]
+    fn test_sequence16_stepping_round_trips() {
+        for_pairs(|a, b, step| {
+            assert_eq!(b.distance_from(a), step);
+            assert_eq!(b.before(step), a);
+            assert_eq!(a.after(step).after(0u16.wrapping_sub(step)), a);
+            assert_eq!(a.after(b.difference(a) as u16), b);
+        });
+    }
+
+    #[test]
+    fn test_sequence16_exactly_one_of_a_pair_is_newer() {
+        for_pairs(|a, b, _| {
+            let newer = a.more_recent_than(b) as u32 + b.more_recent_than(a) as u32;
+
+            if a == b {
+                assert_eq!(newer, 0);
+                assert_eq!(a.difference(b), 0);
+            }
+            else {
+                assert_eq!(newer, 1);
+                assert_eq!(a.difference(b), -b.difference(a));
+                assert_eq!(a.difference(b) > 0, a.more_recent_than(b));
+            }
+            assert!(a.difference(b).abs() <= 0x8000);
+        });
+    }
+
+    #[test]
+    fn test_sequence16_less_than_half_ahead_is_newer() {
+        for_pairs(|a, b, step| {
+            if step > 0 && step < 0x8000 {
+                assert!(b.more_recent_than(a));
+                assert_eq!(b.difference(a), step as i32);
+            }
+            else if step > 0x8000 {
+                assert!(a.more_recent_than(b));
+                assert_eq!(a.difference(b), 0x10000 - step as i32);
+            }
+        });
+    }
+
+    #[test]
+    fn test_sequence16_agrees_with_sequence_buffer() {
+        for_pairs(|a, b, _| {
+            assert_eq!(a.more_recent_than(b), sequence_more_recent(&a.into(), &b.into(), &MAX_SEQUENCE16));
+        });
+
+        // Stepping through the whole space twice, the buffer always has the latest sequence as
+        // its newest and keeps the ones just behind it.
+        let mut buffer = SequenceBuffer::new(64, MAX_SEQUENCE16);
+        let mut sequence = Sequence16(0xFFC0);
+
+        for _ in 0..0x20000 {
+            buffer.insert(sequence.into(), ());
+            assert_eq!(buffer.newest(), Some(sequence.into()));
+            assert!(buffer.exists(sequence.before(63).into()) || buffer.len() < 64);
+
+            sequence = sequence.next();
+        }
+    }
+}
@@ -0,0 +1,523 @@
+/*
+ * A sequence buffer, as described in GafferOnGames' "Reliable Ordered Messages".
+ *
+ * It is a fixed-size ring indexed by sequence number: sequence S lives in slot S % size. Each slot
+ * remembers which sequence it currently holds, so looking up, inserting and removing a sequence
+ * are all O(1), and a lookup can never be fooled by an entry left over from an earlier lap around
+ * the sequence space.
+ *
+ * The buffer only ever holds the most recent `size` sequences. Sequences compare with wrap-around
+ * in mind (see sequence_more_recent), so the buffer keeps working as sequence numbers roll over
+ * from max_sequence back to 0. Inserting a sequence more recent than any before it clears out
+ * the slots skipped over on the way, and sequences that have already fallen out of the window
+ * are refused.
+ */
+
+use std::slice;
+
+// Is s1 newer than s2, allowing for the sequence numbers wrapping around at max_sequence?
+pub fn sequence_more_recent( s1: &u32, s2: &u32, max_sequence: &u32 ) -> bool
+{
+    ( s1 > s2 ) && ( s1 - s2 <= max_sequence/2 ) ||
+    ( s2 > s1 ) && ( s2 - s1 >  max_sequence/2 )
+}
+
+// The sequence `amount` steps before `sequence`, wrapping below 0 back up to max_sequence.
+pub fn sequence_before(sequence: u32, amount: u32, max_sequence: u32) -> u32 {
+    if sequence >= amount {
+        sequence - amount
+    }
+    else {
+        max_sequence - (amount - sequence - 1)
+    }
+}
+
+// How many steps `newer` is ahead of `older`, wrapping at max_sequence.
+pub fn sequence_distance(newer: u32, older: u32, max_sequence: u32) -> u32 {
+    if newer >= older {
+        newer - older
+    }
+    else {
+        (max_sequence - older) + newer + 1
+    }
+}
+
+struct Entry<T> {
+    sequence : u32,
+    data : T,
+}
+
+pub struct SequenceBuffer<T> {
+    entries : Vec<Option<Entry<T>>>,
+    max_sequence : u32,
+    newest : Option<u32>,
+    length : usize,
+}
+
+impl<T> SequenceBuffer<T> {
+    pub fn new(size: usize, max_sequence: u32) -> SequenceBuffer<T> {
+        assert!(size > 0);
+        assert!(size as u64 <= (max_sequence as u64 + 1) / 2, "A sequence buffer can cover at most half its sequence space.");
+
+        let mut entries = Vec::with_capacity(size);
+        for _ in 0..size {
+            entries.push(None);
+        }
+
+        SequenceBuffer {
+            entries : entries,
+            max_sequence : max_sequence,
+            newest : None,
+            length : 0,
+        }
+    }
+
+    // Stores `data` under `sequence` and returns whatever was in its slot before, which is either
+    // an older copy of the same sequence or a sequence one lap behind it. Sequences too old to fit
+    // in the buffer are dropped.
+    pub fn insert(&mut self, sequence: u32, data: T) -> Option<T> {
+        assert!(sequence <= self.max_sequence);
+
+        if self.is_too_old(sequence) {
+            return None;
+        }
+
+        match self.newest {
+            Some(newest) => {
+                if sequence_more_recent(&sequence, &newest, &self.max_sequence) {
+                    self.clear_between(newest, sequence);
+                    self.newest = Some(sequence);
+                }
+            },
+            None => {
+                self.newest = Some(sequence);
+            }
+        }
+
+        let index = self.index_for(sequence);
+        let previous = self.entries[index].take();
+
+        if previous.is_none() {
+            self.length += 1;
+        }
+
+        self.entries[index] = Some(Entry {
+            sequence : sequence,
+            data : data,
+        });
+
+        match previous {
+            Some(entry) => {
+                Some(entry.data)
+            },
+            None => {
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, sequence: u32) -> Option<&T> {
+        match self.entries[self.index_for(sequence)] {
+            Some(ref entry) if entry.sequence == sequence => {
+                Some(&entry.data)
+            },
+            _ => {
+                None
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, sequence: u32) -> Option<&mut T> {
+        let index = self.index_for(sequence);
+
+        match self.entries[index] {
+            Some(ref mut entry) if entry.sequence == sequence => {
+                Some(&mut entry.data)
+            },
+            _ => {
+                None
+            }
+        }
+    }
+
+    pub fn exists(&self, sequence: u32) -> bool {
+        self.get(sequence).is_some()
+    }
+
+    pub fn remove(&mut self, sequence: u32) -> Option<T> {
+        if !self.exists(sequence) {
+            return None;
+        }
+
+        let index = self.index_for(sequence);
+        self.length -= 1;
+
+        match self.entries[index].take() {
+            Some(entry) => {
+                Some(entry.data)
+            },
+            None => {
+                None
+            }
+        }
+    }
+
+    // Keeps only the entries `keep` says yes to, and returns how many were removed.
+    pub fn retain<F>(&mut self, mut keep: F) -> usize where F: FnMut(u32, &T) -> bool {
+        let mut removed = 0;
+
+        for slot in self.entries.iter_mut() {
+            let discard = match *slot {
+                Some(ref entry) => {
+                    !keep(entry.sequence, &entry.data)
+                },
+                None => {
+                    false
+                }
+            };
+
+            if discard {
+                *slot = None;
+                removed += 1;
+            }
+        }
+
+        self.length -= removed;
+        removed
+    }
+
+    // Checks that every entry sits in its own slot and within one buffer's length of the newest.
+    pub fn verify_sequencing(&self) {
+        for (index, slot) in self.entries.iter().enumerate() {
+            match *slot {
+                Some(ref entry) => {
+                    assert!(entry.sequence <= self.max_sequence);
+                    assert_eq!(self.index_for(entry.sequence), index);
+
+                    match self.newest {
+                        Some(newest) => {
+                            assert!(!sequence_more_recent(&entry.sequence, &newest, &self.max_sequence));
+                            assert!(sequence_distance(newest, entry.sequence, self.max_sequence) < self.entries.len() as u32);
+                        },
+                        None => {
+                            panic!("Sequence {} is stored but no newest sequence is known", entry.sequence);
+                        }
+                    }
+                },
+                None => {},
+            }
+        }
+    }
+
+    // True if `sequence` is so far behind the newest sequence that it no longer fits.
+    pub fn is_too_old(&self, sequence: u32) -> bool {
+        match self.newest {
+            Some(newest) => {
+                !sequence_more_recent(&sequence, &newest, &self.max_sequence) &&
+                    sequence_distance(newest, sequence, self.max_sequence) >= self.entries.len() as u32
+            },
+            None => {
+                false
+            }
+        }
+    }
+
+    pub fn newest(&self) -> Option<u32> {
+        self.newest
+    }
+
+    pub fn iter(&self) -> SequenceBufferIter<T> {
+        SequenceBufferIter {
+            slots : self.entries.iter(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for slot in self.entries.iter_mut() {
+            *slot = None;
+        }
+        self.newest = None;
+        self.length = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get_max_sequence(&self) -> u32 {
+        self.max_sequence
+    }
+
+    fn index_for(&self, sequence: u32) -> usize {
+        (sequence as u64 % self.entries.len() as u64) as usize
+    }
+
+    // Empties the slots of every sequence after `from` up to, but not including, `to`. Whatever
+    // they hold is at least a lap old by now.
+    fn clear_between(&mut self, from: u32, to: u32) {
+        let skipped = sequence_distance(to, from, self.max_sequence) - 1;
+        let to_clear = if (skipped as u64) < self.entries.len() as u64 { skipped as usize } else { self.entries.len() };
+
+        let mut sequence = from;
+        for _ in 0..to_clear {
+            sequence = if sequence == self.max_sequence { 0 } else { sequence + 1 };
+
+            let index = self.index_for(sequence);
+            if self.entries[index].take().is_some() {
+                self.length -= 1;
+            }
+        }
+    }
+}
+
+pub struct SequenceBufferIter<'a, T: 'a> {
+    slots : slice::Iter<'a, Option<Entry<T>>>,
+}
+
+impl<'a, T> Iterator for SequenceBufferIter<'a, T> {
+    type Item = (u32, &'a T);
+
+    fn next(&mut self) -> Option<(u32, &'a T)> {
+        loop {
+            match self.slots.next() {
+                Some(&Some(ref entry)) => {
+                    return Some((entry.sequence, &entry.data));
+                },
+                Some(&None) => {},
+                None => {
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+// ---------------------------------
+// |     SequenceBuffer Tests      |
+// ---------------------------------
+
+#[cfg(test)]
+mod test {
+
+    use rand;
+    use sequence_buffer::{SequenceBuffer, sequence_more_recent, sequence_before, sequence_distance};
+
+    #[test]
+    fn test_sequence_more_recent() {
+        assert_eq!(true, sequence_more_recent(&4, &1, &10));
+        assert_eq!(false, sequence_more_recent(&1, &4, &10));
+        assert_eq!(true, sequence_more_recent(&0, &0xFF, &0xFF));
+        assert_eq!(false, sequence_more_recent(&0xFF, &0, &0xFF));
+    }
+
+    #[test]
+    fn test_sequence_arithmetic_wraps() {
+        assert_eq!(sequence_before(100, 1, 0xFF), 99);
+        assert_eq!(sequence_before(0, 1, 0xFF), 0xFF);
+        assert_eq!(sequence_before(1, 3, 0xFF), 0xFE);
+        assert_eq!(sequence_before(0, 1, 0xFFFFFFFF), 0xFFFFFFFF);
+
+        assert_eq!(sequence_distance(100, 90, 0xFF), 10);
+        assert_eq!(sequence_distance(2, 0xFE, 0xFF), 4);
+        assert_eq!(sequence_distance(0, 0xFFFFFFFF, 0xFFFFFFFF), 1);
+    }
+
+    #[test]
+    fn test_sequence_buffer_exists() {
+        let mut buffer = SequenceBuffer::new(32, 0xFFFF);
+
+        buffer.insert(100, "a");
+        buffer.insert(101, "b");
+        buffer.insert(102, "c");
+
+        assert_eq!(buffer.exists(100), true);
+        assert_eq!(buffer.exists(102), true);
+        assert_eq!(buffer.exists(99), false);
+
+        // Same slot as 100, but a different sequence.
+        assert_eq!(buffer.exists(132), false);
+        assert_eq!(buffer.len(), 3);
+    }
+
+    #[test]
+    fn test_sequence_buffer_get_and_remove() {
+        let mut buffer = SequenceBuffer::new(32, 0xFFFF);
+
+        buffer.insert(7, 70);
+        buffer.insert(8, 80);
+
+        assert_eq!(buffer.get(7), Some(&70));
+        assert_eq!(buffer.get(9), None);
+
+        match buffer.get_mut(8) {
+            Some(value) => {
+                *value += 1;
+            },
+            None => {
+                panic!("Sequence 8 should be in the buffer");
+            }
+        }
+        assert_eq!(buffer.get(8), Some(&81));
+
+        assert_eq!(buffer.remove(7), Some(70));
+        assert_eq!(buffer.remove(7), None);
+        assert_eq!(buffer.exists(7), false);
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn test_sequence_buffer_slot_zero_is_usable() {
+        let mut buffer = SequenceBuffer::new(32, 0xFFFF);
+
+        buffer.insert(0, 1);
+        buffer.insert(1, 2);
+
+        assert_eq!(buffer.remove(0), Some(1));
+        assert_eq!(buffer.remove(1), Some(2));
+        assert_eq!(buffer.is_empty(), true);
+    }
+
+    #[test]
+    fn test_sequence_buffer_refuses_sequences_too_old() {
+        let mut buffer = SequenceBuffer::new(32, 0xFFFF);
+
+        buffer.insert(100, ());
+
+        assert_eq!(buffer.is_too_old(68), true);
+        assert_eq!(buffer.is_too_old(69), false);
+
+        buffer.insert(68, ());
+        assert_eq!(buffer.exists(68), false);
+
+        buffer.insert(69, ());
+        assert_eq!(buffer.exists(69), true);
+        buffer.verify_sequencing();
+    }
+
+    #[test]
+    fn test_sequence_buffer_insert_returns_evicted_entry() {
+        let mut buffer = SequenceBuffer::new(4, 0xFFFF);
+
+        for sequence in 0..4 {
+            assert_eq!(buffer.insert(sequence, sequence), None);
+        }
+
+        assert_eq!(buffer.insert(4, 4), Some(0));
+        assert_eq!(buffer.exists(0), false);
+        assert_eq!(buffer.len(), 4);
+    }
+
+    #[test]
+    fn test_sequence_buffer_jump_clears_skipped_slots() {
+        let mut buffer = SequenceBuffer::new(32, 0xFFFF);
+
+        for sequence in 0..32 {
+            buffer.insert(sequence, ());
+        }
+
+        buffer.insert(40, ());
+
+        // 9..31 are still within 32 of 40; 0..8 fell out of the window or had their slots skipped over.
+        for sequence in 0..9 {
+            assert_eq!(buffer.exists(sequence), false);
+        }
+        for sequence in 9..32 {
+            assert_eq!(buffer.exists(sequence), true);
+        }
+        assert_eq!(buffer.len(), 24);
+        buffer.verify_sequencing();
+    }
+
+    #[test]
+    fn test_sequence_buffer_wrap_around() {
+        const MAXIMUM_SEQUENCE : u32 = 0xFF;
+
+        let mut buffer = SequenceBuffer::new(64, MAXIMUM_SEQUENCE);
+
+        for sequence in 200..256 {
+            buffer.insert(sequence, ());
+            buffer.verify_sequencing();
+        }
+
+        for sequence in 0..50 {
+            buffer.insert(sequence, ());
+            buffer.verify_sequencing();
+        }
+
+        assert_eq!(buffer.newest(), Some(49));
+        assert_eq!(buffer.exists(49), true);
+        assert_eq!(buffer.exists(242), true);
+        assert_eq!(buffer.exists(241), false);
+        assert_eq!(buffer.len(), 64);
+    }
+
+    #[test]
+    fn test_sequence_buffer_old_lap_does_not_alias() {
+        const MAXIMUM_SEQUENCE : u32 = 0xFF;
+
+        let mut buffer = SequenceBuffer::new(128, MAXIMUM_SEQUENCE);
+
+        buffer.insert(10, ());
+        buffer.insert(100, ());
+        assert_eq!(buffer.exists(10), true);
+
+        // 10 is now more than a buffer behind; the next lap's 10 must not find it.
+        buffer.insert(200, ());
+        assert_eq!(buffer.exists(10), false);
+
+        assert_eq!(buffer.insert(10, ()), None);
+        assert_eq!(buffer.newest(), Some(10));
+        assert_eq!(buffer.exists(200), true);
+        assert_eq!(buffer.exists(100), false);
+        buffer.verify_sequencing();
+    }
+
+    #[test]
+    fn test_sequence_buffer_retain() {
+        let mut buffer = SequenceBuffer::new(32, 0xFFFF);
+
+        for sequence in 0..10 {
+            buffer.insert(sequence, sequence);
+        }
+
+        let removed = buffer.retain(|_, value| value % 2 == 0);
+
+        assert_eq!(removed, 5);
+        assert_eq!(buffer.len(), 5);
+        assert_eq!(buffer.iter().map(|(sequence, _)| sequence).collect::<Vec<u32>>(), vec![0, 2, 4, 6, 8]);
+    }
+
+    #[test]
+    fn test_sequence_buffer_stress_random() {
+        const MAXIMUM_SEQUENCE : u32 = 0xFFFF;
+
+        let mut buffer = SequenceBuffer::new(1024, MAXIMUM_SEQUENCE);
+        let mut sequence = rand::random::<u32>() % MAXIMUM_SEQUENCE;
+
+        // Mostly moving forwards, with the occasional late or duplicate arrival.
+        for _ in 0..10000 {
+            let step = rand::random::<u32>() % 8;
+            let arrival = if rand::random::<bool>() {
+                sequence = (sequence + step) % (MAXIMUM_SEQUENCE + 1);
+                sequence
+            } else {
+                sequence_before(sequence, step * 100, MAXIMUM_SEQUENCE)
+            };
+
+            buffer.insert(arrival, arrival);
+            buffer.verify_sequencing();
+
+            if !buffer.is_too_old(arrival) {
+                assert_eq!(buffer.get(arrival), Some(&arrival));
+            }
+        }
+    }
+}
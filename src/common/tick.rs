@@ -0,0 +1,145 @@
+/*
+ * Fixed timestep scheduling for the server loop.
+ *
+ * Rather than calling ReceivePacket and Update as fast as the CPU allows, the server runs its
+ * work in ticks at a fixed rate. Between ticks it blocks on a mio::Poll, so an idle server
+ * sleeps instead of spinning. Each tick reports how much time really passed since the previous
+ * one, for anything in the tick that works in deltas.
+ *
+ * When a tick's work takes longer than the tick itself, that tick has overrun. We count it and
+ * start timing again from the moment it finished, instead of firing a burst of back-to-back
+ * ticks to catch up.
+ */
+
+use std::time::{Duration, Instant};
+use mio;
+use clock;
+
+pub const DEFAULT_TICK_RATE: u32 = 60;
+
+pub struct TickScheduler {
+    tick_duration : Duration,
+    last_tick : Instant,
+    next_tick : Instant,
+    tick_count : u64,
+    overrun_count : u64,
+}
+
+impl TickScheduler {
+    pub fn new(tick_rate: u32) -> TickScheduler {
+        assert!(tick_rate > 0, "Tick rate must be at least one tick per second.");
+
+        let tick_duration = Duration::new(0, 1_000_000_000 / tick_rate);
+        let now = Instant::now();
+
+        TickScheduler {
+            tick_duration : tick_duration,
+            last_tick : now,
+            next_tick : now + tick_duration,
+            tick_count : 0,
+            overrun_count : 0,
+        }
+    }
+
+    // Blocks on `poll` until the next tick is due, then returns the seconds since the last one.
+    // Readiness events only wake us early; the caller drains the sockets once the tick starts.
+    pub fn wait_for_tick(&mut self, poll: &mio::Poll, events: &mut mio::Events) -> f32 {
+        loop {
+            let now = Instant::now();
+
+            if now >= self.next_tick {
+                break;
+            }
+
+            let _ = poll.poll(events, Some(self.next_tick - now));
+        }
+
+        self.start_tick()
+    }
+
+    // Call once the tick's work is done. Returns by how much it overran, if it did.
+    pub fn end_tick(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+
+        if now <= self.next_tick {
+            return None;
+        }
+
+        let overrun = now - self.next_tick;
+
+        self.overrun_count += 1;
+        self.next_tick = now;
+
+        Some(overrun)
+    }
+
+    pub fn get_tick_duration(&self) -> Duration {
+        self.tick_duration
+    }
+
+    pub fn get_tick_count(&self) -> u64 {
+        self.tick_count
+    }
+
+    pub fn get_overrun_count(&self) -> u64 {
+        self.overrun_count
+    }
+
+    fn start_tick(&mut self) -> f32 {
+        let now = Instant::now();
+        let elapsed = now - self.last_tick;
+
+        self.last_tick = now;
+        self.next_tick = self.next_tick + self.tick_duration;
+        self.tick_count += 1;
+
+        clock::as_seconds(elapsed) as f32
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::thread;
+    use std::time::Duration;
+    use mio;
+    use tick::TickScheduler;
+
+    #[test]
+    fn test_tick_waits_for_tick_duration() {
+        let poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(8);
+        let mut scheduler = TickScheduler::new(50);
+
+        let elapsed = scheduler.wait_for_tick(&poll, &mut events);
+
+        assert!(elapsed >= 0.019, "Tick came early: {}", elapsed);
+        assert_eq!(scheduler.get_tick_count(), 1);
+        assert_eq!(scheduler.end_tick(), None);
+        assert_eq!(scheduler.get_overrun_count(), 0);
+    }
+
+    #[test]
+    fn test_tick_reports_overrun() {
+        let poll = mio::Poll::new().unwrap();
+        let mut events = mio::Events::with_capacity(8);
+        let mut scheduler = TickScheduler::new(100);
+
+        scheduler.wait_for_tick(&poll, &mut events);
+        thread::sleep(Duration::from_millis(30));
+
+        match scheduler.end_tick() {
+            Some(overrun) => {
+                assert!(overrun >= Duration::from_millis(15));
+            },
+            None => {
+                panic!("A 30ms tick at 100Hz should have overrun.");
+            }
+        }
+        assert_eq!(scheduler.get_overrun_count(), 1);
+
+        // After an overrun the next tick starts straight away and reports the real time passed.
+        let elapsed = scheduler.wait_for_tick(&poll, &mut events);
+        assert!(elapsed >= 0.03, "Elapsed time should include the overrun: {}", elapsed);
+    }
+}
@@ -1,105 +1,653 @@
-extern crate mioco;
-#[macro_use] extern crate log;
 extern crate env_logger;
-extern crate bincode;
+extern crate mio;
 extern crate rustc_serialize;
 extern crate common;
 
-use std::net::{SocketAddr, SocketAddrV4};
+use std::env;
+use std::fs;
 use std::io;
-use mioco::udp::{UdpSocket};
-use mioco::mio::Ipv4Addr;
-use common::packet::*;
-use common::communicate;
+use std::io::BufRead;
+use std::net;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use rustc_serialize::hex::FromHex;
+use common::access_list::AccessList;
+use common::clock;
 use common::net as mynet;
+use common::net::BanTarget;
+use common::packet::MAX_PACKET_SIZE;
+use common::rate_limit::{RateLimit, MAX_BAN_SECONDS};
+use common::tick::{TickScheduler, DEFAULT_TICK_RATE};
 
-static mut packet_counter : u16 = 0;
+const SERVER_TOKEN: mio::Token = mio::Token(0);
+const PROTOCOL_ID: u32 = 0x4C494645;
+// Clients send keep-alives while they have nothing else to say, so one this quiet has gone.
+const PEER_TIMEOUT: f32 = 10.0;
 
-fn listen_on_port(port: u16){
-    mioco::spawn(move || -> io::Result<()> {
-        let ip = Ipv4Addr::new(0, 0, 0, 0);
-        let addr = SocketAddr::V4(SocketAddrV4::new(ip, port));
+// What the server does with each payload it receives.
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum ServerMode {
+    Echo,   // reply to the sender with the same payload
+    Sink,   // count it and drop it
+    Relay,  // forward it to every other connected client
+}
 
-        let mut sock;
-        match UdpSocket::v4(){ // .unwrap();
-            Ok(udpsocket) => {
-                sock = udpsocket
-            },
-            Err(_) => {
-                panic!("Could not create UdpSocket...");
-            }
+impl FromStr for ServerMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<ServerMode, String> {
+        match mode {
+            "echo"  => Ok(ServerMode::Echo),
+            "sink"  => Ok(ServerMode::Sink),
+            "relay" => Ok(ServerMode::Relay),
+            _       => Err(format!("Unknown server mode '{}'. Expected echo, sink or relay.", mode)),
         }
+    }
+}
+
+fn print_usage() {
+println!("
+Usage:
+server [--mode echo|sink|relay] [--bind ADDRESS] [--tick-rate HZ] [--threads N] [--secret SECRET] [--encrypt]
+       [--token-key KEY] [--rate-limit PACKETS] [--ban-seconds SECONDS] [--access-list FILE]
+
+Modes:
+echo    - reply to each payload with the same payload
+sink    - count and drop every payload (default)
+relay   - forward each payload to every other connected client
+
+Options:
+--bind ADDRESS  - address and port to listen on, e.g. 127.0.0.1:8890 or [::]:8890
+                  (default 0.0.0.0:8890). [::] takes IPv4 clients as well.
+--tick-rate HZ  - how many times per second the server updates (default 60)
+--threads N     - how many worker threads share the port (default 1). Each client sticks to one
+                  worker, and relay mode only forwards between clients on the same worker.
+--secret SECRET - authenticate every packet with this key; clients must use the same one.
+                  Packets without a valid MAC are dropped and counted as rejected.
+--encrypt       - only accept clients that complete a key exchange, and encrypt everything
+                  sent to and from them. Clients must pass --encrypt as well.
+--token-key KEY - only accept clients with a connect token minted with this key, given as 64
+                  hex digits. Implies --encrypt.
+--rate-limit PACKETS
+                - drop datagrams from any address sending more than this many a second, and
+                  ban it for a while. Addresses that keep sending garbage are banned too.
+--ban-seconds SECONDS
+                - how long those bans last (default 30, at most a year). Only used with
+                  --rate-limit.
+--access-list FILE
+                - ignore every address the list in FILE does not permit. One rule to a line,
+                  'allow SUBNET' or 'deny SUBNET', e.g. 'allow 10.0.0.0/8' or 'deny fd00::/8';
+                  # starts a comment. Deny rules win, and with any allow rules an address has
+                  to match one. The file is reloaded within a second of being changed.
+
+Commands, typed while the server runs:
+{}", COMMANDS);
+}
+
+const COMMANDS: &'static str = "\
+peers                   - list every connected client with its client ids
+kick ADDRESS [CODE]     - disconnect the client at ADDRESS (ip:port), telling it CODE (default 0)
+ban TARGET SECONDS      - kick and keep out an IP address, or a connect token client id, for
+                          up to a year
+unban TARGET            - lift a ban early
+";
+
+// Commands typed at the server's console. Every worker gets a copy and does what it can with it.
+#[derive(Clone)]
+enum AdminCommand {
+    Peers,
+    Kick(mynet::Address, u8),
+    Ban(BanTarget, f64),
+    Unban(BanTarget),
+}
+
+// An IP address, with or without a port, or a connect token client id.
+fn parse_ban_target(text: &str) -> Result<BanTarget, String> {
+    if let Ok(address) = text.parse::<net::IpAddr>() {
+        return Ok(BanTarget::Address(mynet::Address::new(address, 0).get_address()));
+    }
+
+    if let Ok(address) = text.parse::<net::SocketAddr>() {
+        return Ok(BanTarget::Address(mynet::Address::from_socket_addr(address).get_address()));
+    }
+
+    match text.parse::<u64>() {
+        Ok(client_id) if client_id != 0 => Ok(BanTarget::ClientId(client_id)),
+        _ => Err(format!("'{}' is neither an address nor a client id.", text)),
+    }
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<AdminCommand, String> {
+        let words : Vec<&str> = line.split_whitespace().collect();
 
-        match sock.bind(&addr) {
-            Ok(_) => {
-                info!("Bound socket...");
+        match (words.first().map(|word| *word), words.len()) {
+            (Some("peers"), 1) => Ok(AdminCommand::Peers),
+            (Some("kick"), 2) | (Some("kick"), 3) => {
+                let address = match words[1].parse::<net::SocketAddr>() {
+                    Ok(address) => mynet::Address::from_socket_addr(address),
+                    Err(_) => {
+                        return Err(format!("'{}' is not an address and port.", words[1]));
+                    }
+                };
+
+                match words.get(2).map_or(Ok(0), |code| code.parse::<u8>()) {
+                    Ok(code) => Ok(AdminCommand::Kick(address, code)),
+                    Err(_) => Err(String::from("A kick code is a number from 0 to 255.")),
+                }
             },
-            Err(_) => {
-                panic!("Could not bind on {}", addr);
+            (Some("ban"), 3) => {
+                let target = try!(parse_ban_target(words[1]));
+
+                match words[2].parse::<f64>() {
+                    Ok(seconds) if seconds > 0.0 && seconds <= MAX_BAN_SECONDS => Ok(AdminCommand::Ban(target, seconds)),
+                    _ => Err(String::from("A ban needs a positive number of seconds, up to a year.")),
+                }
             },
+            (Some("unban"), 2) => parse_ban_target(words[1]).map(AdminCommand::Unban),
+            _ => Err(format!("Unknown command '{}'. Commands are:\n{}", line.trim(), COMMANDS)),
         }
+    }
+}
 
-        let mut buf = [0u8; 1024 * 16];
-
-        loop {
-
-            if let Some((len, addr)) = try!(sock.try_recv(&mut buf)) {
-                info!("Length: {}, Addr: {}", len, addr);
+#[derive(Clone)]
+struct ServerOptions {
+    mode : ServerMode,
+    bind : net::SocketAddr,
+    tick_rate : u32,
+    threads : usize,
+    secret : Option<String>,
+    encrypt : bool,
+    token_key : Option<Vec<u8>>,
+    rate_limit : Option<RateLimit>,
+    access_list : Option<String>,  // Path to the file, which every worker reloads on its own
+}
 
-                let data = Vec::from(&buf[0..len]);
+fn exit_with_usage(message: &str) -> ! {
+    println!("{}", message);
+    print_usage();
+    std::process::exit(1);
+}
 
-                match bincode::rustc_serialize::decode::<Packet>(&data[..]) {
-                    //  let decoded : Packet;
-                    Ok(decoded) => {
-                        info!("{:?}", decoded);
-                        try!(sock.try_send(&mut buf[0..len], &addr));
+fn parse_arguments() -> ServerOptions {
+    let mut options = ServerOptions {
+        mode : ServerMode::Sink,
+        bind : net::SocketAddr::new(net::IpAddr::V4(net::Ipv4Addr::new(0, 0, 0, 0)), mynet::Port::Server as u16),
+        tick_rate : DEFAULT_TICK_RATE,
+        threads : 1,
+        secret : None,
+        encrypt : false,
+        token_key : None,
+        rate_limit : None,
+        access_list : None,
+    };
+    let mut ban_seconds = None;
+    let mut args = env::args().skip(1);
 
-                        // TODO: Please be safe!
-                        unsafe {
-                            packet_counter+=1;
-                            info!("{}", packet_counter);
+    loop {
+        match args.next() {
+            Some(arg) => {
+                match arg.as_ref() {
+                    "--mode" | "-m" => {
+                        match args.next().map(|value| value.parse::<ServerMode>()) {
+                            Some(Ok(parsed)) => {
+                                options.mode = parsed;
+                            },
+                            Some(Err(error)) => {
+                                exit_with_usage(&error);
+                            },
+                            None => {
+                                exit_with_usage("--mode needs a value.");
+                            },
                         }
-
                     },
-                    Err(_) => {
-                        panic!("Could not decode message...");
+                    "--bind" | "-b" => {
+                        match args.next().map(|value| value.parse::<net::SocketAddr>()) {
+                            Some(Ok(parsed)) => {
+                                options.bind = parsed;
+                            },
+                            _ => {
+                                exit_with_usage("--bind needs an address and port, like 127.0.0.1:8890 or [::1]:8890.");
+                            },
+                        }
+                    },
+                    "--tick-rate" | "-t" => {
+                        match args.next().map(|value| value.parse::<u32>()) {
+                            Some(Ok(parsed)) if parsed > 0 => {
+                                options.tick_rate = parsed;
+                            },
+                            _ => {
+                                exit_with_usage("--tick-rate needs a positive whole number of ticks per second.");
+                            },
+                        }
+                    },
+                    "--threads" | "-n" => {
+                        match args.next().map(|value| value.parse::<usize>()) {
+                            Some(Ok(parsed)) if parsed > 0 => {
+                                options.threads = parsed;
+                            },
+                            _ => {
+                                exit_with_usage("--threads needs a positive whole number of threads.");
+                            },
+                        }
+                    },
+                    "--secret" | "-s" => {
+                        match args.next() {
+                            Some(value) => {
+                                options.secret = Some(value);
+                            },
+                            None => {
+                                exit_with_usage("--secret needs a value.");
+                            },
+                        }
+                    },
+                    "--encrypt" | "-e" => {
+                        options.encrypt = true;
+                    },
+                    "--token-key" | "-k" => {
+                        match args.next().map(|value| value.from_hex()) {
+                            Some(Ok(ref parsed)) if parsed.len() == 32 => {
+                                options.token_key = Some(parsed.clone());
+                            },
+                            _ => {
+                                exit_with_usage("--token-key needs a key of 64 hex digits.");
+                            },
+                        }
+                    },
+                    "--rate-limit" | "-r" => {
+                        match args.next().map(|value| value.parse::<f64>()) {
+                            Some(Ok(parsed)) if parsed > 0.0 && parsed.is_finite() => {
+                                let mut limit = RateLimit::new();
+                                limit.packets_per_second = parsed;
+                                limit.burst = parsed.max(1.0);
+                                options.rate_limit = Some(limit);
+                            },
+                            _ => {
+                                exit_with_usage("--rate-limit needs a positive number of packets per second.");
+                            },
+                        }
+                    },
+                    "--ban-seconds" => {
+                        match args.next().map(|value| value.parse::<f64>()) {
+                            Some(Ok(parsed)) if parsed > 0.0 && parsed <= MAX_BAN_SECONDS => {
+                                ban_seconds = Some(parsed);
+                            },
+                            _ => {
+                                exit_with_usage("--ban-seconds needs a positive number of seconds, up to a year.");
+                            },
+                        }
+                    },
+                    "--access-list" | "-a" => {
+                        match args.next() {
+                            Some(path) => {
+                                // Checked now so a typo stops the server instead of letting everyone in.
+                                if let Err(error) = AccessList::load(&path) {
+                                    exit_with_usage(&format!("--access-list: {}", error));
+                                }
+                                options.access_list = Some(path);
+                            },
+                            None => {
+                                exit_with_usage("--access-list needs a file.");
+                            },
+                        }
+                    },
+                    "--help" | "-h" => {
+                        print_usage();
+                        std::process::exit(0);
+                    },
+                    _ => {
+                        exit_with_usage(&format!("Unknown argument '{}'", arg));
                     },
                 }
+            },
+            None => {
+                break;
+            },
+        }
+    }
+
+    // Each worker binds on its own, so port 0 would scatter them across different ports.
+    if options.threads > 1 && options.bind.port() == 0 {
+        exit_with_usage("--threads needs a fixed --bind port.");
+    }
+
+    if let (Some(limit), Some(seconds)) = (options.rate_limit.as_mut(), ban_seconds) {
+        limit.ban_seconds = seconds;
+    }
+    options
+}
+
+#[derive(Default, Clone, Copy)]
+struct ModeCounters {
+    received : u64,
+    echoed : u64,
+    sunk : u64,
+    relayed : u64,
+}
+
+fn handle_payload(server: &mut mynet::ReliableServer, mode: ServerMode, counters: &mut ModeCounters,
+                  sender: &mynet::Address, payload: &[u8]) {
+    counters.received += 1;
+
+    match mode {
+        ServerMode::Echo => {
+            if server.SendPacket(sender, payload).is_some() {
+                counters.echoed += 1;
+            }
+        },
+        ServerMode::Sink => {
+            counters.sunk += 1;
+        },
+        ServerMode::Relay => {
+            for peer in server.GetPeerAddresses() {
+                if peer != *sender && server.SendPacket(&peer, payload).is_some() {
+                    counters.relayed += 1;
+                }
+            }
+        },
+    }
+}
+
+impl ModeCounters {
+    fn merge(&mut self, other: &ModeCounters) {
+        self.received += other.received;
+        self.echoed += other.echoed;
+        self.sunk += other.sunk;
+        self.relayed += other.relayed;
+    }
+}
+
+// What each worker last told the main thread about itself.
+#[derive(Default, Clone)]
+struct WorkerReport {
+    stats : mynet::ServerStats,
+    counters : ModeCounters,
+    overruns : u64,
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Loads the access list at `path` into `server` again if the file has changed since it was last
+// seen at `seen`, and returns when it was last changed. A list that no longer parses is reported
+// and the old one kept until the file changes again.
+fn reload_access_list(index: usize, server: &mut mynet::ReliableServer, path: &str, seen: Option<SystemTime>) -> Option<SystemTime> {
+    let modified = modified_time(path);
+
+    if modified == seen {
+        return seen;
+    }
+
+    match AccessList::load(path) {
+        Ok(list) => {
+            server.SetAccessList(list);
+
+            if index == 0 {
+                println!("Reloaded the access list from {}", path);
+            }
+        },
+        Err(error) => {
+            if index == 0 {
+                println!("Keeping the old access list: {}", error);
+            }
+        },
+    }
+    modified
+}
+
+fn run_command(index: usize, server: &mut mynet::ReliableServer, command: AdminCommand) {
+    match command {
+        AdminCommand::Peers => {
+            for address in server.GetPeerAddresses() {
+                println!("Worker {}: {}, client id {}, token client id {}", index, address,
+                         server.GetClientId(&address).unwrap_or(0), server.GetTokenClientId(&address).unwrap_or(0));
+            }
+        },
+        AdminCommand::Kick(address, code) => {
+            server.Kick(&address, code);
+        },
+        AdminCommand::Ban(target, seconds) => {
+            if server.Ban(target, seconds).is_some() && index == 0 {
+                println!("Banned {} for {} seconds", target, seconds);
             }
+        },
+        AdminCommand::Unban(target) => {
+            if server.Unban(&target) && index == 0 {
+                println!("Unbanned {}", target);
+            }
+        },
+    }
+}
+
+// Runs one server on its own tick loop, forever. With more than one worker, each binds the port
+// with SO_REUSEPORT and the kernel spreads clients between them. Whether the server came up is
+// sent on `started` before the loop begins.
+fn run_worker(index: usize, options: ServerOptions, reports: Arc<Mutex<Vec<WorkerReport>>>, commands: mpsc::Receiver<AdminCommand>,
+              started: mpsc::Sender<Result<(), String>>) {
+    let mode = options.mode;
+
+    let mut server = if options.threads > 1 {
+        mynet::ReliableServer::with_reuse_port(PROTOCOL_ID, PEER_TIMEOUT, options.bind, clock::system_clock())
+    } else {
+        mynet::ReliableServer::bind(PROTOCOL_ID, PEER_TIMEOUT, options.bind, clock::system_clock())
+    };
+
+    if let Some(ref secret) = options.secret {
+        server.SetSecretKey(secret.as_bytes());
+    }
+
+    if options.encrypt {
+        server.EnableEncryption();
+    }
+
+    if let Some(ref token_key) = options.token_key {
+        server.SetConnectTokenKey(token_key);
+    }
+
+    if let Some(limit) = options.rate_limit {
+        if !server.SetRateLimit(limit) {
+            let _ = started.send(Err("Error: The rate limit is out of range.".to_string()));
+            return;
         }
+    }
 
-    });
+    // Stat before loading, so a change made in between is picked up on the next check.
+    let mut access_list_seen = None;
+    if let Some(ref path) = options.access_list {
+        access_list_seen = modified_time(path);
+
+        match AccessList::load(path) {
+            Ok(list) => server.SetAccessList(list),
+            Err(error) => {
+                let _ = started.send(Err(format!("Error: {}", error)));
+                return;
+            },
+        }
+    }
+
+    if !server.Start() {
+        let _ = started.send(Err("Error: Could not start connection.".to_string()));
+        return;
+    }
+
+    server.Listen();
+
+    if index == 0 {
+        println!("Listening on {}", server.local_addr());
+    }
+
+    let poll = mio::Poll::new().expect("Could not create a poll for the server socket.");
+    let mut events = mio::Events::with_capacity(1024);
+
+    if let Err(error) = server.Register(&poll, SERVER_TOKEN) {
+        let _ = started.send(Err(format!("Error: Could not register the server socket: {}", error)));
+        return;
+    }
+
+    let _ = started.send(Ok(()));
+    drop(started);
+
+    let mut scheduler = TickScheduler::new(options.tick_rate);
+    let mut counters = ModeCounters::default();
+    let mut payload = [0u8; MAX_PACKET_SIZE];
+
+    loop {
+        scheduler.wait_for_tick(&poll, &mut events);
+
+        loop {
+            match server.ReceivePacket(&mut payload) {
+                Some((sender, amount)) => {
+                    handle_payload(&mut server, mode, &mut counters, &sender, &payload[..amount]);
+                },
+                None => {
+                    break;
+                },
+            }
+        }
+
+        // Before Tick, so anything a command queues goes out with this tick's Flush.
+        while let Ok(command) = commands.try_recv() {
+            run_command(index, &mut server, command);
+        }
+
+        server.Tick();
+
+        match scheduler.end_tick() {
+            Some(overrun) => {
+                println!("Worker {}: tick {} overran by {:?}", index, scheduler.get_tick_count(), overrun);
+            },
+            None => {},
+        }
+
+        if scheduler.get_tick_count() % (options.tick_rate as u64) == 0 {
+            if let Some(ref path) = options.access_list {
+                access_list_seen = reload_access_list(index, &mut server, path, access_list_seen);
+            }
+
+            let report = WorkerReport {
+                stats : server.GetStats(),
+                counters : counters,
+                overruns : scheduler.get_overrun_count(),
+            };
+
+            match reports.lock() {
+                Ok(mut reports) => {
+                    reports[index] = report;
+                },
+                Err(_) => {
+                    return;
+                },
+            }
+        }
+    }
 }
 
 fn main() {
-    use std::time::Duration;
-    use std::thread;
+    let _ = env_logger::init();
 
-    const DELTA_TIME : f32 = 0.1/30.0;
+    let options = parse_arguments();
+    println!("Server running in {:?} mode at {} ticks per second on {} thread(s)", options.mode, options.tick_rate, options.threads);
 
-    let mut reliable_connection = mynet::ReliableConnection::new(0x4C494645, 6000000.0, 0xFFFFFFFF, mynet::Port::Server as u16);
-    let connection_started = reliable_connection.Start();
+    let reports = Arc::new(Mutex::new(vec![WorkerReport::default(); options.threads]));
+    let (started_sender, started) = mpsc::channel();
+    let mut workers = Vec::new();
+    let mut handles = Vec::new();
 
-    if connection_started == false {
-        panic!("Error: Could not start connection.")
+    for index in 0..options.threads {
+        let reports = reports.clone();
+        let options = options.clone();
+        let started_sender = started_sender.clone();
+        let (sender, commands) = mpsc::channel();
+        workers.push(sender);
+
+        handles.push(thread::spawn(move || {
+            run_worker(index, options, reports, commands, started_sender);
+        }));
     }
 
-    reliable_connection.Listen();
-    //reliable_connection.connection.address = net::Address::new( std::net::Ipv4Addr::new(127, 0, 0, 1) , net::Port::Client as u16);
-    let mut i = 0;
-    loop {
-        let mut buffer = Vec::<u8>::with_capacity(200);
+    // A worker that panics before it reports, say because it could not bind, drops its sender
+    // without a word; once every sender is gone, recv gives up rather than waiting for ever.
+    drop(started_sender);
 
-        let amount = reliable_connection.ReceivePacket(&mut buffer, 100*8);
+    for _ in 0..options.threads {
+        match started.recv() {
+            Ok(Ok(())) => {},
+            Ok(Err(error)) => {
+                println!("{}", error);
+                std::process::exit(1);
+            },
+            Err(_) => {
+                println!("Error: A worker thread died while starting.");
+                std::process::exit(1);
+            },
+        }
+    }
+
+    // Commands come in on stdin and go to every worker, since none knows which clients the others have.
+    thread::spawn(move || {
+        let stdin = io::stdin();
+
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => {
+                    return;
+                }
+            };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match line.parse::<AdminCommand>() {
+                Ok(command) => {
+                    for worker in &workers {
+                        let _ = worker.send(command.clone());
+                    }
+                },
+                Err(error) => {
+                    println!("{}", error);
+                },
+            }
+        }
+    });
+
+    // The workers report in about once a second; print what they said, all added up.
+    loop {
+        thread::sleep(Duration::from_secs(1));
 
-        if amount != 0 {
-            //println!("Data received:\n{}\n{:?}\n\n", amount, buffer);
+        // Workers never return while they are serving, so one that has stopped has failed.
+        for (index, handle) in handles.iter().enumerate() {
+            if handle.is_finished() {
+                println!("Error: Worker {} stopped.", index);
+                std::process::exit(1);
+            }
         }
 
-        reliable_connection.Update(DELTA_TIME);
-        //thread::sleep(Duration::from_millis(20));
-        if i % 500 == 0 {
-            reliable_connection.PrintStats();
+        let mut stats = mynet::ServerStats::default();
+        let mut counters = ModeCounters::default();
+        let mut overruns = 0;
+
+        match reports.lock() {
+            Ok(reports) => {
+                for report in reports.iter() {
+                    stats.merge(&report.stats);
+                    counters.merge(&report.counters);
+                    overruns += report.overruns;
+                }
+            },
+            Err(_) => {
+                panic!("Error: A worker thread died.");
+            },
         }
+
+        println!("{}", stats);
+        println!("received {}, echoed {}, sunk {}, relayed {}, tick overruns {}\n",
+                 counters.received, counters.echoed, counters.sunk, counters.relayed, overruns);
     }
 }